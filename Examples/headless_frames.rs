@@ -0,0 +1,16 @@
+use OxidBoy::motherboard::MotherBoard;
+
+// Runs a rom without a window or audio device and saves the last frame
+// $ cargo run --example headless_frames -- ./Roms/game.gb frame.png
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let rom = args.next().expect("usage: headless_frames <rom> [out.png]");
+    let out = args.next().unwrap_or_else(|| String::from("frame.png"));
+
+    let data = std::fs::read(&rom).unwrap();
+    let mut motherboard = MotherBoard::power_up_from_bytes(data);
+    motherboard.run_frames(60);
+
+    motherboard.screenshot(&out);
+    println!("{} -> {}", rom, out);
+}
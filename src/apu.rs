@@ -1,10 +1,14 @@
 use super::clock::Clock;
 use super::cpu;
 use super::mem::Memory;
+use super::terms::Term;
 use blip_buf::BlipBuf;
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Clone, PartialEq, Eq)]
 enum Channel {
@@ -194,15 +198,17 @@ struct VolumeEnvelope {
     reg: Rc<RefCell<Register>>,
     timer: Clock,
     volume: u8,
+    // Period latched at the last trigger, 0 leaves the volume alone until the next trigger
+    period: u8,
 }
 
 impl VolumeEnvelope {
     fn power_up(reg: Rc<RefCell<Register>>) -> Self {
-        Self { reg, timer: Clock::power_up(8), volume: 0x00 }
+        Self { reg, timer: Clock::power_up(8), volume: 0x00, period: 0 }
     }
 
     fn next(&mut self) {
-        if self.reg.borrow().get_period() == 0 {
+        if self.period == 0 {
             return;
         }
         if self.timer.next(1) == 0x00 {
@@ -219,8 +225,9 @@ impl VolumeEnvelope {
     }
 
     fn reload(&mut self) {
-        let p = self.reg.borrow().get_period();
-        self.timer.period = if p == 0 { 8 } else { u32::from(p) };
+        self.period = self.reg.borrow().get_period();
+        self.timer.set_period(u32::from(self.period.max(1)));
+        self.timer.reset();
         self.volume = self.reg.borrow().get_starting_volume();
     }
 }
@@ -300,6 +307,12 @@ impl Blip {
         self.ampl = ampl;
         self.data.add_delta(time, d);
     }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.from = 0x0000_0000;
+        self.ampl = 0x0000_0000;
+    }
 }
 
 struct ChannelSquare {
@@ -389,12 +402,24 @@ struct ChannelWave {
     blip: Blip,
     waveram: [u8; 16],
     waveidx: usize,
+    wave_ram_quirk: bool,
 }
 
 impl ChannelWave {
     fn power_up(blip: BlipBuf) -> ChannelWave {
         let reg = Rc::new(RefCell::new(Register::power_up(Channel::Wave)));
-        ChannelWave { reg: reg.clone(), timer: Clock::power_up(8192), lc: LengthCounter::power_up(reg.clone()), blip: Blip::power_up(blip), waveram: [0x00; 16], waveidx: 0x00 }
+        ChannelWave { reg: reg.clone(), timer: Clock::power_up(8192), lc: LengthCounter::power_up(reg.clone()), blip: Blip::power_up(blip), waveram: DMG_WAVE_RAM, waveidx: 0x00, wave_ram_quirk: false }
+    }
+
+    /// Offset into wave ram the CPU actually reaches for the given address
+    /// * With the DMG quirk on, a playing channel redirects every access to the byte it is currently reading
+    fn wave_index(&self, a: u16) -> usize {
+        let playing = self.reg.borrow().get_trigger() && self.reg.borrow().get_dac_power();
+        if self.wave_ram_quirk && playing {
+            self.waveidx / 2
+        } else {
+            a as usize - 0xFF30
+        }
     }
 
     fn next(&mut self, cycles: u32) {
@@ -430,7 +455,7 @@ impl Memory for ChannelWave {
             0xFF1C => self.reg.borrow().nrx2,
             0xFF1D => self.reg.borrow().nrx3,
             0xFF1E => self.reg.borrow().nrx4,
-            0xFF30..=0xFF3F => self.waveram[a as usize - 0xFF30],
+            0xFF30..=0xFF3F => self.waveram[self.wave_index(a)],
             _ => unreachable!()
         }    
     }
@@ -455,7 +480,10 @@ impl Memory for ChannelWave {
                     self.waveidx = 0x00;
                 }
             }
-            0xFF30..=0xFF3F => self.waveram[a as usize - 0xFF30] = v,
+            0xFF30..=0xFF3F => {
+                let i = self.wave_index(a);
+                self.waveram[i] = v;
+            }
             _ => unreachable!(),
         }
     }
@@ -552,8 +580,50 @@ impl Memory for ChannelNoise {
     }
 }
 
+/// How the left and right mix is written into the output buffer
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    Stereo,
+    /// Both sides get the average of left and right, like the original speaker
+    Mono,
+}
+
+/// Construction time settings for the APU
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ApuConfig {
+    pub sample_rate: u32,
+    /// Bit 0 is channel 1 through bit 3 for channel 4, cleared channels run but stay out of the mix
+    pub initial_channel_mask: u8,
+    /// Scales the final mix, 1.0 is the normal level
+    pub master_volume: f32,
+}
+
+impl Default for ApuConfig {
+    fn default() -> Self {
+        Self { sample_rate: 48000, initial_channel_mask: 0x0F, master_volume: 1.0 }
+    }
+}
+
+/// Wave RAM the DMG boot rom leaves behind
+const DMG_WAVE_RAM: [u8; 16] = [0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34, 0xB8, 0x2E, 0xDA];
+
+/// Wave RAM contents a game finds when it starts on the given hardware
+/// * CGB hardware alternates 00 and FF, the DMG family shares one noisy pattern
+pub fn wave_ram_at_power_up(term: Term) -> [u8; 16] {
+    match term {
+        Term::GBC => std::array::from_fn(|i| if i % 2 == 0 { 0x00 } else { 0xFF }),
+        _ => DMG_WAVE_RAM,
+    }
+}
+
+/// Samples kept per channel for the scope
+pub const SCOPE_SAMPLES: usize = 1024;
+
 pub struct Apu {
     pub buffer: Arc<Mutex<Vec<(f32, f32)>>>,
+    /// Shared with the audio callback, incremented whenever it drains fewer samples than requested
+    pub underruns: Arc<AtomicU64>,
+    dropped_samples: u64,
     reg: Register,
     timer: Clock,
     fs: FrameSequencer,
@@ -562,28 +632,111 @@ pub struct Apu {
     channel3: ChannelWave,
     channel4: ChannelNoise,
     sample_rate: u32,
+    output_mode: OutputMode,
+    channel_mask: u8,
+    master_volume: f32,
+    div_apu_sync: bool,
+    scope: Option<[VecDeque<i16>; 4]>,
+    step_hook: Option<Box<dyn Fn(u8)>>,
+    target_latency: Duration,
 }
 
 impl Apu {
     pub fn power_up(sample: u32) -> Self {
+        Self::with_config(ApuConfig { sample_rate: sample, ..ApuConfig::default() })
+    }
+
+    pub fn with_config(config: ApuConfig) -> Self {
+        let sample = config.sample_rate;
         let blipbuf1 = create_blipbuf(sample);
         let blipbuf2 = create_blipbuf(sample);
         let blipbuf3 = create_blipbuf(sample);
         let blipbuf4 = create_blipbuf(sample);
-        Self { buffer: Arc::new(Mutex::new(Vec::new())), reg: Register::power_up(Channel::Mixer), timer: Clock::power_up(cpu::CLOCK_FREQUENCY / 512), 
+        Self { buffer: Arc::new(Mutex::new(Vec::new())), underruns: Arc::new(AtomicU64::new(0)), dropped_samples: 0, reg: Register::power_up(Channel::Mixer), timer: Clock::power_up(cpu::CLOCK_FREQUENCY / 512), 
         fs: FrameSequencer::power_up(), channel1: ChannelSquare::power_up(blipbuf1, Channel::Square1),
         channel2: ChannelSquare::power_up(blipbuf2, Channel::Square2), 
-        channel3: ChannelWave::power_up(blipbuf3), channel4: ChannelNoise::power_up(blipbuf4), sample_rate: sample }
+        channel3: ChannelWave::power_up(blipbuf3), channel4: ChannelNoise::power_up(blipbuf4), sample_rate: sample,
+        output_mode: OutputMode::Stereo, channel_mask: config.initial_channel_mask & 0x0F, master_volume: config.master_volume, div_apu_sync: false, scope: None, step_hook: None, target_latency: Duration::from_secs(1) }
+    }
+
+    /// Channels currently let into the mix, bit 0 is channel 1
+    pub fn channel_mask(&self) -> u8 {
+        self.channel_mask
+    }
+
+    /// Changes the output sample rate by rebuilding the blip buffers, channel registers are kept as is
+    pub fn set_sample_rate(&mut self, sample: u32) {
+        if sample == self.sample_rate {
+            return;
+        }
+        self.channel1.blip = Blip::power_up(create_blipbuf(sample));
+        self.channel2.blip = Blip::power_up(create_blipbuf(sample));
+        self.channel3.blip = Blip::power_up(create_blipbuf(sample));
+        self.channel4.blip = Blip::power_up(create_blipbuf(sample));
+        self.buffer.lock().unwrap().clear();
+        self.sample_rate = sample;
+    }
+
+    /// Drops queued samples and pending blip deltas, so a jump in emulated state doesn't click
+    pub fn flush(&mut self) {
+        self.channel1.blip.clear();
+        self.channel2.blip.clear();
+        self.channel3.blip.clear();
+        self.channel4.blip.clear();
+        self.buffer.lock().unwrap().clear();
+    }
+
+    /// Overwrites all 16 bytes of wave RAM, bypassing the access quirk
+    pub fn set_wave_ram(&mut self, data: [u8; 16]) {
+        self.channel3.waveram = data;
+    }
+
+    /// Models the DMG wave ram access quirk, on by default for DMG revisions and off on GBC
+    pub fn set_wave_ram_quirk(&mut self, enabled: bool) {
+        self.channel3.wave_ram_quirk = enabled;
+    }
+
+    pub fn set_output_mode(&mut self, mode: OutputMode) {
+        self.output_mode = mode;
+    }
+
+    /// Most audio kept waiting for the device, one second by default
+    /// * Lower means less delay, too low and the device runs dry between frames
+    pub fn set_target_latency(&mut self, latency: Duration) {
+        self.target_latency = latency;
+    }
+
+    /// Samples the buffer holds at most, the target latency at the current sample rate
+    pub fn buffer_cap(&self) -> usize {
+        (f64::from(self.sample_rate) * self.target_latency.as_secs_f64()) as usize
+    }
+
+    /// Number of times the audio callback ran out of samples
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Number of samples thrown away because the buffer was already full
+    pub fn dropped_samples(&self) -> u64 {
+        self.dropped_samples
     }
 
     fn play(&mut self, l: &[f32], r: &[f32]) {
         assert_eq!(l.len(), r.len());
+        let cap = self.buffer_cap();
         let mut buffer = self.buffer.lock().unwrap();
-        for (l, r) in l.iter().zip(r) {
-            if buffer.len() > self.sample_rate as usize {
+        for (i, (sl, sr)) in l.iter().zip(r).enumerate() {
+            if buffer.len() >= cap {
+                self.dropped_samples += (l.len() - i) as u64;
                 return;
             }
-            buffer.push((*l, *r));
+            match self.output_mode {
+                OutputMode::Stereo => buffer.push((*sl, *sr)),
+                OutputMode::Mono => {
+                    let m = (sl + sr) / 2.0;
+                    buffer.push((m, m));
+                }
+            }
         }
     }
 
@@ -593,37 +746,119 @@ impl Apu {
         }
 
         for _ in 0..self.timer.next(cycles) {
-            self.channel1.next(self.timer.period);
-            self.channel2.next(self.timer.period);
-            self.channel3.next(self.timer.period);
-            self.channel4.next(self.timer.period);
-
-            let step = self.fs.next();
-            if step == 0 || step == 2 || step == 4 || step == 6 {
-                self.channel1.lc.next();
-                self.channel2.lc.next();
-                self.channel3.lc.next();
-                self.channel4.lc.next();
-            }
-            if step == 7 {
-                self.channel1.ve.next();
-                self.channel2.ve.next();
-                self.channel4.ve.next();
-            }
-            if step == 2 || step == 6 {
-                self.channel1.fs.next();
-                self.channel1.timer.period = period(self.channel1.reg.clone());
-            }
-            self.channel1.blip.data.end_frame(self.timer.period);
-            self.channel2.blip.data.end_frame(self.timer.period);
-            self.channel3.blip.data.end_frame(self.timer.period);
-            self.channel4.blip.data.end_frame(self.timer.period);
-            
-            self.channel1.blip.from = self.channel1.blip.from.wrapping_sub(self.timer.period);
-            self.channel2.blip.from = self.channel2.blip.from.wrapping_sub(self.timer.period);
-            self.channel3.blip.from = self.channel3.blip.from.wrapping_sub(self.timer.period);
-            self.channel4.blip.from = self.channel4.blip.from.wrapping_sub(self.timer.period);
-            self.mix();
+            self.run(self.timer.period, true);
+        }
+    }
+
+    /// Frame sequencer step that fired last, 0-7
+    /// * Length counters clock on even steps, sweep on 2 and 6, envelopes on 7
+    pub fn frame_step(&self) -> u8 {
+        self.fs.step
+    }
+
+    /// Calls the hook with the step index every time the frame sequencer fires, None removes it
+    pub fn set_frame_step_hook(&mut self, hook: Option<Box<dyn Fn(u8)>>) {
+        self.step_hook = hook;
+    }
+
+    /// Keeps the last SCOPE_SAMPLES raw samples of every channel before mixing, off by default
+    pub fn set_scope_enabled(&mut self, enabled: bool) {
+        self.scope = if enabled { Some(Default::default()) } else { None };
+    }
+
+    /// Last raw samples of channel 1-4, oldest first, empty while the scope is off
+    pub fn channel_scope(&self, channel: usize) -> Vec<i16> {
+        assert!((1..=4).contains(&channel), "No channel {}", channel);
+        self.scope.as_ref().map_or_else(Vec::new, |scope| scope[channel - 1].iter().copied().collect())
+    }
+
+    fn record_scope(&mut self, channel: usize, samples: &[i16]) {
+        if let Some(scope) = self.scope.as_mut() {
+            let ring = &mut scope[channel];
+            ring.extend(samples);
+            let excess = ring.len().saturating_sub(SCOPE_SAMPLES);
+            ring.drain(..excess);
+        }
+    }
+
+    /// True when nothing can be heard, so a frontend may pause its audio stream
+    /// * The mixer is off, or no channel that reaches an output is playing at a non zero volume
+    pub fn is_silent(&self) -> bool {
+        if !self.reg.get_power() {
+            return true;
+        }
+        let routing = self.reg.nrx1 & (self.channel_mask * 0x11);
+        let audible = [
+            self.channel1.reg.borrow().get_trigger() && self.channel1.ve.volume > 0,
+            self.channel2.reg.borrow().get_trigger() && self.channel2.ve.volume > 0,
+            self.channel3.reg.borrow().get_trigger() && self.channel3.reg.borrow().get_dac_power() && self.channel3.reg.borrow().get_volume_code() > 0,
+            self.channel4.reg.borrow().get_trigger() && self.channel4.ve.volume > 0,
+        ];
+        !audible.iter().enumerate().any(|(i, &on)| on && routing & (0x11 << i) != 0x00)
+    }
+
+    /// Drives the frame sequencer off DIV like hardware, so writing DIV can cut a step short
+    /// * The sequencer phase follows DIV from the next write to it on
+    pub fn set_div_apu_sync(&mut self, enabled: bool) {
+        self.div_apu_sync = enabled;
+    }
+
+    pub fn div_apu_sync(&self) -> bool {
+        self.div_apu_sync
+    }
+
+    /// Called on a DIV write when synced, edge is whether the write made the DIV-APU bit fall
+    /// * The cycles since the last step are played out, then the sequencer restarts its count from the write
+    pub fn div_reset(&mut self, edge: bool) {
+        if !self.div_apu_sync || !self.reg.get_power() {
+            return;
+        }
+        let elapsed = self.timer.progress();
+        self.timer.reset();
+        self.run(elapsed, edge);
+    }
+
+    /// Advances every channel by elapsed cycles and closes the blip frame, step clocks the frame sequencer
+    fn run(&mut self, elapsed: u32, step: bool) {
+        self.channel1.next(elapsed);
+        self.channel2.next(elapsed);
+        self.channel3.next(elapsed);
+        self.channel4.next(elapsed);
+
+        if step {
+            self.step_sequencer();
+        }
+        self.channel1.blip.data.end_frame(elapsed);
+        self.channel2.blip.data.end_frame(elapsed);
+        self.channel3.blip.data.end_frame(elapsed);
+        self.channel4.blip.data.end_frame(elapsed);
+
+        self.channel1.blip.from = self.channel1.blip.from.wrapping_sub(elapsed);
+        self.channel2.blip.from = self.channel2.blip.from.wrapping_sub(elapsed);
+        self.channel3.blip.from = self.channel3.blip.from.wrapping_sub(elapsed);
+        self.channel4.blip.from = self.channel4.blip.from.wrapping_sub(elapsed);
+        self.mix();
+    }
+
+    fn step_sequencer(&mut self) {
+        let step = self.fs.next();
+        if let Some(hook) = &self.step_hook {
+            hook(step);
+        }
+        if step == 0 || step == 2 || step == 4 || step == 6 {
+            self.channel1.lc.next();
+            self.channel2.lc.next();
+            self.channel3.lc.next();
+            self.channel4.lc.next();
+        }
+        if step == 7 {
+            self.channel1.ve.next();
+            self.channel2.ve.next();
+            self.channel4.ve.next();
+        }
+        if step == 2 || step == 6 {
+            self.channel1.fs.next();
+            self.channel1.timer.period = period(self.channel1.reg.clone());
         }
     }
 
@@ -639,8 +874,10 @@ impl Apu {
         let sample_count = sc1 as usize;
         let mut sum = 0;
 
-        let l_volume = (f32::from(self.reg.get_l()) / 7.0) * (1.0 / 15.0) * 0.25;
-        let r_volume = (f32::from(self.reg.get_r()) / 7.0) * (1.0 / 15.0) * 0.25;
+        let l_volume = (f32::from(self.reg.get_l()) / 7.0) * (1.0 / 15.0) * 0.25 * self.master_volume;
+        let r_volume = (f32::from(self.reg.get_r()) / 7.0) * (1.0 / 15.0) * 0.25 * self.master_volume;
+        // NR51 with masked channels taken off both sides
+        let routing = self.reg.nrx1 & (self.channel_mask * 0x11);
 
         while sum < sample_count {
             let buf_l = &mut [0f32; 2048];
@@ -648,41 +885,45 @@ impl Apu {
             let buf = &mut [0i16; 2048];
 
             let count1 = self.channel1.blip.data.read_samples(buf, false);
+            self.record_scope(0, &buf[..count1]);
             for (i, v) in buf[..count1].iter().enumerate() {
-                if self.reg.nrx1 & 0x01 == 0x01 {
+                if routing & 0x01 == 0x01 {
                     buf_l[i] += f32::from(*v) * l_volume;
                 }
-                if self.reg.nrx1 & 0x10 == 0x10 {
+                if routing & 0x10 == 0x10 {
                     buf_r[i] += f32::from(*v) * r_volume;
                 }
             }
 
             let count2 = self.channel2.blip.data.read_samples(buf, false);
+            self.record_scope(1, &buf[..count2]);
             for (i, v) in buf[..count2].iter().enumerate() {
-                if self.reg.nrx1 & 0x02 == 0x02 {
+                if routing & 0x02 == 0x02 {
                     buf_l[i] += f32::from(*v) * l_volume;
                 }
-                if self.reg.nrx1 & 0x20 == 0x20 {
+                if routing & 0x20 == 0x20 {
                     buf_r[i] += f32::from(*v) * r_volume;
                 }
             }
 
             let count3 = self.channel3.blip.data.read_samples(buf, false);
+            self.record_scope(2, &buf[..count3]);
             for (i, v) in buf[..count3].iter().enumerate() {
-                if self.reg.nrx1 & 0x04 == 0x04 {
+                if routing & 0x04 == 0x04 {
                     buf_l[i] += f32::from(*v) * l_volume;
                 }
-                if self.reg.nrx1 & 0x40 == 0x40 {
+                if routing & 0x40 == 0x40 {
                     buf_r[i] += f32::from(*v) * r_volume;
                 }
             }
 
             let count4 = self.channel4.blip.data.read_samples(buf, false);
+            self.record_scope(3, &buf[..count4]);
             for (i, v) in buf[..count4].iter().enumerate() {
-                if self.reg.nrx1 & 0x08 == 0x08 {
+                if routing & 0x08 == 0x08 {
                     buf_l[i] += f32::from(*v) * l_volume;
                 }
-                if self.reg.nrx1 & 0x80 == 0x80 {
+                if routing & 0x80 == 0x80 {
                     buf_r[i] += f32::from(*v) * r_volume;
                 }
             }
@@ -797,4 +1038,243 @@ fn period(reg: Rc<RefCell<Register>>) -> u32 {
         }
         Channel::Mixer => cpu::CLOCK_FREQUENCY / 512,
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overfilling_the_buffer_counts_dropped_samples() {
+        let mut apu = Apu::power_up(48000);
+        apu.set_target_latency(Duration::from_millis(1));
+        assert_eq!(apu.buffer_cap(), 48);
+        apu.play(&[0.5; 40], &[0.5; 40]);
+        assert_eq!(apu.dropped_samples(), 0);
+        apu.play(&[0.5; 40], &[0.5; 40]);
+        assert_eq!(apu.buffer.lock().unwrap().len(), 48);
+        assert_eq!(apu.dropped_samples(), 32);
+    }
+
+    /// Runs an eighth of a second and returns the samples it produced
+    fn eighth_second(apu: &mut Apu) -> usize {
+        apu.buffer.lock().unwrap().clear();
+        for _ in 0..cpu::CLOCK_FREQUENCY / 8 / 1024 {
+            apu.next(1024);
+        }
+        apu.buffer.lock().unwrap().len()
+    }
+
+    #[test]
+    fn sample_rate_switch_changes_output_rate() {
+        let mut apu = Apu::power_up(48000);
+        apu.set(0xFF26, 0x80);
+        let before = eighth_second(&mut apu);
+        assert!((5900..=6100).contains(&before), "{} samples at 48000", before);
+        apu.set_sample_rate(44100);
+        let after = eighth_second(&mut apu);
+        assert!((5412..=5612).contains(&after), "{} samples at 44100", after);
+    }
+
+    #[test]
+    fn mono_output_averages_both_sides() {
+        let mut apu = Apu::power_up(48000);
+        apu.set_output_mode(OutputMode::Mono);
+        apu.play(&[0.75, -0.25], &[0.25, 0.75]);
+        assert_eq!(*apu.buffer.lock().unwrap(), [(0.5, 0.5), (0.25, 0.25)]);
+    }
+
+    /// Reads 0xFF35 after loading wave ram with 0x10-0x1F and triggering channel 3
+    fn read_wave_ram_while_playing(quirk: bool) -> u8 {
+        let mut apu = Apu::power_up(48000);
+        apu.set_wave_ram_quirk(quirk);
+        apu.set(0xFF26, 0x80);
+        for i in 0..16u8 {
+            apu.set(0xFF30 + u16::from(i), 0x10 + i);
+        }
+        apu.set(0xFF1A, 0x80);
+        apu.set(0xFF1E, 0x80);
+        apu.get(0xFF35)
+    }
+
+    #[test]
+    fn wave_ram_reads_follow_the_playing_byte_on_dmg_only() {
+        // Just triggered, the channel sits on the first byte
+        assert_eq!(read_wave_ram_while_playing(true), 0x10);
+        assert_eq!(read_wave_ram_while_playing(false), 0x15);
+        let mut apu = Apu::power_up(48000);
+        apu.set_wave_ram_quirk(true);
+        apu.set(0xFF26, 0x80);
+        apu.set(0xFF30 + 5, 0x15);
+        // A stopped channel leaves wave ram addressed normally
+        assert_eq!(apu.get(0xFF35), 0x15);
+    }
+
+    /// Channel 1 volume after triggering it with the given NR12 and running half a second
+    fn volume_after_half_second(nr12: u8) -> u8 {
+        let mut apu = Apu::power_up(48000);
+        apu.set(0xFF26, 0x80);
+        apu.set(0xFF12, nr12);
+        apu.set(0xFF14, 0x80);
+        for _ in 0..cpu::CLOCK_FREQUENCY / 2 / 1024 {
+            apu.next(1024);
+        }
+        apu.channel1.ve.volume
+    }
+
+    #[test]
+    fn envelope_period_zero_keeps_the_volume() {
+        // Starting volume 15, decreasing
+        assert_eq!(volume_after_half_second(0xF0), 15);
+        // Period 1 steps 64 times a second, so it has long reached 0
+        assert_eq!(volume_after_half_second(0xF1), 0);
+    }
+
+    /// Largest sample out of an eighth of a second with only channel 1 playing
+    fn channel1_peak(config: ApuConfig) -> f32 {
+        let mut apu = Apu::with_config(config);
+        for (a, v) in [(0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0xFF), (0xFF11, 0x80), (0xFF12, 0xF0), (0xFF13, 0x00), (0xFF14, 0x87)] {
+            apu.set(a, v);
+        }
+        eighth_second(&mut apu);
+        let buffer = apu.buffer.lock().unwrap();
+        buffer.iter().fold(0.0f32, |m, &(l, r)| m.max(l.abs()).max(r.abs()))
+    }
+
+    #[test]
+    fn channel_mask_keeps_masked_channels_out_of_the_mix() {
+        let only_wave = ApuConfig { initial_channel_mask: 0x04, ..ApuConfig::default() };
+        assert_eq!(Apu::with_config(only_wave).channel_mask(), 0x04);
+        assert!(channel1_peak(ApuConfig::default()) > 0.0);
+        assert_eq!(channel1_peak(only_wave), 0.0);
+    }
+
+    #[test]
+    fn flush_drops_samples_and_restarts_from_zero() {
+        let mut apu = Apu::power_up(48000);
+        for (a, v) in [(0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0xFF), (0xFF11, 0x80), (0xFF12, 0xF0), (0xFF14, 0x87)] {
+            apu.set(a, v);
+        }
+        assert!(eighth_second(&mut apu) > 0);
+        apu.flush();
+        assert!(apu.buffer.lock().unwrap().is_empty());
+        assert_eq!(apu.channel1.blip.ampl, 0);
+        // Retriggered at volume 0: with the old level forgotten there's no step back down to click
+        apu.set(0xFF12, 0x00);
+        apu.set(0xFF14, 0x87);
+        assert!(eighth_second(&mut apu) > 0);
+        assert!(apu.buffer.lock().unwrap().iter().all(|&s| s == (0.0, 0.0)));
+    }
+
+    #[test]
+    fn div_reset_near_a_step_boundary() {
+        let period = cpu::CLOCK_FREQUENCY / 512;
+        let mut apu = Apu::power_up(48000);
+        apu.set(0xFF26, 0x80);
+        apu.set_div_apu_sync(true);
+
+        // A falling edge a few cycles short of the boundary steps early
+        apu.next(period - 4);
+        assert_eq!(apu.frame_step(), 0);
+        apu.div_reset(true);
+        assert_eq!(apu.frame_step(), 1);
+        // The count restarted from the write, so the old boundary passes quietly
+        apu.next(4);
+        assert_eq!(apu.frame_step(), 1);
+        apu.next(period - 4);
+        assert_eq!(apu.frame_step(), 2);
+
+        // No edge, the count still restarts but no step fires
+        apu.next(period - 4);
+        apu.div_reset(false);
+        apu.next(4);
+        assert_eq!(apu.frame_step(), 2);
+        apu.next(period - 4);
+        assert_eq!(apu.frame_step(), 3);
+
+        // Unsynced, a DIV write leaves the sequencer alone
+        apu.set_div_apu_sync(false);
+        apu.next(period - 4);
+        apu.div_reset(true);
+        assert_eq!(apu.frame_step(), 3);
+        apu.next(4);
+        assert_eq!(apu.frame_step(), 4);
+    }
+
+    #[test]
+    fn is_silent_until_a_routed_channel_plays() {
+        let mut apu = Apu::power_up(48000);
+        assert!(apu.is_silent());
+        apu.set(0xFF26, 0x80);
+        apu.set(0xFF25, 0x11);
+        assert!(apu.is_silent());
+        // Triggered at volume 0 is still silent
+        apu.set(0xFF12, 0x00);
+        apu.set(0xFF14, 0x80);
+        assert!(apu.is_silent());
+        apu.set(0xFF12, 0xF0);
+        apu.set(0xFF14, 0x80);
+        assert!(!apu.is_silent());
+        // Routed to neither side
+        apu.set(0xFF25, 0x22);
+        assert!(apu.is_silent());
+        apu.set(0xFF25, 0x01);
+        assert!(!apu.is_silent());
+        // Masked out of the mix
+        let mut masked = Apu::with_config(ApuConfig { initial_channel_mask: 0x0E, ..ApuConfig::default() });
+        for (a, v) in [(0xFF26, 0x80), (0xFF25, 0xFF), (0xFF12, 0xF0), (0xFF14, 0x80)] {
+            masked.set(a, v);
+        }
+        assert!(masked.is_silent());
+        apu.set(0xFF26, 0x00);
+        assert!(apu.is_silent());
+    }
+
+    #[test]
+    fn channel1_scope_fills_once_triggered() {
+        let mut apu = Apu::power_up(48000);
+        assert!(apu.channel_scope(1).is_empty());
+        apu.set_scope_enabled(true);
+        for (a, v) in [(0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0xFF)] {
+            apu.set(a, v);
+        }
+        eighth_second(&mut apu);
+        assert!(apu.channel_scope(1).iter().all(|&s| s == 0));
+        for (a, v) in [(0xFF11, 0x80), (0xFF12, 0xF0), (0xFF14, 0x87)] {
+            apu.set(a, v);
+        }
+        eighth_second(&mut apu);
+        let scope = apu.channel_scope(1);
+        assert_eq!(scope.len(), SCOPE_SAMPLES);
+        assert!(scope.iter().any(|&s| s != 0));
+        // The other channels weren't triggered
+        assert!(apu.channel_scope(2).iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn frame_step_runs_through_all_eight_steps_and_wraps() {
+        let period = cpu::CLOCK_FREQUENCY / 512;
+        let mut apu = Apu::power_up(48000);
+        apu.set(0xFF26, 0x80);
+        let steps = Rc::new(RefCell::new(Vec::new()));
+        let seen = steps.clone();
+        apu.set_frame_step_hook(Some(Box::new(move |step| seen.borrow_mut().push(step))));
+        let mut polled = Vec::new();
+        for _ in 0..9 {
+            apu.next(period);
+            polled.push(apu.frame_step());
+        }
+        assert_eq!(polled, [1, 2, 3, 4, 5, 6, 7, 0, 1]);
+        assert_eq!(*steps.borrow(), polled);
+    }
+
+    #[test]
+    fn target_latency_sets_the_buffer_cap() {
+        let mut apu = Apu::power_up(48000);
+        assert_eq!(apu.buffer_cap(), 48000);
+        apu.set_target_latency(Duration::from_millis(50));
+        assert_eq!(apu.buffer_cap(), 2400);
+        apu.set_sample_rate(44100);
+        assert_eq!(apu.buffer_cap(), 2205);
+    }
+}
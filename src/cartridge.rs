@@ -2,14 +2,44 @@ use std::time::SystemTime;
 use std::fs::File;
 use std::io::{Write, Read};
 use std::path::{Path, PathBuf};
+use super::error::{EmuError, LoadWarning};
 use super::mem::Memory;
 
 
 pub trait Stable {
-    fn sav(&self);
+    fn sav(&mut self);
+
+    /// Whether battery ram changed since the last sav
+    fn is_dirty(&self) -> bool {
+        false
+    }
+
+    /// Battery backed data as the .sav holds it, a cart with a clock appends its 8 byte epoch
+    fn sram(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Overwrites battery backed data with bytes from sram, written out by the next sav
+    /// * Extra bytes are ignored and missing ones leave the rest of the ram as it is
+    fn set_sram(&mut self, _: &[u8]) {}
 }
 
 
+/// Empty cartridge slot, reads float to 0xFF and writes go nowhere
+/// * For running code from HRAM or WRAM without a game
+pub struct NoCartridge;
+
+impl Memory for NoCartridge {
+    fn get(&self, _: u16) -> u8 {
+        0xFF
+    }
+
+    fn set(&mut self, _: u16, _: u8) {}
+}
+
+impl Stable for NoCartridge {
+    fn sav(&mut self) {}
+}
 
 pub struct RomOnly {
     rom: Vec<u8>,
@@ -29,7 +59,65 @@ impl Memory for RomOnly {
 }
 
 impl Stable for RomOnly {
-    fn sav(&self) {}
+    fn sav(&mut self) {}
+}
+
+/// 32KB rom with up to 8KB of plain ram at 0xA000, no banking
+pub struct RomRam {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    sav_path: PathBuf,
+    dirty: bool,
+}
+
+impl RomRam {
+    pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+        Self { rom, ram, sav_path: PathBuf::from(sav.as_ref()), dirty: false }
+    }
+}
+
+impl Memory for RomRam {
+    fn get(&self, a: u16) -> u8 {
+        match a {
+            0x0000..=0x7FFF => self.rom.get(a as usize).copied().unwrap_or(0xFF),
+            0xA000..=0xBFFF if !self.ram.is_empty() => self.ram[ram_index(&self.ram, 0, a)],
+            _ => 0x00,
+        }
+    }
+
+    fn set(&mut self, a: u16, v: u8) {
+        if let 0xA000..=0xBFFF = a {
+            if !self.ram.is_empty() {
+                let i = ram_index(&self.ram, 0, a);
+                self.ram[i] = v;
+                self.dirty = true;
+            }
+        }
+    }
+}
+
+impl Stable for RomRam {
+    fn sav(&mut self) {
+        if !self.dirty || self.sav_path.to_str().unwrap().is_empty() {
+            return;
+        }
+        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap();
+        self.dirty = false;
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn sram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn set_sram(&mut self, data: &[u8]) {
+        let n = data.len().min(self.ram.len());
+        self.ram[..n].copy_from_slice(&data[..n]);
+        self.dirty = true;
+    }
 }
 
 enum BankMode {
@@ -42,21 +130,39 @@ pub struct Mbc1 {
     ram: Vec<u8>,
     bank_mode: BankMode,
     bank: u8,
+    multicart: bool,
     ram_enabled: bool,
     sav_path: PathBuf,
+    dirty: bool,
 }
 
 impl Mbc1 {
     pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
-        Mbc1 {rom , ram, bank_mode: BankMode::Rom, bank: 0x01, ram_enabled: false, sav_path: PathBuf::from(sav.as_ref()),}
+        // MBC1M multicarts are 1MB with a second game header at bank 0x10
+        let multicart = rom.len() == 0x10_0000 && rom[0x4_0104..0x4_0134] == NINTENDO_LOGO[..];
+        Mbc1 {rom , ram, bank_mode: BankMode::Rom, bank: 0x01, multicart, ram_enabled: false, sav_path: PathBuf::from(sav.as_ref()), dirty: false }
+    }
+
+    /// Bits 5-6 of bank are the upper rom bits, on multicarts they sit one bit lower
+    fn upper_bits(&self) -> u8 {
+        let shift = if self.multicart { 4 } else { 5 };
+        ((self.bank & 0x60) >> 5) << shift
     }
+
+    /// Bank mapped to 0x4000-0x7FFF, the upper bits apply in both modes
     fn rom_bank(&self) -> usize {
-        let n = match self.bank_mode {
-            BankMode::Rom => self.bank & 0x7F,
-            BankMode::Ram => self.bank & 0x1F,
-        };
-        n as usize
+        let lower = if self.multicart { self.bank & 0x0F } else { self.bank & 0x1F };
+        (self.upper_bits() | lower) as usize
     }
+
+    /// Bank mapped to 0x0000-0x3FFF, only moved by the upper bits in mode 1
+    fn zero_bank(&self) -> usize {
+        match self.bank_mode {
+            BankMode::Rom => 0x00,
+            BankMode::Ram => self.upper_bits() as usize,
+        }
+    }
+
     fn ram_bank(&self) -> usize {
         let n = match self.bank_mode {
             BankMode::Rom => 0x00,
@@ -69,15 +175,11 @@ impl Mbc1 {
 impl Memory for Mbc1 {
     fn get(&self, a: u16) -> u8 {
         match a {
-            0x0000..=0x3FFF => self.rom[a as usize],
-            0x4000..=0x7FFF => {
-                let i = self.rom_bank() * 0x4000 + a as usize - 0x4000;
-                self.rom[i]
-            }
+            0x0000..=0x3FFF => self.rom[(self.zero_bank() * 0x4000 + a as usize) % self.rom.len()],
+            0x4000..=0x7FFF => self.rom[rom_index(&self.rom, self.rom_bank(), a)],
             0xA000..=0xBFFF => {
-                if self.ram_enabled {
-                    let i = self.ram_bank() * 0x2000 + a as usize - 0xA000;
-                    self.ram[i]
+                if self.ram_enabled && !self.ram.is_empty() {
+                    self.ram[ram_index(&self.ram, self.ram_bank(), a)]
                 } else {
                     0x00
                 }
@@ -89,9 +191,10 @@ impl Memory for Mbc1 {
     fn set(&mut self, a: u16, v: u8) {
         match a {
             0xA000..=0xBFFF => {
-                if self.ram_enabled {
-                    let i = self.ram_bank() * 0x2000 + a as usize - 0xA000;
+                if self.ram_enabled && !self.ram.is_empty() {
+                    let i = ram_index(&self.ram, self.ram_bank(), a);
                     self.ram[i] = v;
+                    self.dirty = true;
                 }
             }
             0x0000..=0x1FFF => {
@@ -109,10 +212,9 @@ impl Memory for Mbc1 {
                 let n = v & 0x03;
                 self.bank = self.bank & 0x9F | (n << 5)
             }
-            0x6000..=0x7FFF => match v {
+            0x6000..=0x7FFF => match v & 0x01 {
                 0x00 => self.bank_mode = BankMode::Rom,
-                0x01 => self.bank_mode = BankMode::Ram,
-                n => panic!("Invalid Cart type {}", n),
+                _ => self.bank_mode = BankMode::Ram,
             }
             _ => {}
         }
@@ -120,12 +222,27 @@ impl Memory for Mbc1 {
 }
 
 impl Stable for Mbc1 {
-    fn sav(&self) {
-        dbg!("Ram is being persisted");
-        if self.sav_path.to_str().unwrap().is_empty() {
+    fn sav(&mut self) {
+        if !self.dirty || self.sav_path.to_str().unwrap().is_empty() {
             return;
         }
-        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap()
+        dbg!("Ram is being persisted");
+        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap();
+        self.dirty = false;
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn sram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn set_sram(&mut self, data: &[u8]) {
+        let n = data.len().min(self.ram.len());
+        self.ram[..n].copy_from_slice(&data[..n]);
+        self.dirty = true;
     }
 }
 
@@ -135,11 +252,12 @@ pub struct Mbc2 {
     rom_bank: usize,
     ram_enable: bool,
     sav_path: PathBuf,
+    dirty: bool,
 }
 
 impl Mbc2 {
     pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
-        Self { rom, ram, rom_bank: 1, ram_enable: false, sav_path: PathBuf::from(sav.as_ref()) }
+        Self { rom, ram, rom_bank: 1, ram_enable: false, sav_path: PathBuf::from(sav.as_ref()), dirty: false }
     }
 }
 
@@ -147,10 +265,7 @@ impl Memory for Mbc2 {
     fn get(&self, a: u16) -> u8 {
         match a {
             0x0000..=0x3FFF => self.rom[a as usize],
-            0x4000..=0x7FFF => {
-                let i = self.rom_bank * 0x4000 + a as usize - 0x4000;
-                self.rom[i]
-            }
+            0x4000..=0x7FFF => self.rom[rom_index(&self.rom, self.rom_bank, a)],
             0xA000..=0xA1FF => {
                 if self.ram_enable {
                     self.ram[(a - 0xA000) as usize]
@@ -167,7 +282,8 @@ impl Memory for Mbc2 {
         match a {
             0xA000..=0xA1FF => {
                 if self.ram_enable {
-                    self.ram[(a - 0xA000) as usize] = v
+                    self.ram[(a - 0xA000) as usize] = v;
+                    self.dirty = true;
                 }
             }
             0x0000..=0x1FFF => {
@@ -186,88 +302,154 @@ impl Memory for Mbc2 {
 }
 
 impl Stable for Mbc2 {
-    fn sav(&self) {
-        dbg!("Ram is being persisted");
-        if self.sav_path.to_str().unwrap().is_empty() {
+    fn sav(&mut self) {
+        if !self.dirty || self.sav_path.to_str().unwrap().is_empty() {
             return;
         }
-        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap()
+        dbg!("Ram is being persisted");
+        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap();
+        self.dirty = false;
     }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn sram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn set_sram(&mut self, data: &[u8]) {
+        let n = data.len().min(self.ram.len());
+        self.ram[..n].copy_from_slice(&data[..n]);
+        self.dirty = true;
+    }
+}
+
+/// In-game clock of an MBC3 cartridge as seen through its latched registers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtcTime {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    /// 0-511, the 9 bit day counter
+    pub days: u16,
+    /// Halt bit of DH, stored for the game but the clock keeps counting
+    pub halted: bool,
 }
 
 struct RTC {
     second: u8,
     minute: u8,
     hour: u8,
-    dl: u8,
-    dh: u8,
+    /// 9 bit day counter, DL holds bits 0-7 and bit 0 of DH holds bit 8
+    days: u16,
+    halt: bool,
+    /// Day counter overflowed past 511, stays set until the game writes DH
+    carry: bool,
     zero: u64,
     sav_path: PathBuf,
+    dirty: bool,
 } 
 
 impl RTC {
     fn power_up(sav_path: impl AsRef<Path>) -> Self {
-        let zero = match std::fs::read(sav_path.as_ref()){
+        // A fresh clock has to be written once so it keeps counting from the same zero
+        let (zero, dirty) = match std::fs::read(sav_path.as_ref()){
             Ok(ok) => {
                 let mut b: [u8; 8] = Default::default() ;
                 b.copy_from_slice(&ok);
-                u64::from_be_bytes(b)
+                (u64::from_be_bytes(b), false)
             }
-            Err(_) => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+            Err(_) => (now(), true),
         };
-        Self { zero, second: 0, minute: 0, hour: 0, dl: 0, dh: 0, sav_path: sav_path.as_ref().to_path_buf() }
+        Self { zero, second: 0, minute: 0, hour: 0, days: 0, halt: false, carry: false, sav_path: sav_path.as_ref().to_path_buf(), dirty }
+    }
+
+    /// Moves the epoch so the clock reads the given time now
+    fn set_time(&mut self, t: RtcTime) {
+        self.second = t.seconds;
+        self.minute = t.minutes;
+        self.hour = t.hours;
+        self.days = t.days & 0x01FF;
+        self.halt = t.halted;
+        self.carry = false;
+        self.rebase();
+    }
+
+    fn time(&self) -> RtcTime {
+        RtcTime { seconds: self.second, minutes: self.minute, hours: self.hour, days: self.days, halted: self.halt }
+    }
+
+    /// Moves the epoch so the clock keeps counting from the registers as they are now
+    /// * A set carry is kept by placing the epoch another 512 days back
+    fn rebase(&mut self) {
+        let days = u64::from(self.days) + if self.carry { 512 } else { 0 };
+        let elapsed = u64::from(self.second) + u64::from(self.minute) * 60 + u64::from(self.hour) * 3600 + days * 86400;
+        self.zero = now().saturating_sub(elapsed);
+        self.dirty = true;
     }
+
+    /// Latches the time elapsed since the epoch into the registers
     fn tic(&mut self) {
-        let d = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() - self.zero;
+        let d = now().saturating_sub(self.zero);
 
         self.second = (d % 60) as u8;
         self.minute = (d / 60 % 60) as u8;
         self.hour = (d / 3600 % 24) as u8;
-        let days = (d / 3600 / 24) as u16;
-        self.dl = (days % 256) as u8;
-        match days {
-            0x0000..=0x00ff => {}
-            0x0100..=0x01ff => { 
-                self.dh |= 0x01;
-            }
-            _ => {
-                self.dh |= 0x01;
-                self.dh |= 0x80;
-            }
-        }
+        let days = d / 3600 / 24;
+        self.days = (days % 512) as u16;
+        self.carry = days >= 512;
     }
 }
 
+/// Seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
 impl Memory for RTC {
+    /// DH packs day bit 8 in bit 0, halt in bit 6 and the day carry in bit 7
     fn get(&self, a: u16) -> u8 {
         match a {
             0x08 => self.second,
             0x09 => self.minute,
             0x0A => self.hour,
-            0x0B => self.dl,
-            0x0C => self.dh,
+            0x0B => self.days as u8,
+            0x0C => (self.days >> 8) as u8 | if self.halt { 0x40 } else { 0x00 } | if self.carry { 0x80 } else { 0x00 },
             _ => panic!("No entry"),
         }
     }
-    
+
+    /// Writes set the clock, it keeps counting from the new value
     fn set(&mut self, a: u16, v: u8) {
         match a {
             0x08 => self.second = v,
             0x09 => self.minute = v,
             0x0A => self.hour = v,
-            0x0B => self.dl = v,
-            0x0C => self.dh = v,
+            0x0B => self.days = (self.days & 0x0100) | u16::from(v),
+            0x0C => {
+                self.days = (self.days & 0x00FF) | (u16::from(v & 0x01) << 8);
+                self.halt = v & 0x40 != 0;
+                self.carry = v & 0x80 != 0;
+            }
             _ => panic!("No Entry"),
         }
+        self.rebase();
     }
 }
 
 impl Stable for RTC {
-    fn sav(&self) {
-        if self.sav_path.to_str().unwrap().is_empty() {
+    fn sav(&mut self) {
+        if !self.dirty || self.sav_path.to_str().unwrap().is_empty() {
             return;
         }
-        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.zero.to_be_bytes())).unwrap()
+        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.zero.to_be_bytes())).unwrap();
+        self.dirty = false;
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
     }
 }
 
@@ -275,15 +457,19 @@ pub struct Mbc3 {
     rom: Vec<u8>,
     ram: Vec<u8>,
     rtc: RTC,
+    timer: bool,
     rom_bank: usize,
     ram_bank: usize,
     ram_enable: bool,
     sav_path: PathBuf,
+    dirty: bool,
 }
 
 impl Mbc3 {
     pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>, rtc: impl AsRef<Path>) -> Self {
-        Self { rom, ram, rtc: RTC::power_up(rtc), rom_bank: 1, ram_bank: 0, ram_enable: false, sav_path: PathBuf::from(sav.as_ref()) }
+        // Only the TIMER variants, 0x0F and 0x10, have a clock chip
+        let timer = matches!(rom[0x0147], 0x0F | 0x10);
+        Self { rom, ram, rtc: RTC::power_up(rtc), timer, rom_bank: 1, ram_bank: 0, ram_enable: false, sav_path: PathBuf::from(sav.as_ref()), dirty: false }
     }
 }
 
@@ -291,17 +477,18 @@ impl Memory for Mbc3 {
     fn get(&self, a: u16) -> u8 {
         match a {
             0x0000..=0x3FFF => self.rom[a as usize],
-            0x4000..=0x7FFF => {
-                let i = self.rom_bank * 0x4000 + a as usize - 0x4000;
-                self.rom[i]
-            }
+            0x4000..=0x7FFF => self.rom[rom_index(&self.rom, self.rom_bank, a)],
             0xA000..=0xBFFF => {
                 if self.ram_enable {
                     if self.ram_bank <= 0x03 {
-                        let i = self.ram_bank * 0x2000 + a as usize - 0xA000;
-                        self.ram[i]
-                    } else {
+                        if self.ram.is_empty() {
+                            return 0x00;
+                        }
+                        self.ram[ram_index(&self.ram, self.ram_bank, a)]
+                    } else if (0x08..=0x0C).contains(&self.ram_bank) {
                         self.rtc.get(self.ram_bank as u16)
+                    } else {
+                        0xFF
                     }
                 } else {
                     0x00
@@ -316,9 +503,13 @@ impl Memory for Mbc3 {
             0xA000..=0xBFFF => {
                 if self.ram_enable {
                     if self.ram_bank <= 0x03 {
-                        let i = self.ram_bank * 0x2000 + a as usize - 0xA000;
+                        if self.ram.is_empty() {
+                            return;
+                        }
+                        let i = ram_index(&self.ram, self.ram_bank, a);
                         self.ram[i] = v;
-                    } else {
+                        self.dirty = true;
+                    } else if (0x08..=0x0C).contains(&self.ram_bank) {
                         self.rtc.set(self.ram_bank as u16, v)
                     }
                 }
@@ -349,13 +540,40 @@ impl Memory for Mbc3 {
 }
 
 impl Stable for Mbc3 {
-    fn sav(&self) {
-        println!("Ram is being persisted");
+    fn sav(&mut self) {
         self.rtc.sav();
-        if self.sav_path.to_str().unwrap().is_empty() {
+        if !self.dirty || self.sav_path.to_str().unwrap().is_empty() {
             return;
         }
+        println!("Ram is being persisted");
         File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap();
+        self.dirty = false;
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty || self.rtc.is_dirty()
+    }
+
+    fn sram(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if self.timer {
+            data.extend_from_slice(&self.rtc.zero.to_be_bytes());
+        }
+        data
+    }
+
+    fn set_sram(&mut self, data: &[u8]) {
+        let mut data = data;
+        // Only a RAM image with the footer on the end carries a clock, anything else is all RAM
+        if self.timer && data.len() == self.ram.len() + 8 {
+            let (ram, zero) = data.split_at(data.len() - 8);
+            self.rtc.zero = u64::from_be_bytes(zero.try_into().unwrap());
+            self.rtc.dirty = true;
+            data = ram;
+        }
+        let n = data.len().min(self.ram.len());
+        self.ram[..n].copy_from_slice(&data[..n]);
+        self.dirty = true;
     }
 }
 
@@ -366,11 +584,12 @@ pub struct Mbc5 {
     ram_bank: usize,
     ram_enable: bool,
     sav_path: PathBuf,
+    dirty: bool,
 }
 
 impl Mbc5 {
     pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
-        Self { rom, ram, rom_bank: 1, ram_bank: 0, ram_enable:false, sav_path: PathBuf::from(sav.as_ref()) }
+        Self { rom, ram, rom_bank: 1, ram_bank: 0, ram_enable:false, sav_path: PathBuf::from(sav.as_ref()), dirty: false }
     }
 }
 
@@ -378,14 +597,10 @@ impl Memory for Mbc5 {
     fn get(&self, a: u16) -> u8 {
         match a {
             0x0000..=0x3FFF => self.rom[a as usize],
-            0x4000..=0x7FFF => {
-                let i = self.rom_bank * 0x4000 + a as usize - 0x4000;
-                self.rom[i]
-            }
+            0x4000..=0x7FFF => self.rom[rom_index(&self.rom, self.rom_bank, a)],
             0xA000..=0xBFFF => {
-                if self.ram_enable {
-                    let i = self.ram_bank * 0x2000 + a as usize - 0xA000;
-                    self.ram[i]
+                if self.ram_enable && !self.ram.is_empty() {
+                    self.ram[ram_index(&self.ram, self.ram_bank, a)]
                 } else {
                     0x00
                 }
@@ -397,9 +612,10 @@ impl Memory for Mbc5 {
     fn set(&mut self, a: u16, v: u8) {
         match a {
             0xA000..=0xBFFF => {
-                if self.ram_enable {
-                    let i = self.ram_bank * 0x2000 + a as usize - 0xA000;
+                if self.ram_enable && !self.ram.is_empty() {
+                    let i = ram_index(&self.ram, self.ram_bank, a);
                     self.ram[i] = v;
+                    self.dirty = true;
                 }
             }
             0x0000..=0x1FFF => {
@@ -414,12 +630,110 @@ impl Memory for Mbc5 {
 }
 
 impl Stable for Mbc5 {
-    fn sav(&self) {
+    fn sav(&mut self) {
+        if !self.dirty || self.sav_path.to_str().unwrap().is_empty() {
+            return;
+        }
         dbg!("Ram is being persisted");
-        if self.sav_path.to_str().unwrap().is_empty() {
+        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap();
+        self.dirty = false;
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn sram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn set_sram(&mut self, data: &[u8]) {
+        let n = data.len().min(self.ram.len());
+        self.ram[..n].copy_from_slice(&data[..n]);
+        self.dirty = true;
+    }
+}
+
+/// Multicart mapper that boots into a menu stored in the last 32KB of rom
+/// * While unmapped, writes to 0x2000-0x3FFF pick the base bank of a game
+/// * A write to 0x0000-0x1FFF with bit 6 set locks the base and the game then banks like MBC1 relative to it
+pub struct Mmm01 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mapped: bool,
+    base: usize,
+    rom_bank: usize,
+    ram_bank: usize,
+    ram_enable: bool,
+    sav_path: PathBuf,
+    dirty: bool,
+}
+
+impl Mmm01 {
+    pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+        Self { rom, ram, mapped: false, base: 0, rom_bank: 1, ram_bank: 0, ram_enable: false, sav_path: PathBuf::from(sav.as_ref()), dirty: false }
+    }
+}
+
+impl Memory for Mmm01 {
+    fn get(&self, a: u16) -> u8 {
+        match a {
+            0x0000..=0x7FFF if !self.mapped => {
+                let menu = self.rom.len().saturating_sub(0x8000);
+                self.rom[(menu + a as usize) % self.rom.len()]
+            }
+            0x0000..=0x3FFF => self.rom[(self.base * 0x4000 + a as usize) % self.rom.len()],
+            0x4000..=0x7FFF => self.rom[rom_index(&self.rom, self.base + self.rom_bank, a)],
+            0xA000..=0xBFFF if self.ram_enable && !self.ram.is_empty() => self.ram[ram_index(&self.ram, self.ram_bank, a)],
+            _ => 0x00,
+        }
+    }
+
+    fn set(&mut self, a: u16, v: u8) {
+        match a {
+            0xA000..=0xBFFF if self.ram_enable && !self.ram.is_empty() => {
+                let i = ram_index(&self.ram, self.ram_bank, a);
+                self.ram[i] = v;
+                self.dirty = true;
+            }
+            0x0000..=0x1FFF => {
+                self.ram_enable = v & 0x0F == 0x0A;
+                self.mapped |= v & 0x40 != 0;
+            }
+            0x2000..=0x3FFF if !self.mapped => self.base = (v & 0x3F) as usize,
+            0x2000..=0x3FFF => {
+                self.rom_bank = match v & 0x1F {
+                    0x00 => 0x01,
+                    n => n as usize,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_bank = (v & 0x03) as usize,
+            _ => {}
+        }
+    }
+}
+
+impl Stable for Mmm01 {
+    fn sav(&mut self) {
+        if !self.dirty || self.sav_path.to_str().unwrap().is_empty() {
             return;
         }
-        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap()
+        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap();
+        self.dirty = false;
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn sram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn set_sram(&mut self, data: &[u8]) {
+        let n = data.len().min(self.ram.len());
+        self.ram[..n].copy_from_slice(&data[..n]);
+        self.dirty = true;
     }
 }
 
@@ -444,32 +758,413 @@ impl Memory for HuC1 {
 }
 
 impl Stable for HuC1 {
-    fn sav(&self) {
+    fn sav(&mut self) {
         self.cart.sav()
     }
+
+    fn is_dirty(&self) -> bool {
+        self.cart.is_dirty()
+    }
+
+    fn sram(&self) -> Vec<u8> {
+        self.cart.sram()
+    }
+
+    fn set_sram(&mut self, data: &[u8]) {
+        self.cart.set_sram(data)
+    }
+}
+
+/// 93LC56 serial EEPROM of MBC7 carts, 128 16-bit words driven over CS, CLK, DI and DO
+struct Eeprom {
+    words: Vec<u8>,
+    cs: bool,
+    clk: bool,
+    di: bool,
+    /// Level of the DO line, 1 means ready
+    out: bool,
+    /// Bits clocked in since the start bit, start bit included
+    command: u32,
+    bits: u8,
+    /// Word being shifted out by READ
+    read: u16,
+    read_bits: u8,
+    writable: bool,
+}
+
+impl Eeprom {
+    fn power_up(words: Vec<u8>) -> Self {
+        Self { words, cs: false, clk: false, di: false, out: true, command: 0, bits: 0, read: 0, read_bits: 0, writable: false }
+    }
+
+    fn get(&self) -> u8 {
+        u8::from(self.cs) << 7 | u8::from(self.clk) << 6 | u8::from(self.di) << 1 | u8::from(self.out)
+    }
+
+    fn word(&self, addr: usize) -> u16 {
+        u16::from_le_bytes([self.words[addr * 2], self.words[addr * 2 + 1]])
+    }
+
+    fn set_word(&mut self, addr: usize, v: u16) {
+        self.words[addr * 2..addr * 2 + 2].copy_from_slice(&v.to_le_bytes());
+    }
+
+    /// Feeds a write to the Ax8x register, DI is sampled on the rising edge of CLK while CS is high
+    /// * Returns whether a word was written so the owner can mark the save dirty
+    fn set(&mut self, v: u8) -> bool {
+        let rising = !self.clk && v & 0x40 != 0;
+        self.cs = v & 0x80 != 0;
+        self.clk = v & 0x40 != 0;
+        self.di = v & 0x02 != 0;
+        if !self.cs {
+            self.command = 0;
+            self.bits = 0;
+            self.read_bits = 0;
+            return false;
+        }
+        if !rising {
+            return false;
+        }
+        if self.read_bits > 0 {
+            self.out = self.read & 0x8000 != 0;
+            self.read <<= 1;
+            self.read_bits -= 1;
+            return false;
+        }
+        // Leading zeros before the start bit are ignored
+        if self.bits == 0 && !self.di {
+            return false;
+        }
+        self.command = self.command << 1 | u32::from(self.di);
+        self.bits += 1;
+        self.exec()
+    }
+
+    /// Runs the command once enough bits arrived, 1 start bit, 2 opcode bits, 8 address bits and 16 data bits for writes
+    fn exec(&mut self) -> bool {
+        if self.bits < 11 {
+            return false;
+        }
+        let head = self.command >> (self.bits - 11);
+        let op = (head >> 8) & 0x03;
+        let addr = (head & 0x7F) as usize;
+        let needs_data = op == 0x01 || (op == 0x00 && (head >> 6) & 0x03 == 0x01);
+        if needs_data && self.bits < 27 {
+            return false;
+        }
+        let data = self.command as u16;
+        let mut written = false;
+        match (op, (head >> 6) & 0x03) {
+            // READ, a dummy 0 comes first
+            (0x02, _) => {
+                self.read = self.word(addr);
+                self.read_bits = 16;
+                self.out = false;
+            }
+            (0x01, _) if self.writable => {
+                self.set_word(addr, data);
+                written = true;
+            }
+            (0x03, _) if self.writable => {
+                self.set_word(addr, 0xFFFF);
+                written = true;
+            }
+            (0x00, 0x03) => self.writable = true,
+            (0x00, 0x00) => self.writable = false,
+            (0x00, 0x02) if self.writable => {
+                self.words.iter_mut().for_each(|b| *b = 0xFF);
+                written = true;
+            }
+            (0x00, 0x01) if self.writable => {
+                (0..self.words.len() / 2).for_each(|i| self.set_word(i, data));
+                written = true;
+            }
+            _ => {}
+        }
+        if self.read_bits == 0 {
+            self.out = true;
+        }
+        self.command = 0;
+        self.bits = 0;
+        written
+    }
+}
+
+/// Accelerometer reading with the cart held flat
+const MBC7_CENTER: u16 = 0x81D0;
+/// Change of the reading per g of tilt
+const MBC7_PER_G: f32 = 112.0;
+
+/// MBC7 carts like Kirby Tilt 'n' Tumble, a two axis accelerometer and a serial EEPROM behind 0xA000
+/// * The registers repeat every 16 bytes over 0xA000-0xAFFF and need both ram enables
+/// * Rumble isn't emulated
+pub struct Mbc7 {
+    rom: Vec<u8>,
+    rom_bank: usize,
+    enable1: bool,
+    enable2: bool,
+    tilt: (f32, f32),
+    latched: (u16, u16),
+    latch_armed: bool,
+    eeprom: Eeprom,
+    sav_path: PathBuf,
+    dirty: bool,
+}
+
+impl Mbc7 {
+    pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+        let mut ram = ram;
+        ram.resize(256, 0xFF);
+        Self {
+            rom,
+            rom_bank: 1,
+            enable1: false,
+            enable2: false,
+            tilt: (0.0, 0.0),
+            latched: (0x8000, 0x8000),
+            latch_armed: false,
+            eeprom: Eeprom::power_up(ram),
+            sav_path: PathBuf::from(sav.as_ref()),
+            dirty: false,
+        }
+    }
+}
+
+impl Memory for Mbc7 {
+    fn get(&self, a: u16) -> u8 {
+        match a {
+            0x0000..=0x3FFF => self.rom[a as usize],
+            0x4000..=0x7FFF => self.rom[rom_index(&self.rom, self.rom_bank, a)],
+            0xA000..=0xAFFF if self.enable1 && self.enable2 => match (a >> 4) & 0x0F {
+                0x02 => self.latched.0 as u8,
+                0x03 => (self.latched.0 >> 8) as u8,
+                0x04 => self.latched.1 as u8,
+                0x05 => (self.latched.1 >> 8) as u8,
+                0x06 => 0x00,
+                0x08 => self.eeprom.get(),
+                _ => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn set(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => self.enable1 = v == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = usize::from(v & 0x7F),
+            0x4000..=0x5FFF => self.enable2 = v == 0x40,
+            0xA000..=0xAFFF if self.enable1 && self.enable2 => match (a >> 4) & 0x0F {
+                // Writing 0x55 then 0xAA samples the accelerometer
+                0x00 if v == 0x55 => {
+                    self.latch_armed = true;
+                    self.latched = (0x8000, 0x8000);
+                }
+                0x01 if v == 0xAA && self.latch_armed => {
+                    self.latch_armed = false;
+                    let axis = |g: f32| (f32::from(MBC7_CENTER) + g * MBC7_PER_G) as u16;
+                    self.latched = (axis(self.tilt.0), axis(self.tilt.1));
+                }
+                0x08 => self.dirty |= self.eeprom.set(v),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
 }
 
+impl Stable for Mbc7 {
+    fn sav(&mut self) {
+        if !self.dirty || self.sav_path.to_str().unwrap().is_empty() {
+            return;
+        }
+        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.eeprom.words)).unwrap();
+        self.dirty = false;
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn sram(&self) -> Vec<u8> {
+        self.eeprom.words.clone()
+    }
+
+    fn set_sram(&mut self, data: &[u8]) {
+        let n = data.len().min(self.eeprom.words.len());
+        self.eeprom.words[..n].copy_from_slice(&data[..n]);
+        self.dirty = true;
+    }
+}
+
+/// Pocket Camera and Bandai TAMA5 carts, only banked rom and ram are emulated
+/// * Enough to reach the title screen, the camera sensor and TAMA5 registers are logged once and ignored
+pub struct Unsupported {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    kind: u8,
+    rom_bank: usize,
+    ram_bank: usize,
+    ram_enabled: bool,
+    reported: Vec<&'static str>,
+    sav_path: PathBuf,
+    dirty: bool,
+}
+
+impl Unsupported {
+    pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+        let kind = rom[0x0147];
+        eprintln!("Warning: {} is not fully supported", describe(kind));
+        Self { rom, ram, kind, rom_bank: 1, ram_bank: 0, ram_enabled: false, reported: Vec::new(), sav_path: PathBuf::from(sav.as_ref()), dirty: false }
+    }
+
+    fn report(&mut self, feature: &'static str) {
+        if !self.reported.contains(&feature) {
+            eprintln!("Warning: {} accessed {}, which is not emulated", describe(self.kind), feature);
+            self.reported.push(feature);
+        }
+    }
+
+    /// The camera maps its sensor registers over ram when bank 0x10 is selected
+    fn camera_registers(&self) -> bool {
+        self.kind == 0xFC && self.ram_bank & 0x10 != 0
+    }
+}
+
+impl Memory for Unsupported {
+    fn get(&self, a: u16) -> u8 {
+        match a {
+            0x0000..=0x3FFF => self.rom[a as usize],
+            0x4000..=0x7FFF => self.rom[rom_index(&self.rom, self.rom_bank, a)],
+            0xA000..=0xBFFF if self.camera_registers() => 0x00,
+            0xA000..=0xBFFF if self.ram_enabled && !self.ram.is_empty() => self.ram[ram_index(&self.ram, self.ram_bank, a)],
+            _ => 0xFF,
+        }
+    }
+
+    fn set(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => self.ram_enabled = v & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = usize::from(v & 0x7F).max(1),
+            0x4000..=0x5FFF => self.ram_bank = usize::from(v & 0x1F),
+            0xA000..=0xBFFF if self.kind == 0xFD => self.report("the TAMA5 registers"),
+            0xA000..=0xBFFF if self.camera_registers() => self.report("the camera sensor"),
+            0xA000..=0xBFFF if self.ram_enabled && !self.ram.is_empty() => {
+                let i = ram_index(&self.ram, self.ram_bank, a);
+                self.ram[i] = v;
+                self.dirty = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Stable for Unsupported {
+    fn sav(&mut self) {
+        if !self.dirty || self.sav_path.to_str().unwrap().is_empty() {
+            return;
+        }
+        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap();
+        self.dirty = false;
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn sram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn set_sram(&mut self, data: &[u8]) {
+        let n = data.len().min(self.ram.len());
+        self.ram[..n].copy_from_slice(&data[..n]);
+        self.dirty = true;
+    }
+}
+
+/// Loads a cartridge from a rom file, `-` reads the rom from stdin
+/// * gzip and zip archives are decompressed before the header is parsed
+/// * Panics on anything try_power_up reports as an error
 pub fn power_up(path: impl AsRef<Path>) -> Box<dyn Cartridge> {
+    try_power_up(path).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Builds a cartridge from rom bytes already in memory
+/// * path is only used to place the .sav and .rtc files, an empty path disables saving
+pub fn power_up_from_bytes(rom: Vec<u8>, path: impl AsRef<Path>) -> Box<dyn Cartridge> {
+    try_power_up_from_bytes(rom, path).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Checks loading a rom can relax, the defaults load whatever real hardware would run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadOptions {
+    /// A wrong header checksum only gives a warning, on by default since hacks and translations often ship one
+    /// * Only the boot rom checks it, so real hardware with a flashcart runs these roms fine
+    pub allow_bad_checksum: bool,
+    /// Gives cartridges all the ram their mapper can bank instead of the size in the header, off by default
+    /// * Some homebrew declares less ram than it uses, spec sized ram wraps those extra banks onto the first ones
+    pub lenient_ram: bool,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self { allow_bad_checksum: true, lenient_ram: false }
+    }
+}
+
+/// Like power_up but returns unreadable, corrupt and unsupported roms as errors
+/// * Warnings are dropped, try_power_up_with hands them back
+pub fn try_power_up(path: impl AsRef<Path>) -> Result<Box<dyn Cartridge>, EmuError> {
+    try_power_up_with(path, LoadOptions::default()).map(|(cart, _)| cart)
+}
+
+/// Like try_power_up with the given options, also returns what was odd about a rom that loaded anyway
+pub fn try_power_up_with(path: impl AsRef<Path>, options: LoadOptions) -> Result<(Box<dyn Cartridge>, Vec<LoadWarning>), EmuError> {
     dbg!(path.as_ref());
-    let mut file = File::open(path.as_ref()).unwrap();
+    let io = |source| EmuError::Io { path: path.as_ref().to_path_buf(), source };
     let mut rom = Vec::new();
-    file.read_to_end(&mut rom).unwrap();
+    if path.as_ref() == Path::new("-") {
+        std::io::stdin().read_to_end(&mut rom).map_err(io)?;
+        // No file to save next to so battery ram won't be persisted
+        return try_power_up_from_bytes_with(rom, "", options);
+    }
+    File::open(path.as_ref()).and_then(|mut f| f.read_to_end(&mut rom)).map_err(io)?;
+    try_power_up_from_bytes_with(rom, path, options)
+}
+
+/// Like power_up_from_bytes but returns corrupt and unsupported roms as errors
+/// * Warnings are dropped, try_power_up_from_bytes_with hands them back
+pub fn try_power_up_from_bytes(rom: Vec<u8>, path: impl AsRef<Path>) -> Result<Box<dyn Cartridge>, EmuError> {
+    try_power_up_from_bytes_with(rom, path, LoadOptions::default()).map(|(cart, _)| cart)
+}
+
+/// Like try_power_up_from_bytes with the given options, also returns what was odd about a rom that loaded anyway
+pub fn try_power_up_from_bytes_with(rom: Vec<u8>, path: impl AsRef<Path>, options: LoadOptions) -> Result<(Box<dyn Cartridge>, Vec<LoadWarning>), EmuError> {
+    let bad = |reason: String| EmuError::BadRom { path: path.as_ref().to_path_buf(), reason };
+    let rom = unpack(rom).map_err(|source| EmuError::Io { path: path.as_ref().to_path_buf(), source })?;
     if rom.len() < 0x150 {
-        panic!("Missing important information")
+        return Err(bad(String::from("Missing important information")));
+    }
+    let header = mmm01_header(&rom);
+    if !matches!(rom[header + 0x0148], 0x00..=0x08 | 0x52..=0x54) {
+        return Err(bad(format!("Rom size 0x{:02x} is not supported", rom[header + 0x0148])));
     }
-    let rom_maximum = rom_size(rom[0x0148]);
+    if rom[header + 0x0149] > 0x05 {
+        return Err(bad(format!("Unsupported ram size at 0x{:02x}", rom[header + 0x0149])));
+    }
+    let rom_maximum = rom_size(rom[header + 0x0148]);
     if rom.len() > rom_maximum {
-        panic!("Rom is larger than maximum {}", rom_maximum)
+        return Err(bad(format!("Rom is larger than maximum {}", rom_maximum)));
     }
-    let cart: Box<dyn Cartridge> = match rom[0x0147] {
+    let cart: Box<dyn Cartridge> = match rom[header + 0x0147] {
         0x00 => Box::new(RomOnly::power_up(rom)),
         0x01 => Box::new(Mbc1::power_up(rom, vec![], "")),
         0x02 => {
-            let ram_maximum = ram_size(rom[0x0149]);
+            let ram_maximum = ram_capacity(rom[0x0149], 0x8000, options.lenient_ram);
             Box::new(Mbc1::power_up(rom, vec![0; ram_maximum], ""))
         }
         0x03 => {
-            let ram_maximum = ram_size(rom[0x0149]);
+            let ram_maximum = ram_capacity(rom[0x0149], 0x8000, options.lenient_ram);
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
             let ram = ram_read(sav_path.clone(), ram_maximum);
             Box::new(Mbc1::power_up(rom, ram, sav_path))
@@ -484,13 +1179,34 @@ pub fn power_up(path: impl AsRef<Path>) -> Box<dyn Cartridge> {
             let ram = ram_read(sav_path.clone(), ram_maximum);
             Box::new(Mbc2::power_up(rom, ram, sav_path))
         }
+        0x08 => {
+            let ram_maximum = ram_capacity(rom[0x0149], 0x2000, options.lenient_ram);
+            Box::new(RomRam::power_up(rom, vec![0; ram_maximum], ""))
+        }
+        0x09 => {
+            let ram_maximum = ram_capacity(rom[0x0149], 0x2000, options.lenient_ram);
+            let sav_path = path.as_ref().to_path_buf().with_extension("sav");
+            let ram = ram_read(sav_path.clone(), ram_maximum);
+            Box::new(RomRam::power_up(rom, ram, sav_path))
+        }
+        0x0B => Box::new(Mmm01::power_up(rom, vec![], "")),
+        0x0C => {
+            let ram_maximum = ram_capacity(rom[header + 0x0149], 0x8000, options.lenient_ram);
+            Box::new(Mmm01::power_up(rom, vec![0; ram_maximum], ""))
+        }
+        0x0D => {
+            let ram_maximum = ram_capacity(rom[header + 0x0149], 0x8000, options.lenient_ram);
+            let sav_path = path.as_ref().to_path_buf().with_extension("sav");
+            let ram = ram_read(sav_path.clone(), ram_maximum);
+            Box::new(Mmm01::power_up(rom, ram, sav_path))
+        }
         0x0f => {
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
             let rtc_path = path.as_ref().to_path_buf().with_extension("rtc");
             Box::new(Mbc3::power_up(rom, vec![], sav_path, rtc_path))
         }
         0x10 => {
-            let ram_maximum = ram_size(rom[0x0149]);
+            let ram_maximum = ram_capacity(rom[0x0149], 0x1_0000, options.lenient_ram);
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
             let ram = ram_read(sav_path.clone(), ram_maximum);
             let rtc_path = path.as_ref().to_path_buf().with_extension("rtc");
@@ -498,42 +1214,99 @@ pub fn power_up(path: impl AsRef<Path>) -> Box<dyn Cartridge> {
         }
         0x11 => Box::new(Mbc3::power_up(rom, vec![], "", "")),
         0x12 => {
-            let ram_maximum = ram_size(rom[0x0149]);
+            let ram_maximum = ram_capacity(rom[0x0149], 0x1_0000, options.lenient_ram);
             Box::new(Mbc3::power_up(rom, vec![0; ram_maximum], "", ""))
         }
         0x13 => {
-            let ram_maximum = ram_size(rom[0x0149]);
+            let ram_maximum = ram_capacity(rom[0x0149], 0x1_0000, options.lenient_ram);
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
             let ram = ram_read(sav_path.clone(), ram_maximum);
             Box::new(Mbc3::power_up(rom, ram, sav_path, ""))
         }
         0x19 => Box::new(Mbc5::power_up(rom, vec![], "")),
         0x1A => {
-            let ram_maximum = ram_size(rom[0x0149]);
+            let ram_maximum = ram_capacity(rom[0x0149], 0x2_0000, options.lenient_ram);
             Box::new(Mbc5::power_up(rom, vec![0; ram_maximum], ""))
         }
         0x1B => {
-            let ram_maximum = ram_size(rom[0x0149]);
+            let ram_maximum = ram_capacity(rom[0x0149], 0x2_0000, options.lenient_ram);
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
             let ram = ram_read(sav_path.clone(), ram_maximum);
             Box::new(Mbc5::power_up(rom, ram, sav_path))
         }
-        0xFF => {
+        0x22 => {
+            let sav_path = path.as_ref().to_path_buf().with_extension("sav");
+            let ram = ram_read(sav_path.clone(), 256);
+            Box::new(Mbc7::power_up(rom, ram, sav_path))
+        }
+        0xFC | 0xFD => {
             let ram_maximum = ram_size(rom[0x0149]);
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
             let ram = ram_read(sav_path.clone(), ram_maximum);
+            Box::new(Unsupported::power_up(rom, ram, sav_path))
+        }
+        0xFF => {
+            let ram_maximum = ram_capacity(rom[0x0149], 0x8000, options.lenient_ram);
+            let sav_path = path.as_ref().to_path_buf().with_extension("sav");
+            let ram = ram_read(sav_path.clone(), ram_maximum);
             Box::new(HuC1::power_up(rom, ram, sav_path))
         }
-        n => panic!("Unsupported cartridge type: 0x{:02x}", n),
+        n => return Err(EmuError::UnsupportedCartridge { path: path.as_ref().to_path_buf(), mbc: n }),
     };
     dbg!(cart.title());
-    dbg!(cart_type(cart.get(0x0147)));
-    ensure_logo(cart.as_ref());
-    ensure_header_checksum(cart.as_ref());
-    cart
+    dbg!(describe(cart.get(0x0147)));
+    ensure_logo(cart.as_ref()).map_err(bad)?;
+    let warnings = ensure_header_checksum(cart.as_ref(), options.allow_bad_checksum).map_err(bad)?.into_iter().collect();
+    Ok((cart, warnings))
+}
+
+
+
+/// MMM01 carts keep the menu, and the header describing the whole cart, in the last 32KB
+/// * Returns the offset of the header to parse, 0 for every other cart
+fn mmm01_header(rom: &[u8]) -> usize {
+    let menu = rom.len().saturating_sub(0x8000);
+    match rom.get(menu + 0x0147) {
+        Some(0x0B..=0x0D) if menu > 0 => menu,
+        _ => 0,
+    }
 }
 
+/// Decompresses gzip and zip archives by sniffing their magic bytes, plain roms are returned untouched
+/// * For zips with several entries the first .gb/.gbc file is used
+fn unpack(data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    match data.get(0..4) {
+        Some([0x1F, 0x8B, _, _]) => {
+            let mut rom = Vec::new();
+            flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut rom)?;
+            Ok(rom)
+        }
+        Some([0x50, 0x4B, 0x03, 0x04]) => {
+            let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+            let index = (0..archive.len())
+                .find(|&i| {
+                    let name = archive.name_for_index(i).unwrap_or_default().to_lowercase();
+                    name.ends_with(".gb") || name.ends_with(".gbc")
+                })
+                .unwrap_or(0);
+            let mut rom = Vec::new();
+            archive.by_index(index)?.read_to_end(&mut rom)?;
+            Ok(rom)
+        }
+        _ => Ok(data),
+    }
+}
 
+/// Offset of an address in the switchable rom area, banks past the end of the rom wrap around
+fn rom_index(rom: &[u8], bank: usize, a: u16) -> usize {
+    (bank * 0x4000 + a as usize - 0x4000) % rom.len()
+}
+
+/// Offset of an address in cartridge ram, banks past the end of the ram wrap around
+/// * Callers check the ram is not empty first
+fn ram_index(ram: &[u8], bank: usize, a: u16) -> usize {
+    (bank * 0x2000 + a as usize - 0xA000) % ram.len()
+}
 
 fn rom_size(byte: u8) -> usize {
     let bank = 16384;
@@ -566,19 +1339,80 @@ fn ram_size(byte: u8) -> usize {
     }
 }
 
+/// Ram to allocate for the header's size byte, or the mapper maximum when lenient
+fn ram_capacity(byte: u8, mapper_maximum: usize, lenient: bool) -> usize {
+    let declared = ram_size(byte);
+    if lenient {
+        declared.max(mapper_maximum)
+    } else {
+        declared
+    }
+}
+
+/// Loads battery ram from a .sav, a file shorter than size is padded so every bank exists
 fn ram_read(path: impl AsRef<Path>, size: usize) -> Vec<u8> {
     match File::open(path) {
         Ok(mut ok) => {
             let mut ram = Vec::new();
             ok.read_to_end(&mut ram).unwrap();
+            if ram.len() < size {
+                ram.resize(size, 0x00);
+            }
             ram
         }
         Err(_) => vec![0; size],
     }
 }
 
-fn cart_type(byte: u8) -> String {
-    String::from(match byte {
+/// Whether a cartridge type keeps its ram, or clock, alive with a battery
+pub fn has_battery(kind: u8) -> bool {
+    matches!(kind, 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFC | 0xFD | 0xFF)
+}
+
+/// Cartridge types power_up builds a working mapper for
+/// * The Pocket Camera and TAMA5 load on a stub that only banks rom and ram, so they aren't listed
+const SUPPORTED_TYPES: [u8; 21] = [
+    0x00, 0x01, 0x02, 0x03, 0x05, 0x06, 0x08, 0x09, 0x0B, 0x0C, 0x0D, 0x0F, 0x10, 0x11, 0x12, 0x13, 0x19, 0x1A, 0x1B, 0x22,
+    0xFF,
+];
+
+/// Whether a cartridge type byte (0x0147) is fully emulated
+pub fn is_supported(byte: u8) -> bool {
+    SUPPORTED_TYPES.contains(&byte)
+}
+
+/// Header summary of a rom file for `--info`, nothing is emulated or saved
+pub fn info(path: impl AsRef<Path>) -> Result<String, EmuError> {
+    let io = |source| EmuError::Io { path: path.as_ref().to_path_buf(), source };
+    let rom = unpack(std::fs::read(path.as_ref()).map_err(io)?).map_err(io)?;
+    if rom.len() < 0x150 {
+        return Err(EmuError::BadRom { path: path.as_ref().to_path_buf(), reason: String::from("Missing important information") });
+    }
+    let header = mmm01_header(&rom);
+    let kind = rom[header + 0x0147];
+    let title: String = rom[0x0134..0x0143].iter().take_while(|&&b| b != 0).map(|&b| b as char).collect();
+    let checksum = rom[0x0134..0x014D].iter().fold(0u8, |v, b| v.wrapping_sub(*b).wrapping_sub(1));
+    let verdict = match kind {
+        k if is_supported(k) => "supported",
+        0xFC | 0xFD => "boots on a stub, special hardware isn't emulated",
+        _ => "unsupported",
+    };
+    Ok(format!(
+        "Title:    {}\nType:     0x{:02X} {}\nRom size: 0x{:02X}\nRam size: 0x{:02X}\nCGB flag: 0x{:02X}\nChecksum: {}\nVerdict:  {}\n",
+        title,
+        kind,
+        describe(kind),
+        rom[header + 0x0148],
+        rom[header + 0x0149],
+        rom[0x0143],
+        if checksum == rom[0x014D] { "ok" } else { "bad" },
+        verdict,
+    ))
+}
+
+/// Name of a cartridge type byte (0x0147) as printed in the header documentation
+pub fn describe(byte: u8) -> &'static str {
+    match byte {
         0x00 => "ROM ONLY",
         0x01 => "MBC1",
         0x02 => "MBC1+RAM",
@@ -601,36 +1435,45 @@ fn cart_type(byte: u8) -> String {
         0x1C => "MBC5+RUMBLE",
         0x1D => "MBC5+RUMBLE+RAM",
         0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
         0xFC => "POCKET CAMERA",
         0xFD => "BANDAI TAMA5",
         0xFE => "HuC3",
-        0x1F => "HuC1+RAM+BATTERY",
-        n => panic!("Unsupported cartridge type 0x{:02x}", n),
-    })
+        0xFF => "HuC1+RAM+BATTERY",
+        _ => "UNKNOWN",
+    }
 }
 
-const NINTENDO_LOGO: [u8; 48] = [
+/// Logo the boot rom compares against 0x0104-0x0133
+pub const NINTENDO_LOGO: [u8; 48] = [
     0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11,
     0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E,
     0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
 ];
 
-fn ensure_logo(cart: &dyn Cartridge) {
+fn ensure_logo(cart: &dyn Cartridge) -> Result<(), String> {
     for i in 0..48 {
         if cart.get(0x0104 + i as u16) != NINTENDO_LOGO[i as usize] {
-            panic!("Nintendo logo is incorrect");
+            return Err(String::from("Nintendo logo is incorrect"));
         }
     }
+    Ok(())
 }
 
-fn ensure_header_checksum(cart: &dyn Cartridge) {
+/// A wrong checksum is an error unless allowed, then it comes back as a warning
+fn ensure_header_checksum(cart: &dyn Cartridge, allow_bad: bool) -> Result<Option<LoadWarning>, String> {
     let mut v: u8 = 0;
     for i in 0x0134..0x014d {
         v = v.wrapping_sub(cart.get(i)).wrapping_sub(1);
     }
-    if cart.get(0x014d) != v {
-        panic!("Cartridge checksum isn't correct")
+    if cart.get(0x014d) == v {
+        return Ok(None);
     }
+    if !allow_bad {
+        return Err(String::from("Cartridge checksum isn't correct"));
+    }
+    Ok(Some(LoadWarning::BadHeaderChecksum { found: cart.get(0x014d), expected: v }))
 }
 
 pub trait Cartridge: Memory + Stable + Send {
@@ -646,11 +1489,419 @@ pub trait Cartridge: Memory + Stable + Send {
         }
         buf
     }
+
+    /// Header flags CGB features, bit 7 of 0x0143, set on both enhanced and CGB only games
+    fn supports_cgb(&self) -> bool {
+        self.get(0x0143) & 0x80 != 0x00
+    }
+
+    /// Header says the game won't run on a DMG, 0x0143 is 0xC0
+    fn requires_cgb(&self) -> bool {
+        self.get(0x0143) == 0xC0
+    }
+
+    /// Header enables SGB functions, 0x0146 is 0x03
+    fn supports_sgb(&self) -> bool {
+        self.get(0x0146) == 0x03
+    }
+
+    /// Whether the cartridge type in the header keeps its ram or clock alive with a battery
+    /// * Read from the rom image rather than the bus, so banking can't hide the header
+    fn has_battery(&self) -> bool {
+        false
+    }
+
+    /// Current in-game clock, None for cartridges without one
+    fn get_rtc(&self) -> Option<RtcTime> {
+        None
+    }
+
+    /// Sets the in-game clock, persisted with the next sav
+    fn set_rtc(&mut self, _: RtcTime) {}
+
+    /// Feeds the accelerometer of tilt carts in g, x and y are clamped to -2.0..=2.0
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
 }
 
+impl Cartridge for NoCartridge {
+    fn title(&self) -> String {
+        String::new()
+    }
+}
 impl Cartridge for RomOnly {}
-impl Cartridge for Mbc1 {}
-impl Cartridge for Mbc2 {}
-impl Cartridge for Mbc3 {}
-impl Cartridge for Mbc5 {}
-impl Cartridge for HuC1 {}
\ No newline at end of file
+impl Cartridge for RomRam {
+    fn has_battery(&self) -> bool {
+        has_battery(self.rom[0x0147])
+    }
+}
+impl Cartridge for Mbc1 {
+    fn has_battery(&self) -> bool {
+        has_battery(self.rom[0x0147])
+    }
+}
+impl Cartridge for Mbc2 {
+    fn has_battery(&self) -> bool {
+        has_battery(self.rom[0x0147])
+    }
+}
+impl Cartridge for Mbc3 {
+    fn has_battery(&self) -> bool {
+        has_battery(self.rom[0x0147])
+    }
+
+    fn get_rtc(&self) -> Option<RtcTime> {
+        if !self.timer {
+            return None;
+        }
+        let mut rtc = RTC { sav_path: PathBuf::new(), ..self.rtc };
+        rtc.tic();
+        Some(rtc.time())
+    }
+
+    fn set_rtc(&mut self, t: RtcTime) {
+        if self.timer {
+            self.rtc.set_time(t);
+        }
+    }
+}
+impl Cartridge for Mbc5 {
+    fn has_battery(&self) -> bool {
+        has_battery(self.rom[0x0147])
+    }
+}
+impl Cartridge for Mmm01 {
+    fn has_battery(&self) -> bool {
+        has_battery(self.rom[mmm01_header(&self.rom) + 0x0147])
+    }
+}
+impl Cartridge for HuC1 {
+    fn has_battery(&self) -> bool {
+        self.cart.has_battery()
+    }
+}
+impl Cartridge for Unsupported {
+    fn has_battery(&self) -> bool {
+        has_battery(self.kind)
+    }
+}
+impl Cartridge for Mbc7 {
+    fn has_battery(&self) -> bool {
+        has_battery(self.rom[0x0147])
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x.clamp(-2.0, 2.0), y.clamp(-2.0, 2.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_roms;
+
+    #[test]
+    fn compressed_roms_load_like_the_raw_rom() {
+        let rom = test_roms::assemble("PACKED", &[0x3E, 0x42, 0x18, 0xFE]);
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&rom).unwrap();
+        let gz = gz.finish().unwrap();
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("readme.txt", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"not the rom").unwrap();
+        zip.start_file("packed.gb", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(&rom).unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+
+        let raw = power_up_from_bytes(rom, "");
+        for packed in [gz, zip] {
+            let cart = power_up_from_bytes(packed, "");
+            assert_eq!(cart.title(), "PACKED");
+            assert!((0x0000..0x8000).all(|a| cart.get(a) == raw.get(a)));
+        }
+    }
+
+    /// Fresh path in the temp dir, the file doesn't exist yet
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("oxidboy-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn saving_twice_writes_once() {
+        let sav = temp_path("twice.sav");
+        let mut cart = Mbc1::power_up(vec![0x00; 0x8000], vec![0x00; 0x2000], &sav);
+        cart.set(0x0000, 0x0A);
+        cart.set(0xA000, 0x42);
+        assert!(cart.is_dirty());
+        cart.sav();
+        assert_eq!(std::fs::read(&sav).unwrap()[0], 0x42);
+        assert!(!cart.is_dirty());
+        std::fs::remove_file(&sav).unwrap();
+        cart.sav();
+        assert!(!sav.exists());
+    }
+
+    /// Test rom with the given cartridge type and ram size bytes, the checksum is redone
+    fn rom_with(kind: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = test_roms::assemble("HEADER", &[]);
+        rom[0x0147] = kind;
+        rom[0x0149] = ram_size;
+        rom[0x014D] = test_roms::header_checksum(&rom);
+        rom
+    }
+
+    #[test]
+    fn out_of_range_ram_banks_wrap_on_an_8k_cart() {
+        // MBC1 needs mode 1 before bits 5-6 pick the ram bank
+        for (kind, mode) in [(0x03, Some(0x01)), (0x13, None), (0x1A, None)] {
+            let mut cart = power_up_from_bytes(rom_with(kind, 0x02), "");
+            cart.set(0x0000, 0x0A);
+            if let Some(mode) = mode {
+                cart.set(0x6000, mode);
+            }
+            cart.set(0x4000, 0x03);
+            cart.set(0xA123, 0x77);
+            cart.set(0x4000, 0x00);
+            assert_eq!(cart.get(0xA123), 0x77, "cartridge type 0x{:02x}", kind);
+        }
+    }
+
+    /// Rom of the given size where every bank starts with its own number
+    fn numbered_banks(size: usize) -> Vec<u8> {
+        let mut rom = vec![0x00; size];
+        for (bank, chunk) in rom.chunks_exact_mut(0x4000).enumerate() {
+            chunk[0] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn mbc1_upper_bits_reach_bank_0x21_in_mode_0() {
+        let mut cart = Mbc1::power_up(numbered_banks(0x20_0000), vec![], "");
+        cart.set(0x2000, 0x01);
+        cart.set(0x4000, 0x01);
+        assert_eq!(cart.get(0x4000), 0x21);
+        assert_eq!(cart.get(0x0000), 0x00);
+    }
+
+    #[test]
+    fn mbc1m_multicarts_are_detected_and_bank_in_16s() {
+        let mut rom = numbered_banks(0x10_0000);
+        let mut cart = Mbc1::power_up(rom.clone(), vec![], "");
+        assert!(!cart.multicart);
+        cart.set(0x2000, 0x02);
+        cart.set(0x4000, 0x01);
+        assert_eq!(cart.get(0x4000), 0x22);
+
+        rom[0x4_0104..0x4_0134].copy_from_slice(&NINTENDO_LOGO);
+        let mut cart = Mbc1::power_up(rom, vec![], "");
+        assert!(cart.multicart);
+        cart.set(0x2000, 0x02);
+        cart.set(0x4000, 0x01);
+        assert_eq!(cart.get(0x4000), 0x12);
+        cart.set(0x6000, 0x01);
+        assert_eq!(cart.get(0x0000), 0x10);
+    }
+
+    #[test]
+    fn rom_ram_battery_persists_on_sav() {
+        let rom_path = temp_path("romram.gb");
+        let sav = rom_path.with_extension("sav");
+        let _ = std::fs::remove_file(&sav);
+        let mut cart = power_up_from_bytes(rom_with(0x09, 0x02), &rom_path);
+        cart.set(0xA010, 0x5A);
+        cart.sav();
+        let ram = std::fs::read(&sav).unwrap();
+        assert_eq!((ram.len(), ram[0x10]), (0x2000, 0x5A));
+        let cart = power_up_from_bytes(rom_with(0x09, 0x02), &rom_path);
+        assert_eq!(cart.get(0xA010), 0x5A);
+        std::fs::remove_file(&sav).unwrap();
+    }
+
+    #[test]
+    fn mmm01_maps_the_selected_game_after_the_switch() {
+        // 8 banks, the menu is the last two
+        let mut cart = Mmm01::power_up(numbered_banks(0x2_0000), vec![], "");
+        assert_eq!((cart.get(0x0000), cart.get(0x4000)), (0x06, 0x07));
+        cart.set(0x2000, 0x02);
+        cart.set(0x0000, 0x40);
+        assert_eq!((cart.get(0x0000), cart.get(0x4000)), (0x02, 0x03));
+        cart.set(0x2000, 0x02);
+        assert_eq!(cart.get(0x4000), 0x04);
+    }
+
+    #[test]
+    fn set_rtc_and_get_rtc_go_through_the_registers() {
+        let mut cart = power_up_from_bytes(rom_with(0x10, 0x02), "");
+        cart.set_rtc(RtcTime { seconds: 30, minutes: 15, hours: 7, days: 300, halted: false });
+        cart.set(0x0000, 0x0A);
+        cart.set(0x6000, 0x00);
+        cart.set(0x6000, 0x01);
+        let mut read = |reg: u8| {
+            cart.set(0x4000, reg);
+            cart.get(0xA000)
+        };
+        // The clock may tick over a second between setting and latching
+        assert!((30..=31).contains(&read(0x08)));
+        assert_eq!([read(0x09), read(0x0A), read(0x0B), read(0x0C)], [15, 7, 300u16 as u8, 0x01]);
+        cart.set(0x4000, 0x0A);
+        cart.set(0xA000, 5);
+        assert_eq!(cart.get_rtc().map(|t| (t.minutes, t.hours, t.days)), Some((15, 5, 300)));
+    }
+
+    #[test]
+    fn bad_checksum_loads_with_a_warning_by_default() {
+        let mut rom = test_roms::assemble("CHECKSUM", &[]);
+        rom[0x014D] = rom[0x014D].wrapping_add(1);
+        let expected = LoadWarning::BadHeaderChecksum { found: rom[0x014D], expected: rom[0x014D].wrapping_sub(1) };
+        assert!(try_power_up_from_bytes(rom.clone(), "").is_ok());
+        let (_, warnings) = try_power_up_from_bytes_with(rom.clone(), "", LoadOptions::default()).unwrap();
+        assert_eq!(warnings, [expected]);
+        let strict = LoadOptions { allow_bad_checksum: false, ..LoadOptions::default() };
+        assert!(matches!(try_power_up_from_bytes_with(rom, "", strict), Err(EmuError::BadRom { .. })));
+    }
+
+    #[test]
+    fn pocket_camera_header_loads_and_reads_bank_0() {
+        let rom = rom_with(0xFC, 0x03);
+        let sav = temp_path("camera.sav");
+        let mut cart = try_power_up_from_bytes(rom.clone(), sav.with_extension("gb")).unwrap();
+        assert_eq!(cart.title(), "HEADER");
+        assert!((0x0000..0x4000).all(|a| cart.get(a) == rom[a as usize]));
+        cart.set(0x0000, 0x0A);
+        cart.set(0xA000, 0x42);
+        assert_eq!(cart.get(0xA000), 0x42);
+        cart.sav();
+        assert_eq!(std::fs::read(&sav).unwrap()[0], 0x42);
+        std::fs::remove_file(sav).unwrap();
+    }
+
+    #[test]
+    fn load_errors_name_the_file_and_the_problem() {
+        let missing = temp_path("missing.gb");
+        match try_power_up(&missing) {
+            Err(EmuError::Io { path, source }) => {
+                assert_eq!(path, missing);
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            other => panic!("expected an io error, got {:?}", other.map(|c| c.title())),
+        }
+        // 0x20 is MBC6
+        match try_power_up_from_bytes(rom_with(0x20, 0x00), "mbc6.gb") {
+            Err(e @ EmuError::UnsupportedCartridge { mbc: 0x20, .. }) => {
+                assert_eq!(e.to_string(), "mbc6.gb: unsupported cartridge type 0x20");
+            }
+            other => panic!("expected an unsupported cartridge, got {:?}", other.map(|c| c.title())),
+        }
+    }
+
+    #[test]
+    fn rtc_day_counter_spills_into_dh() {
+        let mut cart = Mbc3::power_up(rom_with(0x10, 0x02), vec![0x00; 0x8000], "", "");
+        cart.set(0x0000, 0x0A);
+        // (days since the epoch, DL, DH) with DH bit 0 as day bit 8 and bit 7 as the overflow
+        for (days, dl, dh) in [(255, 0xFF, 0x00), (256, 0x00, 0x01), (300, 0x2C, 0x01), (511, 0xFF, 0x01), (512, 0x00, 0x80), (700, 0xBC, 0x80)] {
+            cart.rtc.zero = now() - days * 86400 - 10;
+            cart.set(0x6000, 0x00);
+            cart.set(0x6000, 0x01);
+            cart.set(0x4000, 0x0B);
+            assert_eq!(cart.get(0xA000), dl, "{} days", days);
+            cart.set(0x4000, 0x0C);
+            assert_eq!(cart.get(0xA000), dh, "{} days", days);
+        }
+        // Writing DH clears the overflow, the day count keeps bit 8
+        cart.set(0xA000, 0x01);
+        assert_eq!(cart.get_rtc().map(|t| t.days), Some(0x1BC));
+        cart.set(0x6000, 0x00);
+        cart.set(0x6000, 0x01);
+        assert_eq!(cart.get(0xA000), 0x01);
+    }
+
+    #[test]
+    fn is_supported_knows_the_emulated_mappers() {
+        // ROM only, MBC1, MBC1+RAM+BATTERY, MBC3+TIMER+RAM+BATTERY, MBC5
+        for kind in [0x00, 0x01, 0x03, 0x10, 0x19] {
+            assert!(is_supported(kind), "0x{:02x}", kind);
+        }
+        // MBC6, the Pocket Camera and types that don't exist
+        for kind in [0x20, 0xFC, 0x04, 0xFE] {
+            assert!(!is_supported(kind), "0x{:02x}", kind);
+        }
+    }
+
+    #[test]
+    fn mbc7_latches_the_tilt_after_0x55_then_0xaa() {
+        let mut cart = power_up_from_bytes(rom_with(0x22, 0x00), "");
+        cart.set(0x0000, 0x0A);
+        cart.set(0x4000, 0x40);
+        cart.set_tilt(1.0, -0.5);
+        let read = |cart: &dyn Cartridge| {
+            let word = |lo: u16| u16::from(cart.get(lo)) | u16::from(cart.get(lo + 0x10)) << 8;
+            (word(0xA020), word(0xA040))
+        };
+        cart.set(0xA000, 0x55);
+        assert_eq!(read(cart.as_ref()), (0x8000, 0x8000));
+        cart.set(0xA010, 0xAA);
+        let center = f32::from(MBC7_CENTER);
+        assert_eq!(read(cart.as_ref()), ((center + MBC7_PER_G) as u16, (center - 0.5 * MBC7_PER_G) as u16));
+        // 0xAA alone doesn't latch again
+        cart.set_tilt(0.0, 0.0);
+        cart.set(0xA010, 0xAA);
+        assert_eq!(read(cart.as_ref()), ((center + MBC7_PER_G) as u16, (center - 0.5 * MBC7_PER_G) as u16));
+    }
+
+    #[test]
+    fn lenient_ram_banks_past_the_declared_size() {
+        let lenient = LoadOptions { lenient_ram: true, ..LoadOptions::default() };
+        assert!(!LoadOptions::default().lenient_ram);
+        let (mut cart, _) = try_power_up_from_bytes_with(rom_with(0x1A, 0x02), "", lenient).unwrap();
+        cart.set(0x0000, 0x0A);
+        cart.set(0x4000, 0x0F);
+        cart.set(0xA123, 0x77);
+        cart.set(0x4000, 0x00);
+        assert_eq!(cart.get(0xA123), 0x00, "bank 15 wrapped onto bank 0");
+        cart.set(0x4000, 0x0F);
+        assert_eq!(cart.get(0xA123), 0x77);
+    }
+
+    #[test]
+    fn header_flags_for_each_kind_of_game() {
+        // (0x0143, 0x0146, supports cgb, requires cgb, supports sgb)
+        let cases = [
+            (0x00, 0x00, false, false, false), // DMG only
+            (0x00, 0x03, false, false, true),  // DMG with SGB functions
+            (0x80, 0x00, true, false, false),  // CGB enhanced
+            (0x80, 0x03, true, false, true),   // CGB enhanced with SGB functions
+            (0xC0, 0x00, true, true, false),   // CGB only
+        ];
+        for (cgb, sgb, supports_cgb, requires_cgb, supports_sgb) in cases {
+            let mut rom = test_roms::assemble("FLAGS", &[]);
+            rom[0x0143] = cgb;
+            rom[0x0146] = sgb;
+            rom[0x014D] = test_roms::header_checksum(&rom);
+            let cart = power_up_from_bytes(rom, "");
+            let flags = (cart.supports_cgb(), cart.requires_cgb(), cart.supports_sgb());
+            assert_eq!(flags, (supports_cgb, requires_cgb, supports_sgb), "0x0143 0x{:02x}, 0x0146 0x{:02x}", cgb, sgb);
+        }
+    }
+
+    #[test]
+    fn mbc3_set_sram_only_splits_off_a_whole_clock_footer() {
+        let mut cart = Mbc3::power_up(rom_with(0x10, 0x02), vec![0x00; 0x2000], "", "");
+        let zero = cart.rtc.zero;
+        // RAM on its own leaves the clock alone, its last 8 bytes are still RAM
+        let ram = vec![0x42; 0x2000];
+        cart.set_sram(&ram);
+        assert_eq!(cart.rtc.zero, zero);
+        assert_eq!(cart.sram()[..0x2000], ram[..]);
+
+        let mut full = vec![0x11; 0x2000];
+        full.extend_from_slice(&1234u64.to_be_bytes());
+        cart.set_sram(&full);
+        assert_eq!(cart.rtc.zero, 1234);
+        assert_eq!(cart.sram(), full);
+    }
+}
@@ -8,10 +8,45 @@ impl Clock {
         Self { period, n: 0x00 }
     }
 
+    /// Drops the cycles counted towards the next tick
+    pub fn reset(&mut self) {
+        self.n = 0x00;
+    }
+
+    /// Changes the tick length, cycles already counted carry over to the new period
+    pub fn set_period(&mut self, period: u32) {
+        self.period = period;
+    }
+
+    /// Cycles counted towards the next tick, always below the period after next
+    pub fn progress(&self) -> u32 {
+        self.n
+    }
+
     pub fn next(&mut self, cycles: u32) -> u32 {
         self.n += cycles;
         let rs = self.n / self.period;
         self.n = self.n % self.period;
         rs
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_counts_the_next_tick_from_zero() {
+        let mut clock = Clock::power_up(8);
+        assert_eq!(clock.next(5), 0);
+        assert_eq!(clock.progress(), 5);
+        clock.reset();
+        assert_eq!(clock.progress(), 0);
+        // Without the reset these 3 cycles would have finished the tick
+        assert_eq!(clock.next(3), 0);
+        assert_eq!(clock.next(5), 1);
+        assert_eq!(clock.progress(), 0);
+        assert_eq!(clock.next(20), 2);
+        assert_eq!(clock.progress(), 4);
+    }
+}
@@ -8,11 +8,16 @@ use std::{thread, time};
 
 pub const CLOCK_FREQUENCY: u32 = 4_194_304;
 pub const STEP_TIME: u32 = 16;
-pub const STEP_CYCLES: u32 = (STEP_TIME as f64 / (1000_f64 / CLOCK_FREQUENCY as f64)) as u32;
+pub const STEP_CYCLES: u32 = step_cycles(STEP_TIME);
+
+/// CPU cycles that run in the given number of milliseconds
+pub const fn step_cycles(ms: u32) -> u32 {
+    (ms as f64 / (1000_f64 / CLOCK_FREQUENCY as f64)) as u32
+}
 
 const OP_CYCLES: [u32; 256] = [
     1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, // 0
-    0, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1, // 1
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1, // 1
     2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 2
     2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 3
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 4
@@ -28,6 +33,25 @@ const OP_CYCLES: [u32; 256] = [
     3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4, // e
     3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4, // f
 ];
+/// Length in bytes of every opcode including its operands, 0xCB counts the second byte so CB opcodes are 2
+pub const INSTR_LEN: [u8; 256] = [
+    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 1
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 2
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 3
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 4
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 5
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 6
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 7
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 8
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 9
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // a
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // b
+    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // c
+    1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, // d
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // e
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // f
+];
 const CB_CYCLES: [u32; 256] = [
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, // 0
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, // 1
@@ -47,33 +71,93 @@ const CB_CYCLES: [u32; 256] = [
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, // f
 ];
 
+/// Carry and half carry of SP + e8, both come from the unsigned add of the low byte (bits 7 and 3)
+/// * The sign of e8 never matters for the flags
+fn add_sp_flags(sp: u16, e8: u8) -> (bool, bool) {
+    let lo = sp as u8;
+    let carry = u16::from(lo) + u16::from(e8) > 0xFF;
+    let half_carry = (lo & 0x0F) + (e8 & 0x0F) > 0x0F;
+    (carry, half_carry)
+}
+
+/// Result a mooneye test rom reports through the LD B,B breakpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Magic {
+    Passed,
+    Failed,
+}
+
+/// Execution counts gathered while profiling, both sorted with the hottest first
+/// * CB prefixed instructions count towards 0xCB
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub opcodes: Vec<(u8, u64)>,
+    /// Keyed by the high byte of PC, so each entry covers 256 bytes of address space
+    pub pages: Vec<(u8, u64)>,
+}
+
+struct Counts {
+    opcodes: [u64; 256],
+    pages: [u64; 256],
+}
+
+impl Counts {
+    /// Non zero entries, hottest first
+    fn sorted(counts: &[u64; 256]) -> Vec<(u8, u64)> {
+        let mut v: Vec<(u8, u64)> = counts.iter().enumerate().filter(|(_, &n)| n != 0).map(|(i, &n)| (i as u8, n)).collect();
+        v.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        v
+    }
+}
+
 pub struct Cpu {
     pub reg: Register,
     pub mem: Rc<RefCell<dyn Memory>>,
     pub halted: bool,
     pub ei: bool,
+    magic_breakpoint: bool,
+    magic: Option<Magic>,
+    stop: bool,
+    profile: Option<Box<Counts>>,
+    strict: bool,
+    locked: bool,
 }
 
 impl Cpu {
-    fn imm(&mut self) -> u8 {
-        let v = self.mem.borrow().get(self.reg.program_counter);
+    /// STOP is two bytes unless an interrupt is pending, then the byte after it runs as the next opcode
+    /// * The second byte is skipped whatever its value, a non zero one doesn't change what STOP does
+    /// * A held button in a selected group blocks the speed switch, pending interrupt or not
+    /// * Neither STOP nor the HALT mode after a speed switch is entered, the CPU carries on at once
+    fn stop_op(&mut self, mem: &dyn Memory) {
+        let held = mem.get(0xFF00) & 0x0F != 0x0F;
+        let pending = mem.get(0xFF0F) & mem.get(0xFFFF) & 0x1F != 0x00;
+        if !pending {
+            self.imm(mem);
+        }
+        if !held {
+            self.stop = true;
+        }
+    }
+
+    fn imm(&mut self, mem: &dyn Memory) -> u8 {
+        let v = mem.get(self.reg.program_counter);
         self.reg.program_counter += 1;
         v
     }
 
-    fn imm_word(&mut self) -> u16 {
-        let v = self.mem.borrow().get_word(self.reg.program_counter);
+    fn imm_word(&mut self, mem: &dyn Memory) -> u16 {
+        let v = mem.get_word(self.reg.program_counter);
         self.reg.program_counter += 2;
         v
     }
 
-    fn stack_add(&mut self, insert: u16) {
+    fn stack_add(&mut self, mem: &mut dyn Memory, insert: u16) {
         self.reg.stack_pointer -= 2;
-        self.mem.borrow_mut().set_word(self.reg.stack_pointer, insert);
+        mem.set_word(self.reg.stack_pointer, insert);
     }
 
-    fn stack_pop(&mut self) -> u16 {
-        let r = self.mem.borrow().get_word(self.reg.stack_pointer);
+    fn stack_pop(&mut self, mem: &dyn Memory) -> u16 {
+        let r = mem.get_word(self.reg.stack_pointer);
         self.reg.stack_pointer += 2;
         r
     }
@@ -177,15 +261,16 @@ impl Cpu {
         self.reg.set_flag(SubtractionFlag, false);
         self.reg.set_hl(r);
     }
-    ///Add one byte signed immediate value to Stack Pointer
-    fn alu_add_sp(&mut self) {
+    ///Add one byte signed immediate value to Stack Pointer and return the sum, shared by ADD SP,e8 and LD HL,SP+e8
+    fn alu_add_sp(&mut self, mem: &dyn Memory) -> u16 {
         let a = self.reg.stack_pointer;
-        let b = i16::from(self.imm() as i8) as u16;
-        self.reg.set_flag(CarryFlag, (a & 0x00FF) + (b & 0x00FF) > 0x00FF);
-        self.reg.set_flag(HalfCarryFlag, (a & 0x000F) + (b & 0x000F) > 0x000F);
+        let e8 = self.imm(mem);
+        let (carry, half_carry) = add_sp_flags(a, e8);
+        self.reg.set_flag(CarryFlag, carry);
+        self.reg.set_flag(HalfCarryFlag, half_carry);
         self.reg.set_flag(SubtractionFlag, false);
         self.reg.set_flag(ZeroFlag, false);
-        self.reg.stack_pointer = a.wrapping_add(b);
+        a.wrapping_add(i16::from(e8 as i8) as u16)
     }
     ///Swaps the upper and lower nibbles of value
     fn alu_swap(&mut self, value: u8) -> u8 {
@@ -196,9 +281,11 @@ impl Cpu {
         (value >> 4) | (value << 4)
     }
     ///Decimal Adjust register A, sets register A to represent Binary Coded Decimal
+    /// * After an addition carry is set when A went past 99, after a subtraction it only keeps the borrow already there
     fn alu_daa(&mut self) {
         let mut a = self.reg.a_reg;
-        let mut adjust = if self.reg.get_flag(CarryFlag) { 0x60 } else {0x00};
+        let mut carry = self.reg.get_flag(CarryFlag);
+        let mut adjust = if carry { 0x60 } else {0x00};
         if self.reg.get_flag(HalfCarryFlag) {
             adjust |= 0x06;
         };
@@ -208,12 +295,13 @@ impl Cpu {
             };
             if a > 0x99 {
                 adjust |= 0x60;
+                carry = true;
             };
             a = a.wrapping_add(adjust);
         } else {
             a = a.wrapping_sub(adjust);
         }
-        self.reg.set_flag(CarryFlag, adjust >= 0x60);
+        self.reg.set_flag(CarryFlag, carry);
         self.reg.set_flag(HalfCarryFlag, false);
         self.reg.set_flag(ZeroFlag, a == 0x00);
         self.reg.a_reg = a;
@@ -331,15 +419,91 @@ impl Cpu {
 
 impl Cpu {
     pub fn power_up(term: Term, mem: Rc<RefCell<dyn Memory>>) -> Self {
-        Self { reg: Register::power_up(term), mem, halted: false, ei: true }
+        Self { reg: Register::power_up(term), mem, halted: false, ei: true, magic_breakpoint: false, magic: None, stop: false, profile: None, strict: false, locked: false }
+    }
+
+    /// Panics on undefined opcodes instead of locking up like hardware does
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Whether an undefined opcode hung the CPU, nothing but a reset brings it back
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Counts executed opcodes and PC pages, off by default
+    /// * Turning it off drops the counts gathered so far
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profile = if enabled { Some(Box::new(Counts { opcodes: [0; 256], pages: [0; 256] })) } else { None };
+    }
+
+    /// Counts since profiling was enabled or the last call, profiling stays on
+    pub fn take_profile(&mut self) -> Profile {
+        match &mut self.profile {
+            Some(counts) => {
+                let profile = Profile { opcodes: Counts::sorted(&counts.opcodes), pages: Counts::sorted(&counts.pages) };
+                counts.opcodes = [0; 256];
+                counts.pages = [0; 256];
+                profile
+            }
+            None => Profile::default(),
+        }
     }
+
+    /// Watches LD B,B for the mooneye pass/fail register pattern
+    pub fn set_magic_breakpoint(&mut self, enabled: bool) {
+        self.magic_breakpoint = enabled;
+    }
+
+    /// Result of the last LD B,B breakpoint hit, cleared once taken
+    pub fn take_magic(&mut self) -> Option<Magic> {
+        self.magic.take()
+    }
+
+    /// Register dump in the Gameboy Doctor log format, also used by SameBoy traces
+    /// * e.g. `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+    pub fn state_line(&self) -> String {
+        let r = &self.reg;
+        let pc = r.program_counter;
+        let mem = self.mem.borrow();
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            r.a_reg, r.f_reg, r.b_reg, r.c_reg, r.d_reg, r.e_reg, r.h_reg, r.l_reg, r.stack_pointer, pc,
+            mem.get(pc), mem.get(pc.wrapping_add(1)), mem.get(pc.wrapping_add(2)), mem.get(pc.wrapping_add(3)),
+        )
+    }
+
+    /// Whether STOP ran since the last call, the owner of the memory map performs an armed speed switch then
+    pub fn take_stop(&mut self) -> bool {
+        std::mem::take(&mut self.stop)
+    }
+
+    /// Passed is fibonacci 3, 5, 8, 13, 21, 34 in B to L, failed is 0x42 in all of them
+    fn check_magic(&mut self) {
+        if !self.magic_breakpoint {
+            return;
+        }
+        let r = &self.reg;
+        self.magic = match [r.b_reg, r.c_reg, r.d_reg, r.e_reg, r.h_reg, r.l_reg] {
+            [3, 5, 8, 13, 21, 34] => Some(Magic::Passed),
+            [0x42, 0x42, 0x42, 0x42, 0x42, 0x42] => Some(Magic::Failed),
+            _ => self.magic,
+        };
+    }
+    /// Services the highest priority pending interrupt
+    /// * Dispatch takes 5 machine cycles, two internal wait cycles, two for pushing PC and one to jump
+    /// * A pending interrupt always ends HALT, with IME off the CPU just resumes without servicing it
     fn hi(&mut self) -> u32 {
         if !self.halted && !self.ei {
             return 0;
         }
-        let intf = self.mem.borrow().get(0xFF0F);
-        let inte = self.mem.borrow().get(0xFFFF);
-        let ii = intf & inte;
+        let (intf, inte) = {
+            let mem = self.mem.borrow();
+            (mem.get(0xFF0F), mem.get(0xFFFF))
+        };
+        // Only bits 0-4 are interrupt lines, IE keeps whatever is written to the top three bits
+        let ii = intf & inte & 0x1F;
         if ii == 0x00 {
             return 0;
         }
@@ -350,72 +514,84 @@ impl Cpu {
         self.ei = false;
 
         let n = ii.trailing_zeros();
-        let intf = intf & !(1 << n);
-        self.mem.borrow_mut().set(0xFF0F, intf);
-
-        self.stack_add(self.reg.program_counter);
+        // Clearing the flag and pushing PC share one borrow
+        self.reg.stack_pointer -= 2;
+        {
+            let mut mem = self.mem.borrow_mut();
+            mem.set(0xFF0F, intf & !(1 << n));
+            mem.set_word(self.reg.stack_pointer, self.reg.program_counter);
+        }
         self.reg.program_counter = 0x0040 | ((n as u16) << 3);
-        4
+        5
     }
+    /// Runs one instruction, memory is borrowed once for all of it
     fn ex(&mut self) -> u32 {
-        let opcode = self.imm();
+        let rc = Rc::clone(&self.mem);
+        let mut guard = rc.borrow_mut();
+        let mem = &mut *guard;
+        let pc = self.reg.program_counter;
+        let opcode = self.imm(mem);
+        if let Some(counts) = &mut self.profile {
+            counts.opcodes[opcode as usize] += 1;
+            counts.pages[(pc >> 8) as usize] += 1;
+        }
         let mut cbcode: u8 = 0;
         match opcode {
             // LD r8, d8
-            0x06 => self.reg.b_reg = self.imm(),
-            0x0e => self.reg.c_reg = self.imm(),
-            0x16 => self.reg.d_reg = self.imm(),
-            0x1e => self.reg.e_reg = self.imm(),
-            0x26 => self.reg.h_reg = self.imm(),
-            0x2e => self.reg.l_reg = self.imm(),
+            0x06 => self.reg.b_reg = self.imm(mem),
+            0x0e => self.reg.c_reg = self.imm(mem),
+            0x16 => self.reg.d_reg = self.imm(mem),
+            0x1e => self.reg.e_reg = self.imm(mem),
+            0x26 => self.reg.h_reg = self.imm(mem),
+            0x2e => self.reg.l_reg = self.imm(mem),
             0x36 => {
                 let a = self.reg.parse_hl();
-                let v = self.imm();
-                self.mem.borrow_mut().set(a, v);
+                let v = self.imm(mem);
+                mem.set(a, v);
             }
-            0x3e => self.reg.a_reg = self.imm(),
+            0x3e => self.reg.a_reg = self.imm(mem),
 
             // LD (r16), A
-            0x02 => self.mem.borrow_mut().set(self.reg.parse_bc(), self.reg.a_reg),
-            0x12 => self.mem.borrow_mut().set(self.reg.parse_de(), self.reg.a_reg),
+            0x02 => mem.set(self.reg.parse_bc(), self.reg.a_reg),
+            0x12 => mem.set(self.reg.parse_de(), self.reg.a_reg),
 
             // LD A, (r16)
-            0x0a => self.reg.a_reg = self.mem.borrow().get(self.reg.parse_bc()),
-            0x1a => self.reg.a_reg = self.mem.borrow().get(self.reg.parse_de()),
+            0x0a => self.reg.a_reg = mem.get(self.reg.parse_bc()),
+            0x1a => self.reg.a_reg = mem.get(self.reg.parse_de()),
 
             // LD (HL+), A
             0x22 => {
                 let a = self.reg.parse_hl();
-                self.mem.borrow_mut().set(a, self.reg.a_reg);
+                mem.set(a, self.reg.a_reg);
                 self.reg.set_hl(a + 1);
             }
             // LD (HL-), A
             0x32 => {
                 let a = self.reg.parse_hl();
-                self.mem.borrow_mut().set(a, self.reg.a_reg);
+                mem.set(a, self.reg.a_reg);
                 self.reg.set_hl(a - 1);
             }
             // LD A, (HL+)
             0x2a => {
                 let v = self.reg.parse_hl();
-                self.reg.a_reg = self.mem.borrow().get(v);
+                self.reg.a_reg = mem.get(v);
                 self.reg.set_hl(v + 1);
             }
             // LD A, (HL-)
             0x3a => {
                 let v = self.reg.parse_hl();
-                self.reg.a_reg = self.mem.borrow().get(v);
+                self.reg.a_reg = mem.get(v);
                 self.reg.set_hl(v - 1);
             }
 
             // LD r8, r8
-            0x40 => {}
+            0x40 => self.check_magic(),
             0x41 => self.reg.b_reg = self.reg.c_reg,
             0x42 => self.reg.b_reg = self.reg.d_reg,
             0x43 => self.reg.b_reg = self.reg.e_reg,
             0x44 => self.reg.b_reg = self.reg.h_reg,
             0x45 => self.reg.b_reg = self.reg.l_reg,
-            0x46 => self.reg.b_reg = self.mem.borrow().get(self.reg.parse_hl()),
+            0x46 => self.reg.b_reg = mem.get(self.reg.parse_hl()),
             0x47 => self.reg.b_reg = self.reg.a_reg,
             0x48 => self.reg.c_reg = self.reg.b_reg,
             0x49 => {}
@@ -423,7 +599,7 @@ impl Cpu {
             0x4b => self.reg.c_reg = self.reg.e_reg,
             0x4c => self.reg.c_reg = self.reg.h_reg,
             0x4d => self.reg.c_reg = self.reg.l_reg,
-            0x4e => self.reg.c_reg = self.mem.borrow().get(self.reg.parse_hl()),
+            0x4e => self.reg.c_reg = mem.get(self.reg.parse_hl()),
             0x4f => self.reg.c_reg = self.reg.a_reg,
             0x50 => self.reg.d_reg = self.reg.b_reg,
             0x51 => self.reg.d_reg = self.reg.c_reg,
@@ -431,7 +607,7 @@ impl Cpu {
             0x53 => self.reg.d_reg = self.reg.e_reg,
             0x54 => self.reg.d_reg = self.reg.h_reg,
             0x55 => self.reg.d_reg = self.reg.l_reg,
-            0x56 => self.reg.d_reg = self.mem.borrow().get(self.reg.parse_hl()),
+            0x56 => self.reg.d_reg = mem.get(self.reg.parse_hl()),
             0x57 => self.reg.d_reg = self.reg.a_reg,
             0x58 => self.reg.e_reg = self.reg.b_reg,
             0x59 => self.reg.e_reg = self.reg.c_reg,
@@ -439,7 +615,7 @@ impl Cpu {
             0x5b => {}
             0x5c => self.reg.e_reg = self.reg.h_reg,
             0x5d => self.reg.e_reg = self.reg.l_reg,
-            0x5e => self.reg.e_reg = self.mem.borrow().get(self.reg.parse_hl()),
+            0x5e => self.reg.e_reg = mem.get(self.reg.parse_hl()),
             0x5f => self.reg.e_reg = self.reg.a_reg,
             0x60 => self.reg.h_reg = self.reg.b_reg,
             0x61 => self.reg.h_reg = self.reg.c_reg,
@@ -447,7 +623,7 @@ impl Cpu {
             0x63 => self.reg.h_reg = self.reg.e_reg,
             0x64 => {}
             0x65 => self.reg.h_reg = self.reg.l_reg,
-            0x66 => self.reg.h_reg = self.mem.borrow().get(self.reg.parse_hl()),
+            0x66 => self.reg.h_reg = mem.get(self.reg.parse_hl()),
             0x67 => self.reg.h_reg = self.reg.a_reg,
             0x68 => self.reg.l_reg = self.reg.b_reg,
             0x69 => self.reg.l_reg = self.reg.c_reg,
@@ -455,54 +631,54 @@ impl Cpu {
             0x6b => self.reg.l_reg = self.reg.e_reg,
             0x6c => self.reg.l_reg = self.reg.h_reg,
             0x6d => {}
-            0x6e => self.reg.l_reg = self.mem.borrow().get(self.reg.parse_hl()),
+            0x6e => self.reg.l_reg = mem.get(self.reg.parse_hl()),
             0x6f => self.reg.l_reg = self.reg.a_reg,
-            0x70 => self.mem.borrow_mut().set(self.reg.parse_hl(), self.reg.b_reg),
-            0x71 => self.mem.borrow_mut().set(self.reg.parse_hl(), self.reg.c_reg),
-            0x72 => self.mem.borrow_mut().set(self.reg.parse_hl(), self.reg.d_reg),
-            0x73 => self.mem.borrow_mut().set(self.reg.parse_hl(), self.reg.e_reg),
-            0x74 => self.mem.borrow_mut().set(self.reg.parse_hl(), self.reg.h_reg),
-            0x75 => self.mem.borrow_mut().set(self.reg.parse_hl(), self.reg.l_reg),
-            0x77 => self.mem.borrow_mut().set(self.reg.parse_hl(), self.reg.a_reg),
+            0x70 => mem.set(self.reg.parse_hl(), self.reg.b_reg),
+            0x71 => mem.set(self.reg.parse_hl(), self.reg.c_reg),
+            0x72 => mem.set(self.reg.parse_hl(), self.reg.d_reg),
+            0x73 => mem.set(self.reg.parse_hl(), self.reg.e_reg),
+            0x74 => mem.set(self.reg.parse_hl(), self.reg.h_reg),
+            0x75 => mem.set(self.reg.parse_hl(), self.reg.l_reg),
+            0x77 => mem.set(self.reg.parse_hl(), self.reg.a_reg),
             0x78 => self.reg.a_reg = self.reg.b_reg,
             0x79 => self.reg.a_reg = self.reg.c_reg,
             0x7a => self.reg.a_reg = self.reg.d_reg,
             0x7b => self.reg.a_reg = self.reg.e_reg,
             0x7c => self.reg.a_reg = self.reg.h_reg,
             0x7d => self.reg.a_reg = self.reg.l_reg,
-            0x7e => self.reg.a_reg = self.mem.borrow().get(self.reg.parse_hl()),
+            0x7e => self.reg.a_reg = mem.get(self.reg.parse_hl()),
             0x7f => {}
 
             // LDH (a8), A
             0xe0 => {
-                let a = 0xff00 | u16::from(self.imm());
-                self.mem.borrow_mut().set(a, self.reg.a_reg);
+                let a = 0xff00 | u16::from(self.imm(mem));
+                mem.set(a, self.reg.a_reg);
             }
             // LDH A, (a8)
             0xf0 => {
-                let a = 0xff00 | u16::from(self.imm());
-                self.reg.a_reg = self.mem.borrow().get(a);
+                let a = 0xff00 | u16::from(self.imm(mem));
+                self.reg.a_reg = mem.get(a);
             }
 
             // LD (C), A
-            0xe2 => self.mem.borrow_mut().set(0xff00 | u16::from(self.reg.c_reg), self.reg.a_reg),
+            0xe2 => mem.set(0xff00 | u16::from(self.reg.c_reg), self.reg.a_reg),
             // LD A, (C)
-            0xf2 => self.reg.a_reg = self.mem.borrow().get(0xff00 | u16::from(self.reg.c_reg)),
+            0xf2 => self.reg.a_reg = mem.get(0xff00 | u16::from(self.reg.c_reg)),
 
             // LD (a16), A
             0xea => {
-                let a = self.imm_word();
-                self.mem.borrow_mut().set(a, self.reg.a_reg);
+                let a = self.imm_word(mem);
+                mem.set(a, self.reg.a_reg);
             }
             // LD A, (a16)
             0xfa => {
-                let a = self.imm_word();
-                self.reg.a_reg = self.mem.borrow().get(a);
+                let a = self.imm_word(mem);
+                self.reg.a_reg = mem.get(a);
             }
 
             // LD r16, d16
             0x01 | 0x11 | 0x21 | 0x31 => {
-                let v = self.imm_word();
+                let v = self.imm_word(mem);
                 match opcode {
                     0x01 => self.reg.set_bc(v),
                     0x11 => self.reg.set_de(v),
@@ -516,29 +692,24 @@ impl Cpu {
             0xf9 => self.reg.stack_pointer = self.reg.parse_hl(),
             // LD SP, d8
             0xf8 => {
-                let a = self.reg.stack_pointer;
-                let b = i16::from(self.imm() as i8) as u16;
-                self.reg.set_flag(CarryFlag, (a & 0x00ff) + (b & 0x00ff) > 0x00ff);
-                self.reg.set_flag(HalfCarryFlag, (a & 0x000f) + (b & 0x000f) > 0x000f);
-                self.reg.set_flag(SubtractionFlag, false);
-                self.reg.set_flag(ZeroFlag, false);
-                self.reg.set_hl(a.wrapping_add(b));
+                let v = self.alu_add_sp(mem);
+                self.reg.set_hl(v);
             }
             // LD (d16), SP
             0x08 => {
-                let a = self.imm_word();
-                self.mem.borrow_mut().set_word(a, self.reg.stack_pointer);
+                let a = self.imm_word(mem);
+                mem.set_word(a, self.reg.stack_pointer);
             }
 
             // PUSH
-            0xc5 => self.stack_add(self.reg.parse_bc()),
-            0xd5 => self.stack_add(self.reg.parse_de()),
-            0xe5 => self.stack_add(self.reg.parse_hl()),
-            0xf5 => self.stack_add(self.reg.parse_af()),
+            0xc5 => self.stack_add(mem, self.reg.parse_bc()),
+            0xd5 => self.stack_add(mem, self.reg.parse_de()),
+            0xe5 => self.stack_add(mem, self.reg.parse_hl()),
+            0xf5 => self.stack_add(mem, self.reg.parse_af()),
 
             // POP
             0xc1 | 0xf1 | 0xd1 | 0xe1 => {
-                let v = self.stack_pop();
+                let v = self.stack_pop(mem);
                 match opcode {
                     0xc1 => self.reg.set_bc(v),
                     0xd1 => self.reg.set_de(v),
@@ -556,12 +727,12 @@ impl Cpu {
             0x84 => self.alu_add(self.reg.h_reg),
             0x85 => self.alu_add(self.reg.l_reg),
             0x86 => {
-                let v = self.mem.borrow().get(self.reg.parse_hl());
+                let v = mem.get(self.reg.parse_hl());
                 self.alu_add(v);
             }
             0x87 => self.alu_add(self.reg.a_reg),
             0xc6 => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_add(v);
             }
 
@@ -573,12 +744,12 @@ impl Cpu {
             0x8c => self.alu_adc(self.reg.h_reg),
             0x8d => self.alu_adc(self.reg.l_reg),
             0x8e => {
-                let a = self.mem.borrow().get(self.reg.parse_hl());
+                let a = mem.get(self.reg.parse_hl());
                 self.alu_adc(a);
             }
             0x8f => self.alu_adc(self.reg.a_reg),
             0xce => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_adc(v);
             }
 
@@ -590,12 +761,12 @@ impl Cpu {
             0x94 => self.alu_sub(self.reg.h_reg),
             0x95 => self.alu_sub(self.reg.l_reg),
             0x96 => {
-                let a = self.mem.borrow().get(self.reg.parse_hl());
+                let a = mem.get(self.reg.parse_hl());
                 self.alu_sub(a);
             }
             0x97 => self.alu_sub(self.reg.a_reg),
             0xd6 => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_sub(v);
             }
 
@@ -607,12 +778,12 @@ impl Cpu {
             0x9c => self.alu_sbc(self.reg.h_reg),
             0x9d => self.alu_sbc(self.reg.l_reg),
             0x9e => {
-                let a = self.mem.borrow().get(self.reg.parse_hl());
+                let a = mem.get(self.reg.parse_hl());
                 self.alu_sbc(a);
             }
             0x9f => self.alu_sbc(self.reg.a_reg),
             0xde => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_sbc(v);
             }
 
@@ -624,12 +795,12 @@ impl Cpu {
             0xa4 => self.alu_and(self.reg.h_reg),
             0xa5 => self.alu_and(self.reg.l_reg),
             0xa6 => {
-                let a = self.mem.borrow().get(self.reg.parse_hl());
+                let a = mem.get(self.reg.parse_hl());
                 self.alu_and(a);
             }
             0xa7 => self.alu_and(self.reg.a_reg),
             0xe6 => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_and(v);
             }
 
@@ -641,12 +812,12 @@ impl Cpu {
             0xb4 => self.alu_or(self.reg.h_reg),
             0xb5 => self.alu_or(self.reg.l_reg),
             0xb6 => {
-                let a = self.mem.borrow().get(self.reg.parse_hl());
+                let a = mem.get(self.reg.parse_hl());
                 self.alu_or(a);
             }
             0xb7 => self.alu_or(self.reg.a_reg),
             0xf6 => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_or(v);
             }
 
@@ -658,12 +829,12 @@ impl Cpu {
             0xac => self.alu_xor(self.reg.h_reg),
             0xad => self.alu_xor(self.reg.l_reg),
             0xae => {
-                let a = self.mem.borrow().get(self.reg.parse_hl());
+                let a = mem.get(self.reg.parse_hl());
                 self.alu_xor(a);
             }
             0xaf => self.alu_xor(self.reg.a_reg),
             0xee => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_xor(v);
             }
 
@@ -675,12 +846,12 @@ impl Cpu {
             0xbc => self.alu_cp(self.reg.h_reg),
             0xbd => self.alu_cp(self.reg.l_reg),
             0xbe => {
-                let a = self.mem.borrow().get(self.reg.parse_hl());
+                let a = mem.get(self.reg.parse_hl());
                 self.alu_cp(a);
             }
             0xbf => self.alu_cp(self.reg.a_reg),
             0xfe => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_cp(v);
             }
 
@@ -693,9 +864,9 @@ impl Cpu {
             0x2c => self.reg.l_reg = self.alu_inc(self.reg.l_reg),
             0x34 => {
                 let a = self.reg.parse_hl();
-                let v = self.mem.borrow().get(a);
+                let v = mem.get(a);
                 let h = self.alu_inc(v);
-                self.mem.borrow_mut().set(a, h);
+                mem.set(a, h);
             }
             0x3c => self.reg.a_reg = self.alu_inc(self.reg.a_reg),
 
@@ -708,9 +879,9 @@ impl Cpu {
             0x2d => self.reg.l_reg = self.alu_dec(self.reg.l_reg),
             0x35 => {
                 let a = self.reg.parse_hl();
-                let v = self.mem.borrow().get(a);
+                let v = mem.get(a);
                 let h = self.alu_dec(v);
-                self.mem.borrow_mut().set(a, h);
+                mem.set(a, h);
             }
             0x3d => self.reg.a_reg = self.alu_dec(self.reg.a_reg),
 
@@ -721,7 +892,7 @@ impl Cpu {
             0x39 => self.alu_add_hl(self.reg.stack_pointer),
 
             // ADD SP, d8
-            0xe8 => self.alu_add_sp(),
+            0xe8 => self.reg.stack_pointer = self.alu_add_sp(mem),
 
             // INC r16
             0x03 => {
@@ -778,7 +949,7 @@ impl Cpu {
             0x76 => self.halted = true,
 
             // STOP
-            0x10 => {}
+            0x10 => self.stop_op(mem),
 
             // DI/EI
             0xf3 => self.ei = false,
@@ -809,12 +980,12 @@ impl Cpu {
             }
 
             // JUMP
-            0xc3 => self.reg.program_counter = self.imm_word(),
+            0xc3 => self.reg.program_counter = self.imm_word(mem),
             0xe9 => self.reg.program_counter = self.reg.parse_hl(),
 
             // JUMP IF
             0xc2 | 0xca | 0xd2 | 0xda => {
-                let pc = self.imm_word();
+                let pc = self.imm_word(mem);
                 let cond = match opcode {
                     0xc2 => !self.reg.get_flag(ZeroFlag),
                     0xca => self.reg.get_flag(ZeroFlag),
@@ -829,7 +1000,7 @@ impl Cpu {
 
             // JR
             0x18 => {
-                let n = self.imm();
+                let n = self.imm(mem);
                 self.alu_jr(n);
             }
 
@@ -842,7 +1013,7 @@ impl Cpu {
                     0x38 => self.reg.get_flag(CarryFlag),
                     _ => panic!(""),
                 };
-                let n = self.imm();
+                let n = self.imm(mem);
                 if cond {
                     self.alu_jr(n);
                 }
@@ -850,8 +1021,8 @@ impl Cpu {
 
             // CALL
             0xcd => {
-                let nn = self.imm_word();
-                self.stack_add(self.reg.program_counter);
+                let nn = self.imm_word(mem);
+                self.stack_add(mem, self.reg.program_counter);
                 self.reg.program_counter = nn;
             }
 
@@ -864,49 +1035,49 @@ impl Cpu {
                     0xdc => self.reg.get_flag(CarryFlag),
                     _ => panic!(""),
                 };
-                let nn = self.imm_word();
+                let nn = self.imm_word(mem);
                 if cond {
-                    self.stack_add(self.reg.program_counter);
+                    self.stack_add(mem, self.reg.program_counter);
                     self.reg.program_counter = nn;
                 }
             }
 
             // RST
             0xc7 => {
-                self.stack_add(self.reg.program_counter);
+                self.stack_add(mem, self.reg.program_counter);
                 self.reg.program_counter = 0x00;
             }
             0xcf => {
-                self.stack_add(self.reg.program_counter);
+                self.stack_add(mem, self.reg.program_counter);
                 self.reg.program_counter = 0x08;
             }
             0xd7 => {
-                self.stack_add(self.reg.program_counter);
+                self.stack_add(mem, self.reg.program_counter);
                 self.reg.program_counter = 0x10;
             }
             0xdf => {
-                self.stack_add(self.reg.program_counter);
+                self.stack_add(mem, self.reg.program_counter);
                 self.reg.program_counter = 0x18;
             }
             0xe7 => {
-                self.stack_add(self.reg.program_counter);
+                self.stack_add(mem, self.reg.program_counter);
                 self.reg.program_counter = 0x20;
             }
             0xef => {
-                self.stack_add(self.reg.program_counter);
+                self.stack_add(mem, self.reg.program_counter);
                 self.reg.program_counter = 0x28;
             }
             0xf7 => {
-                self.stack_add(self.reg.program_counter);
+                self.stack_add(mem, self.reg.program_counter);
                 self.reg.program_counter = 0x30;
             }
             0xff => {
-                self.stack_add(self.reg.program_counter);
+                self.stack_add(mem, self.reg.program_counter);
                 self.reg.program_counter = 0x38;
             }
 
             // RET
-            0xc9 => self.reg.program_counter = self.stack_pop(),
+            0xc9 => self.reg.program_counter = self.stack_pop(mem),
 
             // RET IF
             0xc0 | 0xc8 | 0xd0 | 0xd8 => {
@@ -918,19 +1089,19 @@ impl Cpu {
                     _ => panic!(""),
                 };
                 if cond {
-                    self.reg.program_counter = self.stack_pop();
+                    self.reg.program_counter = self.stack_pop(mem);
                 }
             }
 
             // RETI
             0xd9 => {
-                self.reg.program_counter = self.stack_pop();
+                self.reg.program_counter = self.stack_pop(mem);
                 self.ei = true;
             }
 
             // Extended Bit Operations
             0xcb => {
-                cbcode = self.mem.borrow().get(self.reg.program_counter);
+                cbcode = mem.get(self.reg.program_counter);
                 self.reg.program_counter += 1;
                 match cbcode {
                     // RLC r8
@@ -942,9 +1113,9 @@ impl Cpu {
                     0x05 => self.reg.l_reg = self.alu_rlc(self.reg.l_reg),
                     0x06 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_rlc(v);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x07 => self.reg.a_reg = self.alu_rlc(self.reg.a_reg),
 
@@ -957,9 +1128,9 @@ impl Cpu {
                     0x0d => self.reg.l_reg = self.alu_rrc(self.reg.l_reg),
                     0x0e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_rrc(v);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x0f => self.reg.a_reg = self.alu_rrc(self.reg.a_reg),
 
@@ -972,9 +1143,9 @@ impl Cpu {
                     0x15 => self.reg.l_reg = self.alu_rl(self.reg.l_reg),
                     0x16 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_rl(v);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x17 => self.reg.a_reg = self.alu_rl(self.reg.a_reg),
 
@@ -987,9 +1158,9 @@ impl Cpu {
                     0x1d => self.reg.l_reg = self.alu_rr(self.reg.l_reg),
                     0x1e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_rr(v);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x1f => self.reg.a_reg = self.alu_rr(self.reg.a_reg),
 
@@ -1002,9 +1173,9 @@ impl Cpu {
                     0x25 => self.reg.l_reg = self.alu_sla(self.reg.l_reg),
                     0x26 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_sla(v);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x27 => self.reg.a_reg = self.alu_sla(self.reg.a_reg),
 
@@ -1017,9 +1188,9 @@ impl Cpu {
                     0x2d => self.reg.l_reg = self.alu_sra(self.reg.l_reg),
                     0x2e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_sra(v);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x2f => self.reg.a_reg = self.alu_sra(self.reg.a_reg),
 
@@ -1032,9 +1203,9 @@ impl Cpu {
                     0x35 => self.reg.l_reg = self.alu_swap(self.reg.l_reg),
                     0x36 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_swap(v);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x37 => self.reg.a_reg = self.alu_swap(self.reg.a_reg),
 
@@ -1047,9 +1218,9 @@ impl Cpu {
                     0x3d => self.reg.l_reg = self.alu_srl(self.reg.l_reg),
                     0x3e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_srl(v);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x3f => self.reg.a_reg = self.alu_srl(self.reg.a_reg),
 
@@ -1062,7 +1233,7 @@ impl Cpu {
                     0x45 => self.alu_bit(self.reg.l_reg, 0),
                     0x46 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 0);
                     }
                     0x47 => self.alu_bit(self.reg.a_reg, 0),
@@ -1074,7 +1245,7 @@ impl Cpu {
                     0x4d => self.alu_bit(self.reg.l_reg, 1),
                     0x4e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 1);
                     }
                     0x4f => self.alu_bit(self.reg.a_reg, 1),
@@ -1086,7 +1257,7 @@ impl Cpu {
                     0x55 => self.alu_bit(self.reg.l_reg, 2),
                     0x56 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 2);
                     }
                     0x57 => self.alu_bit(self.reg.a_reg, 2),
@@ -1098,7 +1269,7 @@ impl Cpu {
                     0x5d => self.alu_bit(self.reg.l_reg, 3),
                     0x5e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 3);
                     }
                     0x5f => self.alu_bit(self.reg.a_reg, 3),
@@ -1110,7 +1281,7 @@ impl Cpu {
                     0x65 => self.alu_bit(self.reg.l_reg, 4),
                     0x66 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 4);
                     }
                     0x67 => self.alu_bit(self.reg.a_reg, 4),
@@ -1122,7 +1293,7 @@ impl Cpu {
                     0x6d => self.alu_bit(self.reg.l_reg, 5),
                     0x6e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 5);
                     }
                     0x6f => self.alu_bit(self.reg.a_reg, 5),
@@ -1134,7 +1305,7 @@ impl Cpu {
                     0x75 => self.alu_bit(self.reg.l_reg, 6),
                     0x76 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 6);
                     }
                     0x77 => self.alu_bit(self.reg.a_reg, 6),
@@ -1146,7 +1317,7 @@ impl Cpu {
                     0x7d => self.alu_bit(self.reg.l_reg, 7),
                     0x7e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 7);
                     }
                     0x7f => self.alu_bit(self.reg.a_reg, 7),
@@ -1160,9 +1331,9 @@ impl Cpu {
                     0x85 => self.reg.l_reg = self.alu_res(self.reg.l_reg, 0),
                     0x86 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 0);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x87 => self.reg.a_reg = self.alu_res(self.reg.a_reg, 0),
                     0x88 => self.reg.b_reg = self.alu_res(self.reg.b_reg, 1),
@@ -1173,9 +1344,9 @@ impl Cpu {
                     0x8d => self.reg.l_reg = self.alu_res(self.reg.l_reg, 1),
                     0x8e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 1);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x8f => self.reg.a_reg = self.alu_res(self.reg.a_reg, 1),
                     0x90 => self.reg.b_reg = self.alu_res(self.reg.b_reg, 2),
@@ -1186,9 +1357,9 @@ impl Cpu {
                     0x95 => self.reg.l_reg = self.alu_res(self.reg.l_reg, 2),
                     0x96 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 2);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x97 => self.reg.a_reg = self.alu_res(self.reg.a_reg, 2),
                     0x98 => self.reg.b_reg = self.alu_res(self.reg.b_reg, 3),
@@ -1199,9 +1370,9 @@ impl Cpu {
                     0x9d => self.reg.l_reg = self.alu_res(self.reg.l_reg, 3),
                     0x9e => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 3);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0x9f => self.reg.a_reg = self.alu_res(self.reg.a_reg, 3),
                     0xa0 => self.reg.b_reg = self.alu_res(self.reg.b_reg, 4),
@@ -1212,9 +1383,9 @@ impl Cpu {
                     0xa5 => self.reg.l_reg = self.alu_res(self.reg.l_reg, 4),
                     0xa6 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 4);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xa7 => self.reg.a_reg = self.alu_res(self.reg.a_reg, 4),
                     0xa8 => self.reg.b_reg = self.alu_res(self.reg.b_reg, 5),
@@ -1225,9 +1396,9 @@ impl Cpu {
                     0xad => self.reg.l_reg = self.alu_res(self.reg.l_reg, 5),
                     0xae => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 5);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xaf => self.reg.a_reg = self.alu_res(self.reg.a_reg, 5),
                     0xb0 => self.reg.b_reg = self.alu_res(self.reg.b_reg, 6),
@@ -1238,9 +1409,9 @@ impl Cpu {
                     0xb5 => self.reg.l_reg = self.alu_res(self.reg.l_reg, 6),
                     0xb6 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 6);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xb7 => self.reg.a_reg = self.alu_res(self.reg.a_reg, 6),
                     0xb8 => self.reg.b_reg = self.alu_res(self.reg.b_reg, 7),
@@ -1251,9 +1422,9 @@ impl Cpu {
                     0xbd => self.reg.l_reg = self.alu_res(self.reg.l_reg, 7),
                     0xbe => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 7);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xbf => self.reg.a_reg = self.alu_res(self.reg.a_reg, 7),
 
@@ -1266,9 +1437,9 @@ impl Cpu {
                     0xc5 => self.reg.l_reg = self.alu_set(self.reg.l_reg, 0),
                     0xc6 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 0);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xc7 => self.reg.a_reg = self.alu_set(self.reg.a_reg, 0),
                     0xc8 => self.reg.b_reg = self.alu_set(self.reg.b_reg, 1),
@@ -1279,9 +1450,9 @@ impl Cpu {
                     0xcd => self.reg.l_reg = self.alu_set(self.reg.l_reg, 1),
                     0xce => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 1);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xcf => self.reg.a_reg = self.alu_set(self.reg.a_reg, 1),
                     0xd0 => self.reg.b_reg = self.alu_set(self.reg.b_reg, 2),
@@ -1292,9 +1463,9 @@ impl Cpu {
                     0xd5 => self.reg.l_reg = self.alu_set(self.reg.l_reg, 2),
                     0xd6 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 2);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xd7 => self.reg.a_reg = self.alu_set(self.reg.a_reg, 2),
                     0xd8 => self.reg.b_reg = self.alu_set(self.reg.b_reg, 3),
@@ -1305,9 +1476,9 @@ impl Cpu {
                     0xdd => self.reg.l_reg = self.alu_set(self.reg.l_reg, 3),
                     0xde => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 3);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xdf => self.reg.a_reg = self.alu_set(self.reg.a_reg, 3),
                     0xe0 => self.reg.b_reg = self.alu_set(self.reg.b_reg, 4),
@@ -1318,9 +1489,9 @@ impl Cpu {
                     0xe5 => self.reg.l_reg = self.alu_set(self.reg.l_reg, 4),
                     0xe6 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 4);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xe7 => self.reg.a_reg = self.alu_set(self.reg.a_reg, 4),
                     0xe8 => self.reg.b_reg = self.alu_set(self.reg.b_reg, 5),
@@ -1331,9 +1502,9 @@ impl Cpu {
                     0xed => self.reg.l_reg = self.alu_set(self.reg.l_reg, 5),
                     0xee => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 5);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xef => self.reg.a_reg = self.alu_set(self.reg.a_reg, 5),
                     0xf0 => self.reg.b_reg = self.alu_set(self.reg.b_reg, 6),
@@ -1344,9 +1515,9 @@ impl Cpu {
                     0xf5 => self.reg.l_reg = self.alu_set(self.reg.l_reg, 6),
                     0xf6 => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 6);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xf7 => self.reg.a_reg = self.alu_set(self.reg.a_reg, 6),
                     0xf8 => self.reg.b_reg = self.alu_set(self.reg.b_reg, 7),
@@ -1357,76 +1528,32 @@ impl Cpu {
                     0xfd => self.reg.l_reg = self.alu_set(self.reg.l_reg, 7),
                     0xfe => {
                         let a = self.reg.parse_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 7);
-                        self.mem.borrow_mut().set(a, h);
+                        mem.set(a, h);
                     }
                     0xff => self.reg.a_reg = self.alu_set(self.reg.a_reg, 7),
                 }
             }
-            0xd3 => panic!("Opcode 0xd3 is not implemented"),
-            0xdb => panic!("Opcode 0xdb is not implemented"),
-            0xdd => panic!("Opcode 0xdd is not implemented"),
-            0xe3 => panic!("Opcode 0xe3 is not implemented"),
-            0xe4 => panic!("Opcode 0xd4 is not implemented"),
-            0xeb => panic!("Opcode 0xeb is not implemented"),
-            0xec => panic!("Opcode 0xec is not implemented"),
-            0xed => panic!("Opcode 0xed is not implemented"),
-            0xf4 => panic!("Opcode 0xf4 is not implemented"),
-            0xfc => panic!("Opcode 0xfc is not implemented"),
-            0xfd => panic!("Opcode 0xfd is not implemented"),
+            // Undefined opcodes hang the CPU until power off
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                if self.strict {
+                    panic!("Undefined opcode 0x{:02x} at 0x{:04x}", opcode, self.reg.program_counter.wrapping_sub(1));
+                }
+                self.locked = true;
+            }
         };
 
+        // Extra cycles when a conditional branch is taken, based on the same flag the branch tested
         let ecycle = match opcode {
-            0x20 | 0x30 => {
-                if self.reg.get_flag(ZeroFlag) {
-                    0x00
-                } else {
-                    0x01
-                }
-            }
-            0x28 | 0x38 => {
-                if self.reg.get_flag(ZeroFlag) {
-                    0x01
-                } else {
-                    0x00
-                }
-            }
-            0xc0 | 0xd0 => {
-                if self.reg.get_flag(ZeroFlag) {
-                    0x00
-                } else {
-                    0x03
-                }
-            }
-            0xc8 | 0xcc | 0xd8 | 0xdc => {
-                if self.reg.get_flag(ZeroFlag) {
-                    0x03
-                } else {
-                    0x00
-                }
-            }
-            0xc2 | 0xd2 => {
-                if self.reg.get_flag(ZeroFlag) {
-                    0x00
-                } else {
-                    0x01
-                }
-            }
-            0xca | 0xda => {
-                if self.reg.get_flag(ZeroFlag) {
-                    0x01
-                } else {
-                    0x00
-                }
-            }
-            0xc4 | 0xd4 => {
-                if self.reg.get_flag(ZeroFlag) {
-                    0x00
-                } else {
-                    0x03
-                }
-            }
+            0x20 | 0xc2 => u32::from(!self.reg.get_flag(ZeroFlag)),
+            0x28 | 0xca => u32::from(self.reg.get_flag(ZeroFlag)),
+            0x30 | 0xd2 => u32::from(!self.reg.get_flag(CarryFlag)),
+            0x38 | 0xda => u32::from(self.reg.get_flag(CarryFlag)),
+            0xc0 | 0xc4 => u32::from(!self.reg.get_flag(ZeroFlag)) * 3,
+            0xc8 | 0xcc => u32::from(self.reg.get_flag(ZeroFlag)) * 3,
+            0xd0 | 0xd4 => u32::from(!self.reg.get_flag(CarryFlag)) * 3,
+            0xd8 | 0xdc => u32::from(self.reg.get_flag(CarryFlag)) * 3,
             _ => 0x00,
         };
         if opcode == 0xcb {
@@ -1437,6 +1564,9 @@ impl Cpu {
     }
 
     pub fn next(&mut self) -> u32 {
+        if self.locked {
+            return OP_CYCLES[0] * 4;
+        }
         let mac = {
             let c = self.hi();
             if c != 0 {
@@ -1456,26 +1586,67 @@ pub struct RTC {
     step_cycles: u32,
     step_zero: time::Instant,
     step_flip: bool,
+    // Milliseconds between sleeps and the cycles that take, STEP_TIME and STEP_CYCLES by default
+    step_time: u32,
+    step_length: u32,
+    throttle: bool,
+    turbo: bool,
+    turbo_multiplier: f32,
 }
 
 impl RTC {
     pub fn power_up(term: Term, mem: Rc<RefCell<dyn Memory>>) -> Self {
         let cpu = Cpu::power_up(term, mem);
-        Self { cpu, step_cycles: 0, step_zero: time::Instant::now(), step_flip: false }
+        Self { cpu, step_cycles: 0, step_zero: time::Instant::now(), step_flip: false, step_time: STEP_TIME, step_length: STEP_CYCLES, throttle: true, turbo: false, turbo_multiplier: 4.0 }
+    }
+
+    /// Turns pacing to real time on or off, headless runs go as fast as the host allows
+    pub fn set_throttle(&mut self, throttle: bool) {
+        self.throttle = throttle;
+    }
+
+    /// Fast forwards while on, capped by the turbo multiplier
+    pub fn set_turbo(&mut self, turbo: bool) {
+        self.turbo = turbo;
+    }
+
+    /// Caps turbo at this multiple of real time, 0 or less lets it run unbounded
+    pub fn set_turbo_multiplier(&mut self, multiplier: f32) {
+        self.turbo_multiplier = multiplier;
     }
+
+    /// Sleeps every ms of emulated time instead of every STEP_TIME, smaller steps pace more evenly
+    /// * Also sets how often flip turns true, 0 is taken as 1
+    pub fn set_step_time(&mut self, ms: u32) {
+        self.step_time = ms.max(1);
+        self.step_length = step_cycles(self.step_time);
+    }
+
+    /// Wall time one step should take, None when it shouldn't be paced at all
+    fn step_time(&self) -> Option<time::Duration> {
+        let step = time::Duration::from_millis(u64::from(self.step_time));
+        match (self.throttle, self.turbo) {
+            (false, _) => None,
+            (true, false) => Some(step),
+            (true, true) if self.turbo_multiplier > 0.0 => Some(step.div_f32(self.turbo_multiplier)),
+            (true, true) => None,
+        }
+    }
+
     pub fn next(&mut self) -> u32 {
-        if self.step_cycles > STEP_CYCLES {
+        if self.step_cycles > self.step_length {
             self.step_flip = true;
-            self.step_cycles -= STEP_CYCLES;
-            let now = time::Instant::now();
-            let d = now.duration_since(self.step_zero);
-            let s = u64::from(STEP_TIME.saturating_sub(d.as_millis() as u32));
-            thread::sleep(time::Duration::from_millis(s));
-            self.step_zero = self.step_zero.checked_add(time::Duration::from_millis(u64::from(STEP_TIME))).unwrap();
+            self.step_cycles -= self.step_length;
+            if let Some(step) = self.step_time() {
+                let now = time::Instant::now();
+                let d = now.duration_since(self.step_zero);
+                thread::sleep(step.saturating_sub(d));
+                self.step_zero = self.step_zero.checked_add(step).unwrap();
 
 
-            if now.checked_duration_since(self.step_zero).is_some() {
-                self.step_zero = now;
+                if now.checked_duration_since(self.step_zero).is_some() {
+                    self.step_zero = now;
+                }
             }
         }
         let cycles = self.cpu.next();
@@ -1490,4 +1661,400 @@ impl RTC {
         }
         r
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64 KiB of plain RAM, so programs and I/O bytes can be poked anywhere
+    struct Flat(Box<[u8; 0x10000]>);
+
+    impl Memory for Flat {
+        fn get(&self, a: u16) -> u8 {
+            self.0[a as usize]
+        }
+
+        fn set(&mut self, a: u16, v: u8) {
+            self.0[a as usize] = v;
+        }
+    }
+
+    /// DMG CPU with the program at 0x0100, where PC starts
+    fn cpu(program: &[u8]) -> (Cpu, Rc<RefCell<Flat>>) {
+        let mut flat = Flat(Box::new([0x00; 0x10000]));
+        flat.0[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        let mem = Rc::new(RefCell::new(flat));
+        (Cpu::power_up(Term::GB, mem.clone()), mem)
+    }
+
+    #[test]
+    fn vblank_dispatch_takes_five_machine_cycles() {
+        let (mut cpu, mem) = cpu(&[0x00]);
+        mem.borrow_mut().set(0xFFFF, 0x01);
+        mem.borrow_mut().set(0xFF0F, 0x01);
+        let sp = cpu.reg.stack_pointer;
+        assert_eq!(cpu.next(), 20);
+        assert_eq!(cpu.reg.program_counter, 0x0040);
+        assert_eq!(cpu.reg.stack_pointer, sp - 2);
+        assert_eq!(mem.borrow().get_word(sp - 2), 0x0100);
+        assert_eq!(mem.borrow().get(0xFF0F), 0x00);
+        assert!(!cpu.ei);
+    }
+
+    #[test]
+    fn conditional_branches_cost_more_when_taken() {
+        // (program, carry that takes the branch, clocks taken, clocks not taken)
+        let cases: [(&[u8], bool, u32, u32); 3] = [
+            (&[0xDA, 0x00, 0x02], true, 16, 12), // jp c, 0x0200
+            (&[0xD0], false, 20, 8),             // ret nc
+            (&[0xDC, 0x00, 0x02], true, 24, 12), // call c, 0x0200
+        ];
+        for (program, carry, taken, not_taken) in cases {
+            let run = |c: bool| {
+                let (mut cpu, _mem) = cpu(program);
+                cpu.reg.stack_pointer = 0xDFF0;
+                cpu.reg.set_flag(CarryFlag, c);
+                (cpu.next(), cpu.reg.program_counter)
+            };
+            assert_eq!(run(carry).0, taken, "{:02x} taken", program[0]);
+            assert_eq!(run(!carry), (not_taken, 0x0100 + program.len() as u16), "{:02x} not taken", program[0]);
+        }
+    }
+
+    #[test]
+    fn turbo_multiplier_caps_the_step_sleep() {
+        let (_, mem) = cpu(&[]);
+        let mut rtc = RTC::power_up(Term::GB, mem);
+        let normal = time::Duration::from_millis(u64::from(STEP_TIME));
+        assert_eq!(rtc.step_time(), Some(normal));
+        rtc.set_turbo(true);
+        rtc.set_turbo_multiplier(2.0);
+        assert_eq!(rtc.step_time(), Some(normal / 2));
+        rtc.set_turbo_multiplier(0.0);
+        assert_eq!(rtc.step_time(), None);
+        rtc.set_throttle(false);
+        rtc.set_turbo(false);
+        assert_eq!(rtc.step_time(), None);
+    }
+
+    #[test]
+    fn ld_b_b_reports_the_mooneye_result() {
+        let results = [([3, 5, 8, 13, 21, 34], Some(Magic::Passed)), ([0x42; 6], Some(Magic::Failed)), ([1, 2, 3, 4, 5, 6], None)];
+        for (regs, expected) in results {
+            let (mut cpu, _mem) = cpu(&[0x40, 0x40]);
+            [cpu.reg.b_reg, cpu.reg.c_reg, cpu.reg.d_reg, cpu.reg.e_reg, cpu.reg.h_reg, cpu.reg.l_reg] = regs;
+            cpu.next();
+            assert_eq!(cpu.take_magic(), None, "breakpoint is off by default");
+            cpu.set_magic_breakpoint(true);
+            cpu.next();
+            assert_eq!(cpu.take_magic(), expected);
+            assert_eq!(cpu.take_magic(), None);
+        }
+    }
+
+    /// B, C, D, E, H, L, (HL), A as an opcode's operand bits number them
+    /// * (HL) is read at the given address, as LD H or LD L moves what HL points to
+    fn operands(cpu: &Cpu, hl: u16) -> [u8; 8] {
+        let r = &cpu.reg;
+        [r.b_reg, r.c_reg, r.d_reg, r.e_reg, r.h_reg, r.l_reg, cpu.mem.borrow().get(hl), r.a_reg]
+    }
+
+    /// Loads the operands in the same order, (HL) is written where H and L point
+    fn set_operands(cpu: &mut Cpu, v: [u8; 8]) {
+        let r = &mut cpu.reg;
+        [r.b_reg, r.c_reg, r.d_reg, r.e_reg, r.h_reg, r.l_reg, r.a_reg] = [v[0], v[1], v[2], v[3], v[4], v[5], v[7]];
+        cpu.mem.borrow_mut().set(cpu.reg.parse_hl(), v[6]);
+    }
+
+    #[test]
+    fn ld_r8_r8_copies_every_source_to_every_destination() {
+        let before = [0x11, 0x22, 0x33, 0x44, 0xC1, 0x23, 0x77, 0x88];
+        for opcode in 0x40..=0x7Fu8 {
+            let (mut cpu, _mem) = cpu(&[opcode]);
+            set_operands(&mut cpu, before);
+            cpu.reg.f_reg = 0xB0;
+            let clocks = cpu.next();
+            assert_eq!(cpu.reg.program_counter, 0x0101, "{:02x}", opcode);
+            assert_eq!(cpu.reg.f_reg, 0xB0, "{:02x} touched the flags", opcode);
+            if opcode == 0x76 {
+                assert!(cpu.halted);
+                assert_eq!(operands(&cpu, 0xC123), before);
+                continue;
+            }
+            let (dst, src) = (usize::from(opcode >> 3 & 0x07), usize::from(opcode & 0x07));
+            let mut expected = before;
+            expected[dst] = before[src];
+            assert_eq!(operands(&cpu, 0xC123), expected, "{:02x}", opcode);
+            assert_eq!(clocks, if dst == 6 || src == 6 { 8 } else { 4 }, "{:02x}", opcode);
+        }
+    }
+
+    /// A and F after ADD, ADC, SUB, SBC, AND, XOR, OR or CP, worked out from the wide result
+    fn alu_reference(op: u8, a: u8, v: u8, carry: bool) -> (u8, u8) {
+        let c = u16::from(carry && (op == 1 || op == 3));
+        let (a16, v16) = (u16::from(a), u16::from(v));
+        let (r, n, h, cy) = match op {
+            0 | 1 => (a16 + v16 + c, false, (a16 & 0x0F) + (v16 & 0x0F) + c > 0x0F, a16 + v16 + c > 0xFF),
+            2 | 3 | 7 => (a16.wrapping_sub(v16 + c), true, a16 & 0x0F < (v16 & 0x0F) + c, a16 < v16 + c),
+            4 => (a16 & v16, false, true, false),
+            5 => (a16 ^ v16, false, false, false),
+            _ => (a16 | v16, false, false, false),
+        };
+        let r = r as u8;
+        let f = u8::from(r == 0x00) << 7 | u8::from(n) << 6 | u8::from(h) << 5 | u8::from(cy) << 4;
+        (if op == 7 { a } else { r }, f)
+    }
+
+    #[test]
+    fn alu_opcodes_match_the_reference_for_every_operand() {
+        let values = [0x00, 0x01, 0x0F, 0x10, 0x3C, 0x7F, 0x80, 0xFF];
+        for opcode in 0x80..=0xBFu8 {
+            let (op, src) = (opcode >> 3 & 0x07, usize::from(opcode & 0x07));
+            for (a, v, carry) in values.iter().flat_map(|&a| values.iter().flat_map(move |&v| [(a, v, false), (a, v, true)])) {
+                let (mut cpu, _mem) = cpu(&[opcode]);
+                // H or L as the source moves (HL), which stays clear of the program at 0x0100
+                let mut before = [0x11, 0x22, 0x33, 0x44, 0xC1, 0x23, 0x77, a];
+                before[src] = v;
+                let hl = u16::from(before[4]) << 8 | u16::from(before[5]);
+                set_operands(&mut cpu, before);
+                cpu.reg.f_reg = if carry { 0x10 } else { 0x00 };
+                let clocks = cpu.next();
+                // With A as the source, the operand is whatever A holds
+                let operand = before[src];
+                let (a, f) = alu_reference(op, before[7], operand, carry);
+                let mut expected = before;
+                expected[7] = a;
+                assert_eq!(operands(&cpu, hl), expected, "{:02x} a={:02x} v={:02x} c={}", opcode, before[7], operand, carry);
+                assert_eq!(cpu.reg.f_reg, f, "{:02x} a={:02x} v={:02x} c={}", opcode, before[7], operand, carry);
+                assert_eq!(clocks, if src == 6 { 8 } else { 4 }, "{:02x}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn instr_len_counts_the_operands() {
+        // nop, ld b d8, jp a16, the CB prefix with its second byte, ld a (a16), ldh (a8) a
+        for (opcode, len) in [(0x00, 1), (0x06, 2), (0xC3, 3), (0xCB, 2), (0xFA, 3), (0xE0, 2)] {
+            assert_eq!(INSTR_LEN[opcode], len, "{:02x}", opcode);
+        }
+    }
+
+    #[test]
+    fn state_line_matches_gameboy_doctor_at_power_up() {
+        let (cpu, _mem) = cpu(&[0x00, 0xC3, 0x13, 0x02]);
+        assert_eq!(cpu.state_line(), "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02");
+    }
+
+    #[test]
+    fn profile_counts_a_tight_loop() {
+        // swap a / dec b / jr nz, -5 ten times, then jr -2
+        let (mut cpu, _mem) = cpu(&[0xCB, 0x37, 0x05, 0x20, 0xFB, 0x18, 0xFE]);
+        cpu.reg.b_reg = 10;
+        cpu.set_profiling(true);
+        for _ in 0..35 {
+            cpu.next();
+        }
+        let profile = cpu.take_profile();
+        assert_eq!(profile.opcodes, [(0x05, 10), (0x20, 10), (0xCB, 10), (0x18, 5)]);
+        assert_eq!(profile.pages, [(0x01, 35)]);
+        assert!(cpu.take_profile().opcodes.is_empty());
+        cpu.set_profiling(false);
+        cpu.next();
+        assert!(cpu.take_profile().opcodes.is_empty());
+    }
+
+    #[test]
+    fn add_sp_and_ld_hl_sp_share_their_flags() {
+        // (SP, e8, result, F), the flags come from the unsigned low byte add whatever the sign of e8
+        let cases = [
+            (0x00FF, 0x01, 0x0100, 0x30),
+            (0x000F, 0x01, 0x0010, 0x20),
+            (0xFFF8, 0x08, 0x0000, 0x30),
+            (0x1000, 0xFF, 0x0FFF, 0x00),
+            (0x10FF, 0xFF, 0x10FE, 0x30),
+            (0x1234, 0x00, 0x1234, 0x00),
+        ];
+        for (sp, e8, result, f) in cases {
+            let (mut add, _mem) = cpu(&[0xE8, e8]);
+            add.reg.stack_pointer = sp;
+            add.reg.f_reg = 0xC0;
+            assert_eq!(add.next(), 16);
+            assert_eq!((add.reg.stack_pointer, add.reg.f_reg), (result, f), "add sp, {:02x} at {:04x}", e8, sp);
+            let (mut ld, _mem) = cpu(&[0xF8, e8]);
+            ld.reg.stack_pointer = sp;
+            ld.reg.f_reg = 0xC0;
+            assert_eq!(ld.next(), 12);
+            assert_eq!((ld.reg.parse_hl(), ld.reg.stack_pointer, ld.reg.f_reg), (result, sp, f), "ld hl, sp+{:02x} at {:04x}", e8, sp);
+        }
+    }
+
+    #[test]
+    fn undefined_opcode_locks_up_in_lenient_mode() {
+        let (mut cpu, mem) = cpu(&[0xD3, 0x00]);
+        cpu.next();
+        assert!(cpu.is_locked());
+        assert_eq!(cpu.reg.program_counter, 0x0101);
+        // Not even an interrupt gets it going again
+        mem.borrow_mut().set(0xFFFF, 0x01);
+        mem.borrow_mut().set(0xFF0F, 0x01);
+        cpu.next();
+        assert_eq!(cpu.reg.program_counter, 0x0101);
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined opcode 0xd3 at 0x0100")]
+    fn undefined_opcode_panics_in_strict_mode() {
+        let (mut cpu, _mem) = cpu(&[0xD3]);
+        cpu.set_strict(true);
+        cpu.next();
+    }
+
+    #[test]
+    fn stop_length_ignores_the_second_byte() {
+        for second in [0x00, 0x01] {
+            // Nothing pending, nothing held: two bytes and a speed switch
+            let (mut free, mem) = cpu(&[0x10, second]);
+            mem.borrow_mut().set(0xFF00, 0xCF);
+            free.next();
+            assert_eq!(free.reg.program_counter, 0x0102, "10 {:02X}", second);
+            assert!(free.take_stop());
+
+            // A pending interrupt, masked by DI, makes STOP one byte
+            let (mut pending, mem) = cpu(&[0x10, second]);
+            mem.borrow_mut().set(0xFF00, 0xCF);
+            mem.borrow_mut().set(0xFFFF, 0x01);
+            mem.borrow_mut().set(0xFF0F, 0x01);
+            pending.ei = false;
+            pending.next();
+            assert_eq!(pending.reg.program_counter, 0x0101, "10 {:02X} with an interrupt pending", second);
+            assert!(pending.take_stop());
+
+            // A held button blocks the switch
+            let (mut held, mem) = cpu(&[0x10, second]);
+            mem.borrow_mut().set(0xFF00, 0xCE);
+            held.next();
+            assert_eq!(held.reg.program_counter, 0x0102);
+            assert!(!held.take_stop());
+        }
+    }
+
+    #[test]
+    fn dispatch_ignores_ie_and_if_bits_5_to_7() {
+        let (mut cpu, mem) = cpu(&[0x76, 0x00]);
+        mem.borrow_mut().set(0xFFFF, 0xE0);
+        mem.borrow_mut().set(0xFF0F, 0xE0);
+        // HALT isn't woken and nothing is dispatched
+        cpu.next();
+        cpu.next();
+        assert!(cpu.halted);
+        assert_eq!(cpu.reg.program_counter, 0x0101);
+        // A real line does both
+        mem.borrow_mut().set(0xFF0F, 0xE4);
+        mem.borrow_mut().set(0xFFFF, 0xE4);
+        cpu.next();
+        assert_eq!(cpu.reg.program_counter, 0x0050);
+        assert_eq!(mem.borrow().get(0xFF0F), 0xE0);
+    }
+
+    #[test]
+    fn step_time_8_halves_the_step() {
+        // Cycles run before flip turns true, unthrottled over a field of NOPs
+        let cycles_to_flip = |rtc: &mut RTC| {
+            let mut cycles = 0;
+            while !rtc.flip() {
+                cycles += rtc.next();
+            }
+            cycles
+        };
+        let (_, mem) = cpu(&[]);
+        let mut rtc = RTC::power_up(Term::GB, mem);
+        rtc.set_throttle(false);
+        assert_eq!(rtc.step_length, STEP_CYCLES);
+        let full = cycles_to_flip(&mut rtc);
+
+        let (_, mem) = cpu(&[]);
+        let mut rtc = RTC::power_up(Term::GB, mem);
+        rtc.set_throttle(false);
+        rtc.set_step_time(8);
+        assert_eq!(rtc.step_length, STEP_CYCLES / 2);
+        rtc.set_throttle(true);
+        assert_eq!(rtc.step_time(), Some(time::Duration::from_millis(8)));
+        rtc.set_throttle(false);
+        let half = cycles_to_flip(&mut rtc);
+        assert!(half.abs_diff(full / 2) <= 4, "{} cycles against {}", half, full);
+    }
+
+    #[test]
+    fn daa_table_for_add_and_sub() {
+        // (N, H before, C before, A before, A after, Z, C after), H is always cleared and N kept
+        let table = [
+            (false, false, false, 0x00, 0x00, true, false),
+            (false, false, false, 0x09, 0x09, false, false),
+            (false, false, false, 0x0A, 0x10, false, false),
+            (false, false, false, 0x99, 0x99, false, false),
+            (false, false, false, 0x9A, 0x00, true, true),
+            (false, false, false, 0xFF, 0x65, false, true),
+            (false, false, true, 0x00, 0x60, false, true),
+            (false, false, true, 0x09, 0x69, false, true),
+            (false, false, true, 0x0A, 0x70, false, true),
+            (false, false, true, 0x99, 0xF9, false, true),
+            (false, false, true, 0x9A, 0x00, true, true),
+            (false, false, true, 0xFF, 0x65, false, true),
+            (false, true, false, 0x00, 0x06, false, false),
+            (false, true, false, 0x09, 0x0F, false, false),
+            (false, true, false, 0x0A, 0x10, false, false),
+            (false, true, false, 0x99, 0x9F, false, false),
+            (false, true, false, 0x9A, 0x00, true, true),
+            (false, true, false, 0xFF, 0x65, false, true),
+            (false, true, true, 0x00, 0x66, false, true),
+            (false, true, true, 0x09, 0x6F, false, true),
+            (false, true, true, 0x0A, 0x70, false, true),
+            (false, true, true, 0x99, 0xFF, false, true),
+            (false, true, true, 0x9A, 0x00, true, true),
+            (false, true, true, 0xFF, 0x65, false, true),
+            (true, false, false, 0x00, 0x00, true, false),
+            (true, false, false, 0x09, 0x09, false, false),
+            (true, false, false, 0x0A, 0x0A, false, false),
+            (true, false, false, 0x99, 0x99, false, false),
+            (true, false, false, 0x9A, 0x9A, false, false),
+            (true, false, false, 0xFF, 0xFF, false, false),
+            (true, false, true, 0x00, 0xA0, false, true),
+            (true, false, true, 0x09, 0xA9, false, true),
+            (true, false, true, 0x0A, 0xAA, false, true),
+            (true, false, true, 0x99, 0x39, false, true),
+            (true, false, true, 0x9A, 0x3A, false, true),
+            (true, false, true, 0xFF, 0x9F, false, true),
+            (true, true, false, 0x00, 0xFA, false, false),
+            (true, true, false, 0x09, 0x03, false, false),
+            (true, true, false, 0x0A, 0x04, false, false),
+            (true, true, false, 0x99, 0x93, false, false),
+            (true, true, false, 0x9A, 0x94, false, false),
+            (true, true, false, 0xFF, 0xF9, false, false),
+            (true, true, true, 0x00, 0x9A, false, true),
+            (true, true, true, 0x09, 0xA3, false, true),
+            (true, true, true, 0x0A, 0xA4, false, true),
+            (true, true, true, 0x99, 0x33, false, true),
+            (true, true, true, 0x9A, 0x34, false, true),
+            (true, true, true, 0xFF, 0x99, false, true),
+        ];
+        for (n, h, c, a, expected, z, carry) in table {
+            let (mut cpu, _mem) = cpu(&[0x27]);
+            cpu.reg.a_reg = a;
+            cpu.reg.f_reg = 0x00;
+            cpu.reg.set_flag(SubtractionFlag, n);
+            cpu.reg.set_flag(HalfCarryFlag, h);
+            cpu.reg.set_flag(CarryFlag, c);
+            cpu.next();
+            let case = format!("daa of 0x{:02X} with n={} h={} c={}", a, n, h, c);
+            assert_eq!(cpu.reg.a_reg, expected, "{}", case);
+            assert_eq!(cpu.reg.get_flag(ZeroFlag), z, "{}", case);
+            assert_eq!(cpu.reg.get_flag(CarryFlag), carry, "{}", case);
+            assert!(!cpu.reg.get_flag(HalfCarryFlag), "{}", case);
+            assert_eq!(cpu.reg.get_flag(SubtractionFlag), n, "{}", case);
+            if n {
+                assert_eq!(carry, c, "{}: after a SUB only a carry that was set stays set", case);
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Why a rom couldn't be loaded, with enough context to show the user
+#[derive(Debug)]
+pub enum EmuError {
+    /// The rom couldn't be read or unpacked
+    Io { path: PathBuf, source: std::io::Error },
+    /// The header asks for a mapper that isn't emulated
+    UnsupportedCartridge { path: PathBuf, mbc: u8 },
+    /// The file isn't a usable rom, reason says which check failed
+    BadRom { path: PathBuf, reason: String },
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmuError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            EmuError::UnsupportedCartridge { path, mbc } => write!(f, "{}: unsupported cartridge type 0x{:02x}", path.display(), mbc),
+            EmuError::BadRom { path, reason } => write!(f, "{}: {}", path.display(), reason),
+        }
+    }
+}
+
+/// Something odd about a rom that still loaded, for the frontend to pass on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadWarning {
+    /// The header checksum at 0x014D doesn't match the header, LoadOptions allowed it
+    BadHeaderChecksum { found: u8, expected: u8 },
+}
+
+impl fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadWarning::BadHeaderChecksum { found, expected } => write!(f, "header checksum is 0x{:02x}, expected 0x{:02x}", found, expected),
+        }
+    }
+}
+
+impl std::error::Error for EmuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmuError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
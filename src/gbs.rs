@@ -0,0 +1,224 @@
+use super::cartridge::{Cartridge, Stable};
+use super::cpu;
+use super::error::EmuError;
+use super::gpu::FRAME_CYCLES;
+use super::joypad::{Joypad, Key};
+use super::mem::Memory;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Address of the spin loop the player returns to after init/play, the CPU idles here between calls
+pub const IDLE_ADDR: u16 = 0x0100;
+
+/// Header at the start of every .gbs file
+pub struct GbsHeader {
+    pub song_count: u8,
+    pub first_song: u8,
+    pub load: u16,
+    pub init: u16,
+    pub play: u16,
+    pub sp: u16,
+    pub tma: u8,
+    pub tac: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub const SIZE: usize = 0x70;
+
+    /// Reads the header, path is only used to report errors
+    pub fn parse(data: &[u8], path: impl AsRef<Path>) -> Result<Self, EmuError> {
+        let bad = |reason: String| EmuError::BadRom { path: path.as_ref().to_path_buf(), reason };
+        if data.len() < Self::SIZE || &data[0..3] != b"GBS" {
+            return Err(bad(String::from("Not a GBS file")));
+        }
+        let word = |i: usize| u16::from(data[i]) | (u16::from(data[i + 1]) << 8);
+        let text = |i: usize| data[i..i + 32].iter().take_while(|&&c| c != 0).map(|&c| c as char).collect();
+        let header = Self {
+            song_count: data[0x04],
+            first_song: data[0x05],
+            load: word(0x06),
+            init: word(0x08),
+            play: word(0x0A),
+            sp: word(0x0C),
+            tma: data[0x0E],
+            tac: data[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+        };
+        if header.load < 0x0400 {
+            return Err(bad(format!("GBS load address 0x{:04x} is below 0x0400", header.load)));
+        }
+        Ok(header)
+    }
+
+    /// Number of CPU cycles between two calls of the play routine
+    /// * Uses the timer rate from TMA/TAC when the timer is enabled, v-blank otherwise
+    pub fn play_period(&self) -> u32 {
+        if self.tac & 0x04 == 0x00 {
+            return FRAME_CYCLES;
+        }
+        let frequency = match self.tac & 0x03 {
+            0x00 => 4096,
+            0x01 => 262144,
+            0x02 => 65536,
+            _ => 16384,
+        };
+        cpu::CLOCK_FREQUENCY / frequency * (256 - u32::from(self.tma))
+    }
+}
+
+/// Synthetic cartridge holding the music code at its load address with MBC1 style rom banking
+pub struct Gbs {
+    pub header: GbsHeader,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_bank: usize,
+}
+
+impl Gbs {
+    /// Builds the cartridge from a .gbs file already in memory, path is only used to report errors
+    pub fn power_up(data: &[u8], path: impl AsRef<Path>) -> Result<Self, EmuError> {
+        let header = GbsHeader::parse(data, path)?;
+        let load = header.load as usize;
+        let code = &data[GbsHeader::SIZE..];
+        let size = (load + code.len()).div_ceil(0x4000).max(2) * 0x4000;
+        let mut rom = vec![0x00; size];
+        rom[load..load + code.len()].copy_from_slice(code);
+        // RST vectors are relocated relative to the load address
+        for v in (0x00..0x40).step_by(8) {
+            let target = header.load + v as u16;
+            rom[v] = 0xC3;
+            rom[v + 1] = target as u8;
+            rom[v + 2] = (target >> 8) as u8;
+        }
+        // JR -2, the CPU spins here while waiting for the next call
+        rom[IDLE_ADDR as usize] = 0x18;
+        rom[IDLE_ADDR as usize + 1] = 0xFE;
+        Ok(Self { header, rom, ram: vec![0x00; 0x2000], rom_bank: 1 })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, EmuError> {
+        let mut data = Vec::new();
+        File::open(path.as_ref())
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|source| EmuError::Io { path: path.as_ref().to_path_buf(), source })?;
+        Self::power_up(&data, path)
+    }
+}
+
+impl Memory for Gbs {
+    fn get(&self, a: u16) -> u8 {
+        match a {
+            0x0000..=0x3FFF => self.rom[a as usize],
+            0x4000..=0x7FFF => {
+                let banks = self.rom.len() / 0x4000;
+                let i = (self.rom_bank % banks) * 0x4000 + a as usize - 0x4000;
+                self.rom[i]
+            }
+            0xA000..=0xBFFF => self.ram[a as usize - 0xA000],
+            _ => 0x00,
+        }
+    }
+
+    fn set(&mut self, a: u16, v: u8) {
+        match a {
+            0x2000..=0x3FFF => {
+                self.rom_bank = match v {
+                    0x00 => 0x01,
+                    n => n as usize,
+                };
+            }
+            0xA000..=0xBFFF => self.ram[a as usize - 0xA000] = v,
+            _ => {}
+        }
+    }
+}
+
+impl Stable for Gbs {
+    fn sav(&mut self) {}
+}
+
+impl Cartridge for Gbs {
+    fn title(&self) -> String {
+        self.header.title.clone()
+    }
+}
+
+/// Keeps track of which song is playing and when the play routine is due
+pub struct GbsPlayer {
+    pub song: u8,
+    pub song_count: u8,
+    init: u16,
+    play: u16,
+    sp: u16,
+    period: u32,
+    cycles: u32,
+    // Left/Right as of the last poll, so holding one doesn't skip through songs
+    held: (bool, bool),
+}
+
+impl GbsPlayer {
+    pub fn power_up(header: &GbsHeader) -> Self {
+        Self {
+            song: header.first_song.saturating_sub(1),
+            song_count: header.song_count,
+            init: header.init,
+            play: header.play,
+            sp: header.sp,
+            period: header.play_period(),
+            cycles: 0,
+            held: (false, false),
+        }
+    }
+
+    /// Address and stack pointer the init routine is entered with
+    pub fn init_call(&self) -> (u16, u16) {
+        (self.init, self.sp)
+    }
+
+    /// Song picked on the joypad, a fresh Right press is the next one and Left the one before
+    /// * Returns None when neither was newly pressed since the last poll
+    pub fn poll(&mut self, joypad: &Joypad) -> Option<u8> {
+        let held = (joypad.is_down(Key::Left), joypad.is_down(Key::Right));
+        let (left, right) = (held.0 && !self.held.0, held.1 && !self.held.1);
+        self.held = held;
+        match (left, right, self.song) {
+            (_, true, n) => Some(n.wrapping_add(1) % self.song_count.max(1)),
+            (true, _, 0) => Some(self.song_count.saturating_sub(1)),
+            (true, _, n) => Some(n - 1),
+            _ => None,
+        }
+    }
+
+    /// Advances the play timer
+    /// * Returns the play address when a call is due and the CPU is idle
+    pub fn next(&mut self, cycles: u32, pc: u16) -> Option<u16> {
+        self.cycles = self.cycles.saturating_add(cycles);
+        if self.cycles < self.period || pc != IDLE_ADDR {
+            return None;
+        }
+        self.cycles -= self.period;
+        Some(self.play)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_headers_are_errors() {
+        let mut data = vec![0x00; GbsHeader::SIZE];
+        data[0..4].copy_from_slice(b"GBX\x01");
+        assert!(matches!(GbsHeader::parse(&data, "bad.gbs"), Err(EmuError::BadRom { .. })));
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[0x06..0x08].copy_from_slice(&0x0200u16.to_le_bytes());
+        assert!(matches!(GbsHeader::parse(&data, "low.gbs"), Err(EmuError::BadRom { .. })));
+        assert!(matches!(GbsHeader::parse(&data[..0x10], "short.gbs"), Err(EmuError::BadRom { .. })));
+    }
+}
@@ -3,6 +3,7 @@ use super::intf::{Flags, Intf};
 use super::mem::Memory;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::Duration;
 
 #[derive(PartialEq, Eq)]
 pub enum HdmaMode {
@@ -139,13 +140,34 @@ pub enum GrayShades {
     Black = 0x00,
 }
 
-struct Attr {
-    priority: bool,
-    yflip: bool,
-    xflip: bool,
-    palette_num_0: usize,
-    bank: bool,
-    palette_num_1: usize,
+/// Sprite and CGB background tile attributes
+#[derive(Clone, Copy, Debug)]
+pub struct Attr {
+    pub priority: bool,
+    pub yflip: bool,
+    pub xflip: bool,
+    /// DMG palette, 0 for OBP0 and 1 for OBP1
+    pub palette_num_0: usize,
+    pub bank: bool,
+    /// CGB palette
+    pub palette_num_1: usize,
+}
+
+/// One decoded OAM entry, y and x are the raw values so on screen position is (x - 8, y - 16)
+#[derive(Clone, Copy, Debug)]
+pub struct SpriteEntry {
+    pub index: usize,
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub attr: Attr,
+}
+
+impl SpriteEntry {
+    /// Whether any part of the sprite can land on screen
+    pub fn visible(&self) -> bool {
+        self.y > 0 && self.y < SCREEN_H as u8 + 16 && self.x > 0 && self.x < SCREEN_W as u8 + 8
+    }
 }
 
 impl From<u8> for Attr {
@@ -155,7 +177,7 @@ impl From<u8> for Attr {
             priority: u & (1 << 7) != 0,
             yflip: u & (1 << 6) != 0,
             xflip: u & (1 << 5) != 0,
-            palette_num_0: u as usize & (1 << 4),
+            palette_num_0: (u as usize >> 4) & 0x01,
             bank: u & (1 << 3) != 0,
             palette_num_1: u as usize & 0x07, 
         }
@@ -164,6 +186,99 @@ impl From<u8> for Attr {
 
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
+/// Cycles the LCD takes for one frame at normal speed, 154 lines of 456 cycles
+pub const FRAME_CYCLES: u32 = 70224;
+/// Frames per second of the real hardware, about 59.7275 rather than 60
+pub const FRAME_RATE: f64 = 4_194_304.0 / FRAME_CYCLES as f64;
+
+/// Wall time of one frame at the hardware frame rate
+pub fn frame_duration() -> Duration {
+    Duration::from_secs_f64(1.0 / FRAME_RATE)
+}
+
+/// Output layouts supported by `Gpu::write_framebuffer`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 3 bytes per pixel in R, G, B order, `out` must be `SCREEN_W * SCREEN_H * 3` long
+    Rgb888,
+    /// 4 bytes per pixel in R, G, B, A order, `out` must be `SCREEN_W * SCREEN_H * 4` long
+    Rgba8888,
+    /// Packed native endian `0xAARRGGBB` u32 (SDL's ARGB8888), `out` must be `SCREEN_W * SCREEN_H * 4` long
+    Argb8888,
+    /// Packed native endian `RRRRRGGGGGGBBBBB` u16, `out` must be `SCREEN_W * SCREEN_H * 2` long
+    Rgb565,
+}
+
+impl PixelFormat {
+    /// Number of bytes a single pixel takes up in this format
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb888 => 3,
+            PixelFormat::Rgba8888 | PixelFormat::Argb8888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+}
+
+/// Picture drawn around the game screen by the frontend, like the SGB and GBA borders
+/// * The 160x144 screen sits centered in it
+pub struct Border {
+    pixels: Vec<u32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Border {
+    /// Takes width * height RGBA pixels, the border must be at least as large as the screen
+    pub fn power_up(rgba: &[u8], width: usize, height: usize) -> Self {
+        assert_eq!(rgba.len(), width * height * 4);
+        assert!(width >= SCREEN_W && height >= SCREEN_H);
+        let pixels = rgba.chunks_exact(4).map(|p| 0xFF00_0000 | (u32::from(p[0]) << 16) | (u32::from(p[1]) << 8) | u32::from(p[2])).collect();
+        Self { pixels, width, height }
+    }
+
+    /// Top left corner of the screen inside the border
+    pub fn screen_offset(&self) -> (usize, usize) {
+        ((self.width - SCREEN_W) / 2, (self.height - SCREEN_H) / 2)
+    }
+
+    /// Writes the border with the screen on top into out, both as `PixelFormat::Argb8888` u32s
+    /// * out must be `width * height` long
+    pub fn composite(&self, screen: &[u32], out: &mut [u32]) {
+        assert_eq!(screen.len(), SCREEN_W * SCREEN_H);
+        assert_eq!(out.len(), self.pixels.len());
+        out.copy_from_slice(&self.pixels);
+        let (ox, oy) = self.screen_offset();
+        for (y, line) in screen.chunks_exact(SCREEN_W).enumerate() {
+            let start = (oy + y) * self.width + ox;
+            out[start..start + SCREEN_W].copy_from_slice(line);
+        }
+    }
+}
+
+/// Snapshot of the PPU registers for overlays and logging
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PpuStatus {
+    pub lcdc: u8,
+    pub stat_mode: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub scx: u8,
+    pub scy: u8,
+    pub wx: u8,
+    pub wy: u8,
+    /// Line of the window being drawn, None while the window is off or below LY
+    pub window_line: Option<u8>,
+}
+
+/// Mode transition reported by the PPU
+/// * A VBlank waiting to be taken is never replaced by a later HBlank
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PpuEvent {
+    HBlank,
+    /// Carries the frame count including this frame
+    VBlank(u64),
+}
 
 pub struct Gpu {
     pub data: [[[u8; 3]; SCREEN_W]; SCREEN_H],
@@ -183,6 +298,8 @@ pub struct Gpu {
 
     ly: u8,
     lc: u8,
+    // LY == LYC as of the last LY or LYC change, STAT bit 2
+    coincidence: bool,
 
     bgp: u8,
     op0: u8,
@@ -194,6 +311,10 @@ pub struct Gpu {
 
     cobpi: Bgpi,
     cobpd: [[[u8; 3]; 4]; 8],
+    // Entries forced through set_bg_palette/set_obj_palette, bit n of a palette is color n
+    forced_bg: [u8; 8],
+    forced_obj: [u8; 8],
+    palette_override: bool,
 
     ram: [u8; 0x4000],
     ram_bank: usize,
@@ -203,6 +324,13 @@ pub struct Gpu {
     prio: [(bool, usize); SCREEN_W],
 
     dots: u32,
+
+    color_correction: bool,
+    brightness: f32,
+    contrast: f32,
+
+    frames: u64,
+    event: Option<PpuEvent>,
 }
 
 impl Gpu {
@@ -221,6 +349,7 @@ impl Gpu {
             wx: 0x00,
             ly: 0x00,
             lc: 0x00,
+            coincidence: true,
             bgp: 0x00,
             op0: 0x00,
             op1: 0x01,
@@ -228,12 +357,75 @@ impl Gpu {
             cbgpd: [[[0u8; 3]; 4]; 8],
             cobpi: Bgpi::power_up(),
             cobpd: [[[0u8; 3]; 4]; 8],
+            forced_bg: [0x00; 8],
+            forced_obj: [0x00; 8],
+            palette_override: false,
             ram: [0x00; 0x4000], 
             ram_bank: 0x00,
             oam: [0x00; 0xA0],
             prio: [(true, 0); SCREEN_W],
             dots: 0,
+            color_correction: true,
+            brightness: 1.0,
+            contrast: 1.0,
+            frames: 0,
+            event: None,
+        }
+    }
+
+    /// Toggles the CGB LCD color correction, disabling it outputs the raw saturated colors
+    pub fn set_color_correction(&mut self, enabled: bool) {
+        self.color_correction = enabled;
+    }
+
+    /// Scales every output channel, clamped to 0.0..=2.0, 1.0 leaves colors untouched
+    pub fn set_brightness(&mut self, brightness: f32) {
+        self.brightness = brightness.clamp(0.0, 2.0);
+    }
+
+    /// Spreads channels away from mid gray, clamped to 0.0..=2.0, 1.0 leaves colors untouched
+    pub fn set_contrast(&mut self, contrast: f32) {
+        self.contrast = contrast.clamp(0.0, 2.0);
+    }
+
+    /// Final brightness and contrast pass over a finished 8-bit channel
+    fn adjust(&self, c: u8) -> u8 {
+        if self.brightness == 1.0 && self.contrast == 1.0 {
+            return c;
         }
+        let v = ((f32::from(c) - 127.5) * self.contrast + 127.5) * self.brightness;
+        v.round().clamp(0.0, 255.0) as u8
+    }
+
+    /// Returns the current STAT mode (0 HBlank, 1 VBlank, 2 OAM search, 3 pixel transfer)
+    pub fn mode(&self) -> u8 {
+        self.stat.mode
+    }
+
+    /// Scanline the PPU is on, what LY (0xFF44) reads
+    pub fn current_line(&self) -> u8 {
+        self.ly
+    }
+
+    /// Jumps the PPU to the start of a scanline without running any cycles, for raster tests
+    /// * The LYC check runs as if LY had moved there, mode interrupts don't fire
+    #[cfg(feature = "testing")]
+    pub fn set_line(&mut self, ly: u8) {
+        assert!(ly < 154, "No scanline {}", ly);
+        self.ly = ly;
+        self.dots = 0;
+        self.stat.mode = if ly >= 144 { 1 } else { 2 };
+        self.update_coincidence();
+    }
+
+    /// Number of times the PPU entered VBlank, so two instances can compare where they are
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Last mode transition since the previous call
+    pub fn take_event(&mut self) -> Option<PpuEvent> {
+        self.event.take()
     }
 
     fn get_ram0(&self, a: u16) -> u8 {
@@ -254,20 +446,128 @@ impl Gpu {
     }
     
     fn set_gre(&mut self, x: usize, g: u8) {
+        let g = self.adjust(g);
         self.data[self.ly as usize][x] = [g, g, g];
     }
 
+    /// Converts a 5-bit CGB color into 8-bit sRGB using the common CGB LCD color correction
+    /// * R = (13r + 2g + b) / 2
+    /// * G = (3g + b) * 2
+    /// * B = (3r + 2g + 11b) / 2
+    /// * Full white peaks at 248 on every channel, so the results always fit a byte
+    /// * With color correction disabled each channel is just scaled from 5 to 8 bits
+    /// * Brightness and contrast are applied last
     fn set_rgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
         assert!(r <= 0x1F);
         assert!(g <= 0x1F);
         assert!(b <= 0x1F);
+        if !self.color_correction {
+            self.data[self.ly as usize][x] = [self.adjust(r << 3 | r >> 2), self.adjust(g << 3 | g >> 2), self.adjust(b << 3 | b >> 2)];
+            return;
+        }
         let r = u32::from(r);
         let g = u32::from(g);
         let b = u32::from(b);
         let lr = ((r * 13 + g * 2 + b) >> 1) as u8;
         let lg = ((g * 3 + b) << 1) as u8;
         let lb = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
-        self.data[self.ly as usize][x] = [lr, lg, lb];
+        self.data[self.ly as usize][x] = [self.adjust(lr), self.adjust(lg), self.adjust(lb)];
+    }
+
+    /// Writes the 16 bytes of a tile into the selected VRAM bank, index 0-383 counts from 0x8000
+    pub fn set_tile(&mut self, index: usize, data: &[u8; 16]) {
+        assert!(index < 384);
+        let base = 0x8000 + index as u16 * 16;
+        for (i, b) in data.iter().enumerate() {
+            self.set(base + i as u16, *b);
+        }
+    }
+
+    /// Writes the tile number at x, y of a 32x32 tilemap, map 0 is 0x9800 and map 1 is 0x9C00
+    pub fn set_tilemap_entry(&mut self, map: u8, x: u8, y: u8, tile: u8) {
+        assert!(map < 2 && x < 32 && y < 32);
+        let base = if map == 0 { 0x9800 } else { 0x9C00 };
+        self.set(base + u16::from(y) * 32 + u16::from(x), tile);
+    }
+
+    /// Decodes the 40 OAM entries in OAM order
+    pub fn sprites(&self) -> impl Iterator<Item = SpriteEntry> + '_ {
+        self.oam.chunks_exact(4).enumerate().map(|(index, e)| SpriteEntry {
+            index,
+            y: e[0],
+            x: e[1],
+            tile: e[2],
+            attr: Attr::from(e[3]),
+        })
+    }
+
+    /// Reads the current PPU registers without side effects
+    pub fn ppu_status(&self) -> PpuStatus {
+        let show_window = self.lcdc.bit5() && self.wy <= self.ly;
+        PpuStatus {
+            lcdc: self.lcdc.data,
+            stat_mode: self.stat.mode,
+            ly: self.ly,
+            lyc: self.lc,
+            scx: self.sx,
+            scy: self.sy,
+            wx: self.wx,
+            wy: self.wy,
+            window_line: if show_window { Some(self.ly - self.wy) } else { None },
+        }
+    }
+
+    /// Writes the current frame into out using the requested pixel format
+    /// * out must be exactly `SCREEN_W * SCREEN_H * fmt.bytes_per_pixel()` bytes long
+    pub fn write_framebuffer(&self, out: &mut [u8], fmt: PixelFormat) {
+        let bpp = fmt.bytes_per_pixel();
+        assert_eq!(out.len(), SCREEN_W * SCREEN_H * bpp);
+        let pixels = self.data.iter().flat_map(|l| l.iter());
+        for (chunk, [r, g, b]) in out.chunks_exact_mut(bpp).zip(pixels) {
+            match fmt {
+                PixelFormat::Rgb888 => chunk.copy_from_slice(&[*r, *g, *b]),
+                PixelFormat::Rgba8888 => chunk.copy_from_slice(&[*r, *g, *b, 0xFF]),
+                PixelFormat::Argb8888 => {
+                    let v = 0xFF00_0000 | (u32::from(*r) << 16) | (u32::from(*g) << 8) | u32::from(*b);
+                    chunk.copy_from_slice(&v.to_ne_bytes());
+                }
+                PixelFormat::Rgb565 => {
+                    let v = (u16::from(*r >> 3) << 11) | (u16::from(*g >> 2) << 5) | u16::from(*b >> 3);
+                    chunk.copy_from_slice(&v.to_ne_bytes());
+                }
+            }
+        }
+    }
+
+    /// Forces color entry 0-3 of CGB background palette 0-7, channels are 5 bit and masked to it
+    /// * The entry is kept from game writes while palette_override is on
+    pub fn set_bg_palette(&mut self, index: usize, entry: usize, color: [u8; 3]) {
+        self.cbgpd[index][entry] = color.map(|c| c & 0x1F);
+        self.forced_bg[index] |= 1 << entry;
+    }
+
+    /// Forces color entry 0-3 of CGB object palette 0-7, like set_bg_palette
+    pub fn set_obj_palette(&mut self, index: usize, entry: usize, color: [u8; 3]) {
+        self.cobpd[index][entry] = color.map(|c| c & 0x1F);
+        self.forced_obj[index] |= 1 << entry;
+    }
+
+    /// Drops game writes to forced palette entries while on, turning it off forgets which were forced
+    pub fn set_palette_override(&mut self, enabled: bool) {
+        self.palette_override = enabled;
+        if !enabled {
+            self.forced_bg = [0x00; 8];
+            self.forced_obj = [0x00; 8];
+        }
+    }
+
+    /// Refreshes the LY == LYC flag, the STAT interrupt only fires when it turns on
+    fn update_coincidence(&mut self) {
+        let coincidence = self.ly == self.lc;
+        if coincidence && !self.coincidence && self.stat.ly_interrupt {
+            self.intf.borrow_mut().hi(Flags::LCDStat);
+        }
+        self.coincidence = coincidence;
     }
 
     pub fn next(&mut self, cycles: u32) {
@@ -290,9 +590,7 @@ impl Gpu {
             self.dots %= 456;
             if d != self.dots {
                 self.ly = (self.ly + 1) % 154;
-                if self.stat.ly_interrupt && self.ly == self.lc {
-                    self.intf.borrow_mut().hi(Flags::LCDStat);
-                }
+                self.update_coincidence();
             }
             if self.ly >= 144 {
                 if self.stat.mode == 1 {
@@ -300,6 +598,8 @@ impl Gpu {
                 }
                 self.stat.mode = 1;
                 self.v_blank = true;
+                self.frames += 1;
+                self.event = Some(PpuEvent::VBlank(self.frames));
                 self.intf.borrow_mut().hi(Flags::Vblank);
                 if self.stat.m1_interrupt {
                     self.intf.borrow_mut().hi(Flags::LCDStat);
@@ -320,20 +620,37 @@ impl Gpu {
                 }
                 self.stat.mode = 0;
                 self.h_blank = true;
+                if !matches!(self.event, Some(PpuEvent::VBlank(_))) {
+                    self.event = Some(PpuEvent::HBlank);
+                }
                 if self.stat.m0_interrupt {
                     self.intf.borrow_mut().hi(Flags::LCDStat);
                 }
-                // Render scanline
-                if self.term == Term::GBC || self.lcdc.bit0() {
-                    self.draw_bg();
-                }
-                if self.lcdc.bit1() {
-                    self.draw_sprites();
-                }
+                self.draw_line();
             }
         }
     }
 
+    /// Renders line ly from the current registers
+    fn draw_line(&mut self) {
+        if self.term == Term::GBC || self.lcdc.bit0() {
+            self.draw_bg();
+        }
+        if self.lcdc.bit1() {
+            self.draw_sprites();
+        }
+    }
+
+    /// Renders one line into data from the current registers, outside the mode state machine
+    /// * LY is only borrowed for the draw, set up SCX and friends between calls for raster effects
+    pub fn render_scanline(&mut self, line: u8) {
+        assert!((line as usize) < SCREEN_H);
+        let ly = self.ly;
+        self.ly = line;
+        self.draw_line();
+        self.ly = ly;
+    }
+
     fn draw_bg(&mut self) {
         let show_window = self.lcdc.bit5() && self.wy <= self.ly;
         let tile_base = if self.lcdc.bit4() { 0x8000 } else { 0x8800 };
@@ -422,6 +739,8 @@ impl Gpu {
                 continue;
             }
 
+            // Tall sprites use the even tile for rows 0-7 and the odd one for rows 8-15. Both come out of the row
+            // offset, so y-flip mirrors across all 16 rows and swaps the two tiles
             let tile_y = if tile_attr.yflip { sprite_size - 1 - self.ly.wrapping_sub(py) } else { self.ly.wrapping_sub(py) };
             let tile_y_addr = 0x8000u16 + u16::from(tile_number) * 16 + u16::from(tile_y) * 2;
             let tile_y_data: [u8; 2] = if self.term == Term::GBC && tile_attr.bank {
@@ -488,7 +807,7 @@ impl Memory for Gpu {
                 let bit5 = if self.stat.m2_interrupt { 0x20 } else { 0x00 };
                 let bit4 = if self.stat.m1_interrupt { 0x10 } else { 0x00 };
                 let bit3 = if self.stat.m0_interrupt { 0x08 } else { 0x00 };
-                let bit2 = if self.ly == self.lc { 0x04 } else { 0x00 };
+                let bit2 = if self.coincidence { 0x04 } else { 0x00 };
                 bit6 | bit5 | bit4 | bit3 | bit2 | self.stat.mode
             }
             0xFF42 => self.sy,
@@ -500,7 +819,9 @@ impl Memory for Gpu {
             0xFF49 => self.op1,
             0xFF4A => self.wy,
             0xFF4B => self.wx,
-            0xFF4F => 0xFE | self.ram_bank as u8,
+            // VBK, DMG has a single VRAM bank and no register behind it
+            0xFF4F if self.term == Term::GBC => 0xFE | self.ram_bank as u8,
+            0xFF4F => 0xFF,
             0xFF68 => self.cbgpi.get(),
             0xFF69 => {
                 let r = self.cbgpi.i as usize >> 3;
@@ -537,14 +858,28 @@ impl Memory for Gpu {
         match a {
             0x8000..=0x9FFF => self.ram[self.ram_bank * 0x2000 + a as usize - 0x8000] = v,
             0xFE00..=0xFE9F => self.oam[a as usize - 0xFE00] = v,
+            // Switching off parks the PPU on line 0 in mode 0 with a blank screen, without raising interrupts
+            // * v_blank is set so the frontend shows the blank screen, IF isn't touched
+            // * Switching on starts line 0 in mode 2, its mode 2 interrupt isn't raised like on hardware
             0xFF40 => {
+                let was_on = self.lcdc.bit7();
                 self.lcdc.data = v;
-                if !self.lcdc.bit7() {
-                    self.dots = 0;
-                    self.ly = 0;
-                    self.stat.mode = 0;
-                    self.data = [[[0xffu8; 3]; SCREEN_W]; SCREEN_H];
-                    self.v_blank = true;
+                match (was_on, self.lcdc.bit7()) {
+                    (true, false) => {
+                        self.dots = 0;
+                        self.ly = 0;
+                        self.coincidence = self.ly == self.lc;
+                        self.stat.mode = 0;
+                        self.data = [[[0xffu8; 3]; SCREEN_W]; SCREEN_H];
+                        self.v_blank = true;
+                    }
+                    (false, true) => {
+                        self.dots = 0;
+                        self.ly = 0;
+                        self.update_coincidence();
+                        self.stat.mode = 2;
+                    }
+                    _ => {}
                 }
             }
             0xFF41 => {
@@ -556,18 +891,24 @@ impl Memory for Gpu {
             0xFF42 => self.sy = v,
             0xFF43 => self.sx = v,
             0xFF44 => {}
-            0xFF45 => self.lc = v,
+            0xFF45 => {
+                self.lc = v;
+                self.update_coincidence();
+            }
             0xFF47 => self.bgp = v,
             0xFF48 => self.op0 = v,
             0xFF49 => self.op1 = v,
             0xFF4A => self.wy = v,
             0xFF4B => self.wx = v,
-            0xFF4F => self.ram_bank = (v & 0x01) as usize,
+            0xFF4F if self.term == Term::GBC => self.ram_bank = (v & 0x01) as usize,
+            0xFF4F => {}
             0xFF68 => self.cbgpi.set(v),
             0xFF69 => {
                 let r = self.cbgpi.i as usize >> 3;
                 let c = self.cbgpi.i as usize >> 1 & 0x03;
-                if self.cbgpi.i & 0x01 == 0x00 {
+                if self.palette_override && self.forced_bg[r] & (1 << c) != 0x00 {
+                    // Forced color, the game's write is dropped
+                } else if self.cbgpi.i & 0x01 == 0x00 {
                     self.cbgpd[r][c][0] = v & 0x1F;
                     self.cbgpd[r][c][1] = (self.cbgpd[r][c][1] & 0x18) | (v >> 5);
                 } else {
@@ -583,7 +924,9 @@ impl Memory for Gpu {
             0xFF6B => {
                 let r = self.cobpi.i as usize >> 3;
                 let c = self.cobpi.i as usize >> 1 & 0x03;
-                if self.cobpi.i & 0x01 == 0x00 {
+                if self.palette_override && self.forced_obj[r] & (1 << c) != 0x00 {
+                    // Forced color, the game's write is dropped
+                } else if self.cobpi.i & 0x01 == 0x00 {
                     self.cobpd[r][c][0] = v & 0x1F;
                     self.cobpd[r][c][1] = (self.cobpd[r][c][1] & 0x18) | (v >> 5);
                 } else {
@@ -598,4 +941,323 @@ impl Memory for Gpu {
             _ => panic!(""),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpu(term: Term) -> Gpu {
+        Gpu::power_up(term, Rc::new(RefCell::new(Intf::power_up())))
+    }
+
+    #[test]
+    fn write_framebuffer_packs_each_format() {
+        let mut gpu = gpu(Term::GB);
+        gpu.data[0][0] = [0xF8, 0x84, 0x08];
+        let first = |fmt: PixelFormat| {
+            let mut out = vec![0x00; SCREEN_W * SCREEN_H * fmt.bytes_per_pixel()];
+            gpu.write_framebuffer(&mut out, fmt);
+            out[..fmt.bytes_per_pixel()].to_vec()
+        };
+        assert_eq!(first(PixelFormat::Rgb888), [0xF8, 0x84, 0x08]);
+        assert_eq!(first(PixelFormat::Rgba8888), [0xF8, 0x84, 0x08, 0xFF]);
+        assert_eq!(first(PixelFormat::Argb8888), 0xFFF8_8408u32.to_ne_bytes());
+        assert_eq!(first(PixelFormat::Rgb565), 0b1111_1100_0010_0001u16.to_ne_bytes());
+    }
+
+    #[test]
+    fn color_correction_keeps_white_near_white() {
+        let mut gpu = gpu(Term::GBC);
+        gpu.set_rgb(0, 0x1F, 0x1F, 0x1F);
+        assert_eq!(gpu.data[0][0], [0xF8, 0xF8, 0xF8]);
+    }
+
+    #[test]
+    fn color_correction_changes_mid_range_colors() {
+        let mut gpu = gpu(Term::GBC);
+        gpu.set_rgb(0, 0x10, 0x08, 0x04);
+        assert_eq!(gpu.data[0][0], [114, 56, 54]);
+        gpu.set_color_correction(false);
+        gpu.set_rgb(0, 0x10, 0x08, 0x04);
+        assert_eq!(gpu.data[0][0], [132, 66, 33]);
+    }
+
+    #[test]
+    fn ppu_status_reflects_register_writes() {
+        let mut gpu = gpu(Term::GB);
+        for (a, v) in [(0xFF40, 0xA3), (0xFF42, 0x12), (0xFF43, 0x34), (0xFF45, 0x56), (0xFF4A, 0x00), (0xFF4B, 0x07)] {
+            gpu.set(a, v);
+        }
+        let status = gpu.ppu_status();
+        assert_eq!(status, PpuStatus { lcdc: 0xA3, stat_mode: 2, ly: 0, lyc: 0x56, scx: 0x34, scy: 0x12, wx: 0x07, wy: 0x00, window_line: Some(0) });
+        gpu.set(0xFF4A, 0x40);
+        assert_eq!(gpu.ppu_status().window_line, None);
+    }
+
+    #[test]
+    fn sprites_decode_oam_entries() {
+        let mut gpu = gpu(Term::GBC);
+        for (i, v) in [0x10, 0x08, 0x42, 0xF5].into_iter().enumerate() {
+            gpu.set(0xFE04 + i as u16, v);
+        }
+        let sprites: Vec<SpriteEntry> = gpu.sprites().collect();
+        assert_eq!(sprites.len(), 40);
+        let s = sprites[1];
+        assert_eq!((s.index, s.y, s.x, s.tile), (1, 0x10, 0x08, 0x42));
+        assert!(s.attr.priority && s.attr.yflip && s.attr.xflip && !s.attr.bank);
+        assert_eq!((s.attr.palette_num_0, s.attr.palette_num_1), (1, 5));
+        assert!(s.visible());
+        assert!(!sprites[0].visible());
+    }
+
+    #[test]
+    fn frame_count_goes_up_once_per_frame() {
+        let mut gpu = gpu(Term::GB);
+        gpu.set(0xFF40, 0x91);
+        for frame in 1..=3 {
+            for _ in 0..FRAME_CYCLES / 4 {
+                gpu.next(4);
+            }
+            assert_eq!(gpu.frame_count(), frame);
+        }
+    }
+
+    #[test]
+    fn brightness_and_contrast_scale_and_clamp() {
+        let mut gpu = gpu(Term::GB);
+        assert_eq!(gpu.adjust(200), 200);
+        gpu.set_brightness(0.5);
+        assert_eq!([gpu.adjust(0), gpu.adjust(200), gpu.adjust(255)], [0, 100, 128]);
+        gpu.set_brightness(2.0);
+        assert_eq!([gpu.adjust(100), gpu.adjust(200)], [200, 255]);
+        gpu.set_brightness(1.0);
+        // Half contrast pulls both ends halfway to mid gray
+        gpu.set_contrast(0.5);
+        assert_eq!([gpu.adjust(0), gpu.adjust(255)], [64, 191]);
+        gpu.set_contrast(2.0);
+        assert_eq!([gpu.adjust(32), gpu.adjust(224)], [0, 255]);
+    }
+
+    #[test]
+    fn render_scanline_follows_scx() {
+        let mut gpu = gpu(Term::GB);
+        gpu.set(0xFF40, 0x91);
+        gpu.set(0xFF47, 0xE4);
+        gpu.set_tile(1, &[0xFF; 16]);
+        gpu.set_tilemap_entry(0, 1, 0, 1);
+        let mut dark = |scx: u8| {
+            gpu.set(0xFF43, scx);
+            gpu.render_scanline(4);
+            let black = gpu.data[4].iter().map(|&p| p != gpu.data[4][SCREEN_W - 1]).collect::<Vec<bool>>();
+            (black.iter().position(|&b| b), black.iter().rposition(|&b| b))
+        };
+        // Tile 1 sits at x 8-15 of the map, SCX moves it left
+        assert_eq!(dark(0), (Some(8), Some(15)));
+        assert_eq!(dark(3), (Some(5), Some(12)));
+        assert_eq!(dark(8), (Some(0), Some(7)));
+        assert_eq!(dark(16), (None, None));
+        // LY is only borrowed for the draw
+        assert_eq!(gpu.ly, 0);
+    }
+
+    #[test]
+    fn y_flipped_tall_sprite_swaps_its_tiles() {
+        let mut gpu = gpu(Term::GB);
+        gpu.set(0xFF40, 0x97);
+        gpu.set(0xFF47, 0xE4);
+        gpu.set(0xFF48, 0xE4);
+        // Tile 2 is solid color 1 and tile 3 solid color 3
+        gpu.set_tile(2, &[0xFF, 0x00].repeat(8).try_into().unwrap());
+        gpu.set_tile(3, &[0xFF; 16]);
+        let mut rows = |attr: u8| {
+            for (i, v) in [16, 8, 2, attr].into_iter().enumerate() {
+                gpu.set(0xFE00 + i as u16, v);
+            }
+            gpu.render_scanline(0);
+            gpu.render_scanline(15);
+            (gpu.data[0][0], gpu.data[15][0])
+        };
+        let (top, bottom) = rows(0x00);
+        assert_ne!(top, bottom);
+        assert_eq!(rows(0x40), (bottom, top));
+    }
+
+    #[test]
+    fn border_composite_centers_the_screen() {
+        // The SGB border size, red all over
+        let (width, height) = (256, 224);
+        let border = Border::power_up(&[0xFF, 0x00, 0x00, 0xFF].repeat(width * height), width, height);
+        assert_eq!(border.screen_offset(), (48, 40));
+        let screen: Vec<u32> = (0..SCREEN_W * SCREEN_H).map(|i| i as u32).collect();
+        let mut out = vec![0; width * height];
+        border.composite(&screen, &mut out);
+        assert_eq!(out[0], 0xFFFF_0000);
+        assert_eq!(out[40 * width + 47], 0xFFFF_0000);
+        assert_eq!(out[40 * width + 48], 0);
+        assert_eq!(out[(40 + 143) * width + 48 + 159], (SCREEN_W * SCREEN_H - 1) as u32);
+        assert_eq!(out[(40 + 144) * width + 48], 0xFFFF_0000);
+    }
+
+    #[test]
+    fn attribute_bit_4_picks_obp1() {
+        assert_eq!(Attr::from(0x10).palette_num_0, 1);
+        assert_eq!(Attr::from(0x00).palette_num_0, 0);
+        let mut gpu = gpu(Term::GB);
+        gpu.set(0xFF40, 0x93);
+        gpu.set(0xFF47, 0xE4);
+        gpu.set_tile(1, &[0xFF, 0x00].repeat(8).try_into().unwrap());
+        let mut pixel = |obp0: u8, obp1: u8, attr: u8| {
+            gpu.set(0xFF48, obp0);
+            gpu.set(0xFF49, obp1);
+            for (i, v) in [16, 8, 1, attr].into_iter().enumerate() {
+                gpu.set(0xFE00 + i as u16, v);
+            }
+            gpu.render_scanline(0);
+            gpu.data[0][0]
+        };
+        // Color 1 is light gray through 0xE4 and dark gray through 0x1B
+        let light = pixel(0xE4, 0x1B, 0x00);
+        let dark = pixel(0x1B, 0xE4, 0x00);
+        assert_ne!(light, dark);
+        assert_eq!(pixel(0xE4, 0x1B, 0x10), dark);
+        assert_eq!(pixel(0x1B, 0xE4, 0x10), light);
+    }
+
+    #[test]
+    fn vbk_switches_banks_on_cgb_only() {
+        // Writes 0x11 to bank 1 and 0x22 to bank 0, then reads 0x8000 back with bank 1 selected
+        let bank_1 = |term: Term| {
+            let mut gpu = gpu(term);
+            gpu.set(0xFF4F, 0x01);
+            gpu.set(0x8000, 0x11);
+            gpu.set(0xFF4F, 0x00);
+            gpu.set(0x8000, 0x22);
+            gpu.set(0xFF4F, 0x01);
+            (gpu.get(0xFF4F), gpu.get(0x8000))
+        };
+        assert_eq!(bank_1(Term::GB), (0xFF, 0x22));
+        assert_eq!(bank_1(Term::GBC), (0xFF, 0x11));
+    }
+
+    #[test]
+    fn set_tile_and_set_tilemap_entry_render() {
+        let mut gpu = gpu(Term::GB);
+        gpu.set(0xFF47, 0xE4);
+        gpu.set_tile(5, &[0xFF; 16]);
+        gpu.set_tilemap_entry(0, 2, 1, 5);
+        gpu.set_tilemap_entry(1, 3, 1, 5);
+        // Dark pixels of line 8, the first line of tilemap row 1
+        let mut dark = |lcdc: u8| {
+            gpu.set(0xFF40, lcdc);
+            gpu.render_scanline(8);
+            let white = gpu.data[8][0];
+            gpu.data[8].iter().enumerate().filter(|(_, &p)| p != white).map(|(x, _)| x).collect::<Vec<usize>>()
+        };
+        assert_eq!(dark(0x91), (16..24).collect::<Vec<usize>>());
+        // LCDC bit 3 moves the background to map 1
+        assert_eq!(dark(0x99), (24..32).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn lyc_interrupt_fires_once_per_frame() {
+        let mut gpu = gpu(Term::GB);
+        gpu.set(0xFF40, 0x91);
+        gpu.set(0xFF41, 0x40);
+        gpu.set(0xFF45, 100);
+        let mut hits = 0;
+        for _ in 0..3 * FRAME_CYCLES / 4 {
+            gpu.next(4);
+            if gpu.ly == 100 {
+                // Writing the same LYC while it matches is no new edge
+                gpu.set(0xFF45, 100);
+            }
+            let mut intf = gpu.intf.borrow_mut();
+            if intf.data & 0x02 != 0x00 {
+                hits += 1;
+                intf.data &= !0x02;
+            }
+        }
+        assert_eq!(hits, 3);
+    }
+
+    #[test]
+    fn lcd_off_then_on_restarts_at_line_0_quietly() {
+        let mut gpu = gpu(Term::GB);
+        gpu.set(0xFF40, 0x91);
+        // Every STAT source on, LYC matches line 0
+        gpu.set(0xFF41, 0x78);
+        gpu.set(0xFF45, 0x00);
+        while gpu.ly != 50 {
+            gpu.next(4);
+        }
+        gpu.intf.borrow_mut().data = 0x00;
+
+        gpu.set(0xFF40, 0x11);
+        assert_eq!((gpu.get(0xFF44), gpu.get(0xFF41) & 0x07), (0, 0x04));
+        // Staying off is a no-op
+        gpu.next(456);
+        gpu.set(0xFF40, 0x11);
+        assert_eq!(gpu.get(0xFF44), 0);
+        gpu.set(0xFF40, 0x91);
+        assert_eq!((gpu.get(0xFF44), gpu.get(0xFF41) & 0x07), (0, 0x06));
+        assert_eq!(gpu.intf.borrow().data, 0x00);
+
+        // Then lines count up from 0 again
+        gpu.next(456);
+        assert_eq!(gpu.get(0xFF44), 1);
+    }
+
+    #[test]
+    fn palette_override_keeps_forced_colors_on_screen() {
+        let mut gpu = gpu(Term::GBC);
+        gpu.set_color_correction(false);
+        gpu.set(0xFF40, 0x91);
+        // Tile 0 is all color 0, tile 1 all color 1
+        gpu.set_tile(1, &[0xFF, 0x00].repeat(8).try_into().unwrap());
+        gpu.set_tilemap_entry(0, 1, 0, 1);
+        let game_writes_white = |gpu: &mut Gpu| {
+            gpu.set(0xFF68, 0x80);
+            for _ in 0..2 {
+                gpu.set(0xFF69, 0xFF);
+                gpu.set(0xFF69, 0x7F);
+            }
+        };
+
+        gpu.set_palette_override(true);
+        gpu.set_bg_palette(0, 0, [0x1F, 0x00, 0x00]);
+        game_writes_white(&mut gpu);
+        gpu.render_scanline(0);
+        assert_eq!(gpu.data[0][0], [0xFF, 0x00, 0x00], "forced entry 0 kept");
+        assert_eq!(gpu.data[0][8], [0xFF, 0xFF, 0xFF], "entry 1 took the game's write");
+
+        // Object palettes are guarded the same way
+        gpu.set_obj_palette(0, 1, [0x00, 0x1F, 0x00]);
+        gpu.set(0xFF6A, 0x82);
+        gpu.set(0xFF6B, 0xFF);
+        gpu.set(0xFF6B, 0x7F);
+        assert_eq!(gpu.cobpd[0][1], [0x00, 0x1F, 0x00]);
+
+        gpu.set_palette_override(false);
+        game_writes_white(&mut gpu);
+        gpu.render_scanline(0);
+        assert_eq!(gpu.data[0][0], [0xFF, 0xFF, 0xFF]);
+        gpu.set(0xFF6A, 0x82);
+        gpu.set(0xFF6B, 0xFF);
+        gpu.set(0xFF6B, 0x7F);
+        assert_eq!(gpu.cobpd[0][1], [0x1F, 0x1F, 0x1F]);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn set_line_moves_ly() {
+        let mut gpu = gpu(Term::GB);
+        gpu.set(0xFF40, 0x91);
+        gpu.set_line(90);
+        assert_eq!(gpu.get(0xFF44), 90);
+        assert_eq!(gpu.get(0xFF41) & 0x03, 2);
+        gpu.set_line(150);
+        assert_eq!(gpu.get(0xFF44), 150);
+        assert_eq!(gpu.get(0xFF41) & 0x03, 1);
+    }
+}
@@ -20,4 +20,33 @@ impl Intf {
     pub fn hi(&mut self, flag: Flags) {
         self.data |= 1 << flag as u8;
     }
-}
\ No newline at end of file
+
+    /// Returns the requested interrupts, only the lower 5 bits are meaningful
+    pub fn pending(&self) -> u8 {
+        self.data & 0x1F
+    }
+
+    pub fn clear(&mut self, flag: Flags) {
+        self.data &= !(1 << flag as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_flag_raises_and_clears_its_own_bit() {
+        let flags = [Flags::Vblank, Flags::LCDStat, Flags::Timer, Flags::Serial, Flags::Joypad];
+        for (bit, flag) in flags.iter().enumerate() {
+            let mut intf = Intf::power_up();
+            intf.hi(flag.clone());
+            assert_eq!(intf.pending(), 1 << bit);
+            intf.clear(flag.clone());
+            assert_eq!(intf.pending(), 0x00);
+        }
+        let mut intf = Intf::power_up();
+        intf.data = 0xE0;
+        assert_eq!(intf.pending(), 0x00);
+    }
+}
@@ -1,58 +1,338 @@
 use super::intf::{Flags, Intf};
 use super::mem::Memory;
+use super::terms::Term;
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Rc;
 
-#[rustfmt::skip]
-#[derive(Clone)]
+/// A Game Boy button, the order and names are stable for configs and input movies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Key {
-    Right = 0b0000_0001,
-    Left = 0b0000_0010,
-    Up = 0b0000_0100,
-    Down = 0b0000_1000,
-    A = 0b0001_0000,
-    B = 0b0010_0000,
-    Select = 0b0100_0000,
-    Start = 0b1000_0000,
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Key {
+    /// Every button in declaration order
+    pub fn all() -> [Key; 8] {
+        [Key::Right, Key::Left, Key::Up, Key::Down, Key::A, Key::B, Key::Select, Key::Start]
+    }
+
+    /// Lowercase name used in configs and movies
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::Right => "right",
+            Key::Left => "left",
+            Key::Up => "up",
+            Key::Down => "down",
+            Key::A => "a",
+            Key::B => "b",
+            Key::Select => "select",
+            Key::Start => "start",
+        }
+    }
+
+    /// Looks a button up by name, case insensitive
+    pub fn from_name(name: &str) -> Option<Key> {
+        Key::all().into_iter().find(|k| k.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Bit of the button in the joypad matrix, the D-pad is the low nibble
+    pub fn to_bit(self) -> u8 {
+        match self {
+            Key::Right => 0b0000_0001,
+            Key::Left => 0b0000_0010,
+            Key::Up => 0b0000_0100,
+            Key::Down => 0b0000_1000,
+            Key::A => 0b0001_0000,
+            Key::B => 0b0010_0000,
+            Key::Select => 0b0100_0000,
+            Key::Start => 0b1000_0000,
+        }
+    }
+}
+
+/// How an analog stick drives the D-pad
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StickConfig {
+    /// Axis values within this distance of the center are ignored so stick drift doesn't press anything
+    pub deadzone: i16,
+}
+
+impl Default for StickConfig {
+    fn default() -> Self {
+        Self { deadzone: 8000 }
+    }
+}
+
+impl StickConfig {
+    /// D-pad keys held for a stick position, y grows downward like SDL axes
+    /// * Returns the horizontal and the vertical direction, None inside the dead zone
+    pub fn directions(&self, x: i16, y: i16) -> (Option<Key>, Option<Key>) {
+        let deadzone = self.deadzone.max(0);
+        let h = match x {
+            x if x > deadzone => Some(Key::Right),
+            x if x < -deadzone => Some(Key::Left),
+            _ => None,
+        };
+        let v = match y {
+            y if y > deadzone => Some(Key::Down),
+            y if y < -deadzone => Some(Key::Up),
+            _ => None,
+        };
+        (h, v)
+    }
+}
+
+/// SGB commands that are captured, the rest are dropped for now
+const SGB_PAL01: u8 = 0x00;
+const SGB_PAL_SET: u8 = 0x0A;
+
+/// Decodes SGB command packets pulsed through P14/P15
+/// * A packet starts with both lines low, then every bit is one line going low followed by both high
+/// * P14 low sends a 0, P15 low sends a 1, 128 bits make the 16 byte packet, LSB first
+struct SgbReceiver {
+    packet: [u8; 16],
+    bits: usize,
+    receiving: bool,
+    lines: u8,
+}
+
+impl SgbReceiver {
+    fn power_up() -> Self {
+        Self { packet: [0x00; 16], bits: 0, receiving: false, lines: 0x30 }
+    }
+
+    /// Feeds the P14/P15 lines of a write to 0xFF00
+    /// * Returns the packet once all 128 bits have arrived
+    fn pulse(&mut self, lines: u8) -> Option<[u8; 16]> {
+        let last = self.lines;
+        self.lines = lines;
+        if last != 0x30 {
+            return None;
+        }
+        match lines {
+            0x00 => {
+                self.packet = [0x00; 16];
+                self.bits = 0;
+                self.receiving = true;
+                None
+            }
+            0x10 | 0x20 if self.receiving => {
+                if lines == 0x10 {
+                    self.packet[self.bits / 8] |= 1 << (self.bits % 8);
+                }
+                self.bits += 1;
+                if self.bits < 128 {
+                    return None;
+                }
+                self.receiving = false;
+                Some(self.packet)
+            }
+            _ => None,
+        }
+    }
 }
 
 pub struct Joypad {
     intf: Rc<RefCell<Intf>>,
     matrix: u8,
     select: u8,
+    sgb: Option<SgbReceiver>,
+    sgb_packets: VecDeque<[u8; 16]>,
+    // D-pad keys currently held by the stick, so centering it doesn't release keyboard presses
+    stick: u8,
 }
 
 impl Joypad {
-    pub fn power_up(intf: Rc<RefCell<Intf>>) -> Self {
-      Self { intf, matrix: 0xFF, select: 0x00 }  
+    pub fn power_up(term: Term, intf: Rc<RefCell<Intf>>) -> Self {
+        let sgb = if term == Term::SGB { Some(SgbReceiver::power_up()) } else { None };
+        Self { intf, matrix: 0xFF, select: 0x00, sgb, sgb_packets: VecDeque::new(), stick: 0x00 }
+    }
+
+    /// Pops the oldest captured SGB command packet
+    /// * Only PAL01 and PAL_SET are captured so far
+    pub fn take_sgb_packet(&mut self) -> Option<[u8; 16]> {
+        self.sgb_packets.pop_front()
     }
 }
 
 impl Joypad {
+    /// Whether a button is held, whichever group the game has selected
+    pub fn is_down(&self, key: Key) -> bool {
+        self.matrix & key.to_bit() == 0x00
+    }
+
     pub fn keyup(&mut self, key: Key) {
-        self.matrix |= key as u8;
+        self.matrix |= key.to_bit();
+    }
+
+    /// Moves the D-pad to follow a stick position, keys only change when the stick crosses the dead zone
+    pub fn set_stick(&mut self, config: &StickConfig, x: i16, y: i16) {
+        let (h, v) = config.directions(x, y);
+        for key in [Key::Right, Key::Left, Key::Down, Key::Up] {
+            let mask = key.to_bit();
+            let held = h == Some(key) || v == Some(key);
+            let by_stick = self.stick & mask != 0x00;
+            if held && !by_stick {
+                self.stick |= mask;
+                self.keydown(key);
+            } else if !held && by_stick {
+                self.stick &= !mask;
+                self.keyup(key);
+            }
+        }
     }
 
     pub fn keydown(&mut self, key: Key) {
-       self.matrix &= !(key as u8);
-       self.intf.borrow_mut().hi(Flags::Joypad); 
+        let lines = self.lines();
+        self.matrix &= !key.to_bit();
+        self.check_interrupt(lines);
+    }
+
+    /// P10-P13 as the CPU sees them, a pressed key in a selected group pulls its line low
+    fn lines(&self) -> u8 {
+        self.get(0xFF00) & 0x0F
+    }
+
+    /// The joypad interrupt fires when one of P10-P13 goes from high to low
+    /// * Keys in an unselected group don't touch the lines, so they don't interrupt
+    fn check_interrupt(&mut self, before: u8) {
+        if before & !self.lines() != 0x00 {
+            self.intf.borrow_mut().hi(Flags::Joypad);
+        }
     }
 }
 
 impl Memory for Joypad {
+    /// Bits 6-7 are unused and read high, a cleared select bit pulls in its key group
+    /// * Both groups selected gives the AND of them, none selected reads as all released
     fn get(&self, a: u16) -> u8 {
         assert_eq!(a, 0xFF00);
+        let mut keys = 0x0F;
         if (self.select & 0b0001_0000) == 0x00 {
-            return self.select | (self.matrix & 0x0F);
+            keys &= self.matrix & 0x0F;
         }
         if (self.select & 0b0010_0000) == 0x00 {
-            return self.select | (self.matrix >> 4);
+            keys &= self.matrix >> 4;
         }
-        self.select
+        0xC0 | (self.select & 0x30) | keys
     }
 
     fn set(&mut self, a: u16, v: u8) {
         assert_eq!(a, 0xFF00);
+        let lines = self.lines();
         self.select = v;
+        self.check_interrupt(lines);
+        if let Some(packet) = self.sgb.as_mut().and_then(|sgb| sgb.pulse(v & 0x30)) {
+            if matches!(packet[0] >> 3, SGB_PAL01 | SGB_PAL_SET) {
+                self.sgb_packets.push_back(packet);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joypad(term: Term) -> (Joypad, Rc<RefCell<Intf>>) {
+        let intf = Rc::new(RefCell::new(Intf::power_up()));
+        (Joypad::power_up(term, intf.clone()), intf)
+    }
+
+    #[test]
+    fn sgb_pulses_decode_into_one_packet() {
+        let (mut joypad, _) = joypad(Term::SGB);
+        let mut packet = [0x00; 16];
+        packet[0] = SGB_PAL01 << 3 | 0x01;
+        packet[1..5].copy_from_slice(&[0xFF, 0x7F, 0x00, 0x42]);
+        // Reset pulse, then one low pulse per bit, LSB first, each followed by both lines high
+        joypad.set(0xFF00, 0x00);
+        joypad.set(0xFF00, 0x30);
+        for i in 0..128 {
+            let one = packet[i / 8] & (1 << (i % 8)) != 0x00;
+            joypad.set(0xFF00, if one { 0x10 } else { 0x20 });
+            joypad.set(0xFF00, 0x30);
+        }
+        assert_eq!(joypad.take_sgb_packet(), Some(packet));
+        assert_eq!(joypad.take_sgb_packet(), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn get_follows_the_selected_groups() {
+        let (mut joypad, _) = joypad(Term::GB);
+        joypad.keydown(Key::Right);
+        joypad.keydown(Key::A);
+        // (select written, byte read back), a pressed key reads 0
+        for (select, expected) in [(0x20, 0xEE), (0x10, 0xDE), (0x30, 0xFF), (0x00, 0xCE)] {
+            joypad.set(0xFF00, select);
+            assert_eq!(joypad.get(0xFF00), expected, "select 0x{:02x}", select);
+        }
+    }
+
+    #[test]
+    fn stick_ignores_the_dead_zone() {
+        let config = StickConfig::default();
+        assert_eq!(config.directions(8000, -8000), (None, None));
+        assert_eq!(config.directions(8001, -8001), (Some(Key::Right), Some(Key::Up)));
+        assert_eq!(config.directions(-20000, 20000), (Some(Key::Left), Some(Key::Down)));
+        let (mut joypad, _) = joypad(Term::GB);
+        joypad.set(0xFF00, 0x20);
+        joypad.keydown(Key::Left);
+        joypad.set_stick(&config, 30000, 0);
+        assert_eq!(joypad.get(0xFF00) & 0x0F, 0x0C);
+        // Drift inside the dead zone releases the stick's keys but not the keyboard's
+        joypad.set_stick(&config, 100, -100);
+        assert_eq!(joypad.get(0xFF00) & 0x0F, 0x0D);
+    }
+
+    #[test]
+    fn key_names_and_bits_round_trip() {
+        let mut bits = 0x00;
+        for key in Key::all() {
+            assert_eq!(Key::from_name(key.name()), Some(key));
+            assert_eq!(Key::from_name(&key.name().to_uppercase()), Some(key));
+            assert_eq!(key.to_bit().count_ones(), 1);
+            bits |= key.to_bit();
+        }
+        assert_eq!(bits, 0xFF);
+        assert_eq!(Key::from_name("turbo"), None);
+    }
+
+    #[test]
+    fn interrupt_fires_on_falling_selected_lines_only() {
+        let (mut joypad, intf) = joypad(Term::GB);
+        let raised = |intf: &Rc<RefCell<Intf>>| {
+            let hit = intf.borrow().data & 0x10 != 0x00;
+            intf.borrow_mut().data = 0x00;
+            hit
+        };
+        // D-pad selected, a button press doesn't reach the lines
+        joypad.set(0xFF00, 0x20);
+        assert!(!raised(&intf));
+        joypad.keydown(Key::A);
+        assert!(!raised(&intf));
+        joypad.keydown(Key::Right);
+        assert!(raised(&intf));
+        // Already low, pressing it again is no edge
+        joypad.keydown(Key::Right);
+        assert!(!raised(&intf));
+        joypad.keydown(Key::Left);
+        assert!(raised(&intf));
+        // Releasing raises a line, which is no edge either
+        joypad.keyup(Key::Right);
+        assert!(!raised(&intf));
+        // Selecting the buttons lets the held A pull P10 low
+        joypad.set(0xFF00, 0x10);
+        assert!(raised(&intf));
+        joypad.set(0xFF00, 0x30);
+        assert!(!raised(&intf));
+    }
+}
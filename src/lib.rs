@@ -1,6 +1,7 @@
 #![allow(non_snake_case)]
 pub mod mem;
 pub mod cartridge;
+pub mod error;
 pub mod cpu;
 pub mod registers;
 pub mod terms;
@@ -13,4 +14,7 @@ pub mod timer;
 pub mod clock;
 pub mod joypad;
 pub mod apu;
-pub mod sdl2;
\ No newline at end of file
+pub mod gbs;
+pub mod limiter;
+pub mod sdl2;
+pub mod test_roms;
\ No newline at end of file
@@ -0,0 +1,92 @@
+use std::{thread, time};
+
+/// Sleeping closer than this to the deadline risks oversleeping, the rest is spun
+const SPIN_MARGIN: time::Duration = time::Duration::from_millis(2);
+
+/// Paces frames to a fixed rate with a sleep/spin hybrid, independent of the display refresh
+pub struct FrameLimiter {
+    interval: time::Duration,
+    multiplier: f64,
+    next: time::Instant,
+}
+
+impl FrameLimiter {
+    pub fn power_up(fps: f64) -> Self {
+        Self { interval: Self::interval(fps), multiplier: 1.0, next: time::Instant::now() }
+    }
+
+    /// Time one frame should take at the given rate
+    pub fn interval(fps: f64) -> time::Duration {
+        time::Duration::from_secs_f64(1.0 / fps)
+    }
+
+    /// Runs at this multiple of the target rate, 0 or less disables limiting
+    pub fn set_multiplier(&mut self, multiplier: f64) {
+        self.multiplier = multiplier;
+    }
+
+    /// Blocks until the next frame is due
+    /// * Falling more than a frame behind drops the debt instead of rushing to catch up
+    pub fn wait(&mut self) {
+        if self.multiplier <= 0.0 {
+            self.next = time::Instant::now();
+            return;
+        }
+        self.next += self.interval.div_f64(self.multiplier);
+        let now = time::Instant::now();
+        if self.next < now {
+            self.next = now;
+            return;
+        }
+        if self.next - now > SPIN_MARGIN {
+            thread::sleep(self.next - now - SPIN_MARGIN);
+        }
+        while time::Instant::now() < self.next {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Decides which finished frames get drawn when only every Nth one should be
+pub struct FrameSkip {
+    every: u32,
+    count: u32,
+}
+
+impl FrameSkip {
+    /// Draws one frame in every, 0 and 1 both draw them all
+    pub fn power_up(every: u32) -> Self {
+        Self { every: every.max(1), count: 0 }
+    }
+
+    /// Called once per vblank, true if this frame should be rendered
+    pub fn render(&mut self) -> bool {
+        let render = self.count == 0;
+        self.count = (self.count + 1) % self.every;
+        render
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::FRAME_RATE;
+
+    #[test]
+    fn interval_is_one_frame_at_the_rate() {
+        assert_eq!(FrameLimiter::interval(50.0), time::Duration::from_millis(20));
+        let frame = FrameLimiter::interval(FRAME_RATE).as_secs_f64();
+        assert!((frame - 0.016_742).abs() < 0.000_001, "{}", frame);
+    }
+
+    #[test]
+    fn frame_skip_draws_every_other_frame() {
+        let mut skip = FrameSkip::power_up(2);
+        let drawn: Vec<bool> = (0..6).map(|_| skip.render()).collect();
+        assert_eq!(drawn, [true, false, true, false, true, false]);
+        for every in [0, 1] {
+            let mut skip = FrameSkip::power_up(every);
+            assert!((0..4).all(|_| skip.render()));
+        }
+    }
+}
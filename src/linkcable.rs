@@ -1,16 +1,115 @@
-use super::intf::Intf;
+use super::intf::{Flags, Intf};
+use super::terms::Term;
 use std::cell::RefCell;
 use  std::rc::Rc;
 
+/// CPU cycles per bit on the normal internal clock, 8192Hz at single speed
+/// * The serial clock comes off the CPU clock, so in double speed a transfer takes the same cycles and half the time
+const BIT_CYCLES: u32 = 512;
+/// CPU cycles per bit on the CGB fast internal clock, 262144Hz at single speed
+const FAST_BIT_CYCLES: u32 = 16;
+
+/// Whatever sits on the other end of the link cable
+pub trait SerialPeer {
+    /// Takes the byte shifted out when a transfer on the internal clock completes and returns the byte shifted in
+    fn exchange(&mut self, byte: u8) -> u8;
+}
+
+/// Nothing plugged in, the line floats high so 0xFF is shifted in
+pub struct NullPeer;
+
+impl SerialPeer for NullPeer {
+    fn exchange(&mut self, _: u8) -> u8 {
+        0xFF
+    }
+}
+
+/// A cable plugged back into the same port, every byte sent is received
+pub struct LoopbackPeer;
+
+impl SerialPeer for LoopbackPeer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        byte
+    }
+}
+
 pub struct Serial {
-    _intf: Rc<RefCell<Intf>>,
+    intf: Rc<RefCell<Intf>>,
+    term: Term,
     data: u8,
     control: u8,
+    /// CPU cycles left in the transfer running on the internal clock
+    remaining: u32,
+    capture: bool,
+    output: Vec<u8>,
+    peer: Box<dyn SerialPeer>,
 }
 
 impl Serial {
-    pub fn power_up(intf: Rc<RefCell<Intf>>) -> Self {
-        Self { _intf: intf, data: 0x00, control: 0x00 }
+    pub fn power_up(term: Term, intf: Rc<RefCell<Intf>>) -> Self {
+        Self { intf, term, data: 0x00, control: 0x00, remaining: 0, capture: false, output: Vec::new(), peer: Box::new(NullPeer) }
+    }
+
+    /// Plugs a peer into the link port, replacing the one there
+    pub fn attach(&mut self, peer: Box<dyn SerialPeer>) {
+        self.peer = peer;
+    }
+
+    /// Unplugs the peer, transfers on the internal clock receive 0xFF again
+    pub fn detach(&mut self) {
+        self.peer = Box::new(NullPeer);
+    }
+
+    /// SB, the byte being shifted out or the last one received
+    pub fn data(&self) -> u8 {
+        self.data
+    }
+
+    /// SC, bit 7 is set while a transfer is pending
+    pub fn control(&self) -> u8 {
+        self.control
+    }
+
+    /// Keeps every byte sent over the link, test roms print their results this way
+    pub fn set_capture(&mut self, capture: bool) {
+        self.capture = capture;
+    }
+
+    /// Bytes sent since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Shifts in a byte clocked by a peer, completing a transfer armed on the external clock
+    /// * Returns the byte shifted out to the peer, None when no external transfer is pending
+    pub fn clock_in(&mut self, byte: u8) -> Option<u8> {
+        if self.control & 0x81 != 0x80 {
+            return None;
+        }
+        let out = self.data;
+        self.finish(byte);
+        Some(out)
+    }
+
+    /// Runs the internal clock for the given CPU cycles
+    pub fn next(&mut self, cycles: u32) {
+        if self.remaining == 0 {
+            return;
+        }
+        self.remaining = self.remaining.saturating_sub(cycles);
+        if self.remaining == 0 {
+            let byte = self.peer.exchange(self.data);
+            self.finish(byte);
+        }
+    }
+
+    fn finish(&mut self, byte: u8) {
+        if self.capture {
+            self.output.push(self.data);
+        }
+        self.data = byte;
+        self.control &= 0x7F;
+        self.intf.borrow_mut().hi(Flags::Serial);
     }
 
     pub fn get(&self, a: u16) -> u8 {
@@ -23,8 +122,61 @@ impl Serial {
     pub fn set(&mut self, a: u16, v: u8) {
         match a {
             0xFF01 => self.data = v,
-            0xFF02 => self.control = v,
+            0xFF02 => {
+                self.control = v;
+                // A transfer on the internal clock takes 8 bit times, on the external clock it waits for clock_in
+                self.remaining = if v & 0x81 != 0x81 {
+                    0
+                } else if self.term == Term::GBC && v & 0x02 != 0 {
+                    8 * FAST_BIT_CYCLES
+                } else {
+                    8 * BIT_CYCLES
+                };
+            }
             _ => panic!("Not supported data")
         };
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_in_completes_an_external_transfer() {
+        let intf = Rc::new(RefCell::new(Intf::power_up()));
+        let mut serial = Serial::power_up(Term::GB, intf.clone());
+        serial.set(0xFF01, 0x42);
+        // Nothing armed yet, the peer's clock is ignored
+        assert_eq!(serial.clock_in(0x99), None);
+        serial.set(0xFF02, 0x80);
+        // The internal clock never finishes an external transfer
+        serial.next(8 * BIT_CYCLES);
+        assert_eq!(serial.control(), 0x80);
+        assert_eq!(serial.clock_in(0x99), Some(0x42));
+        assert_eq!(serial.data(), 0x99);
+        assert_eq!(serial.control(), 0x00);
+        assert_eq!(intf.borrow().pending(), 1 << Flags::Serial as u8);
+    }
+
+    #[test]
+    fn loopback_peer_echoes_the_byte_sent() {
+        let intf = Rc::new(RefCell::new(Intf::power_up()));
+        let mut serial = Serial::power_up(Term::GB, intf.clone());
+        serial.attach(Box::new(LoopbackPeer));
+        serial.set(0xFF01, 0x5A);
+        serial.set(0xFF02, 0x81);
+        serial.next(8 * BIT_CYCLES - 1);
+        assert_eq!(intf.borrow().pending(), 0x00);
+        serial.next(1);
+        assert_eq!(serial.data(), 0x5A);
+        assert_eq!(serial.control(), 0x01);
+        assert_eq!(intf.borrow().pending(), 1 << Flags::Serial as u8);
+
+        // Unplugged, the line floats high again
+        serial.detach();
+        serial.set(0xFF02, 0x81);
+        serial.next(8 * BIT_CYCLES);
+        assert_eq!(serial.data(), 0xFF);
+    }
+}
@@ -1,20 +1,66 @@
+use std::io::Write;
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::surface::Surface;
-use OxidBoy::gpu::{SCREEN_H, SCREEN_W};
-use OxidBoy::motherboard::MotherBoard;
-use OxidBoy::apu::Apu;
+use OxidBoy::gpu::{PixelFormat, FRAME_CYCLES, FRAME_RATE, SCREEN_H, SCREEN_W};
+use OxidBoy::cartridge::LoadOptions;
+use OxidBoy::motherboard::{BoardEvent, MotherBoard, HANG_THRESHOLD};
+use OxidBoy::apu::{Apu, OutputMode};
+use OxidBoy::limiter::{FrameLimiter, FrameSkip};
+use OxidBoy::linkcable::{LoopbackPeer, SerialPeer};
 use cpal::Sample;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use sdl2::pixels::PixelFormatEnum;
-use OxidBoy::sdl2::update_with_buffer;
+use OxidBoy::sdl2::{load_window_position, on_any_display, save_window_position, update_with_buffer, CONFIG_PATH};
 
+/// Instructions run without a v-blank before events are pumped anyway, so a hung rom can still be quit
+const PUMP_BUDGET: u32 = 1_000_000;
+
+/// Counts instructions since the last v-blank to decide when events have to be pumped without one
+struct PumpWatchdog {
+    budget: u32,
+    steps: u32,
+}
+
+impl PumpWatchdog {
+    fn power_up(budget: u32) -> Self {
+        Self { budget, steps: 0 }
+    }
+
+    /// Called once per instruction, true when the budget ran out and events should be pumped now
+    fn step(&mut self, vblank: bool) -> bool {
+        if vblank {
+            self.steps = 0;
+            return false;
+        }
+        self.steps += 1;
+        if self.steps < self.budget {
+            return false;
+        }
+        self.steps = 0;
+        true
+    }
+}
 
 fn main() -> Result<(), String> {
 
     let mut rom = String::from("");
     let mut _scale = 2;
+    let mut mono = false;
+    let mut no_vsync = false;
+    let mut benchmark = 0u32;
+    let mut border = String::new();
+    let mut info = false;
+    let mut window_x: Option<i32> = None;
+    let mut window_y: Option<i32> = None;
+    let mut frameskip = 1u32;
+    let mut latency = 0u64;
+    let mut diag_log = String::new();
+    let mut boot_rom = String::new();
+    let mut link = String::new();
     // Sets up argument parser to get rom location
     {
         let mut ap = argparse::ArgumentParser::new();
@@ -24,84 +70,174 @@ fn main() -> Result<(), String> {
             argparse::Store,
             "Scale the Window",
         );
-        ap.refer(&mut rom).add_argument("rom", argparse::Store, "Rom name");
+        ap.refer(&mut mono).add_option(
+            &["--mono"],
+            argparse::StoreTrue,
+            "Mix audio down to mono",
+        );
+        ap.refer(&mut no_vsync).add_option(
+            &["--no-vsync"],
+            argparse::StoreTrue,
+            "Don't wait for the display refresh, the frame limiter alone paces the emulator",
+        );
+        ap.refer(&mut benchmark).add_option(
+            &["--benchmark"],
+            argparse::Store,
+            "Run this many frames without video or audio as fast as possible and print the speed",
+        );
+        ap.refer(&mut border).add_option(
+            &["--border"],
+            argparse::Store,
+            "PNG drawn around the screen, the game is centered in it",
+        );
+        ap.refer(&mut info).add_option(
+            &["--info"],
+            argparse::StoreTrue,
+            "Print the rom header and whether its cartridge type is supported, then exit",
+        );
+        ap.refer(&mut frameskip).add_option(
+            &["--frameskip"],
+            argparse::Store,
+            "Only draw every Nth frame, emulation and audio still run at full speed",
+        );
+        ap.refer(&mut latency).add_option(
+            &["--latency"],
+            argparse::Store,
+            "Most audio buffered ahead in milliseconds, lower cuts delay but may crackle",
+        );
+        ap.refer(&mut diag_log).add_option(
+            &["--diag-log"],
+            argparse::Store,
+            "Log unusual memory accesses with the PC that made them to this file, - for stderr",
+        );
+        ap.refer(&mut boot_rom).add_option(
+            &["--boot-rom"],
+            argparse::Store,
+            "Boot rom image to run before the game, it hands over at 0x0100 like on hardware",
+        );
+        ap.refer(&mut link).add_option(
+            &["--link"],
+            argparse::Store,
+            "Plug something into the link port: none, or loopback to receive every byte sent",
+        );
+        ap.refer(&mut window_x).add_option(
+            &["--window-x"],
+            argparse::StoreOption,
+            "Left edge of the window, defaults to where it was last closed",
+        );
+        ap.refer(&mut window_y).add_option(
+            &["--window-y"],
+            argparse::StoreOption,
+            "Top edge of the window, defaults to where it was last closed",
+        );
+        ap.refer(&mut rom).add_argument("rom", argparse::Store, "Rom name, .gz/.zip archives or - for stdin");
         ap.parse_args_or_exit();
     }
 
+    if info {
+        print!("{}", OxidBoy::cartridge::info(&rom).map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+
+    if benchmark > 0 {
+        run_benchmark(rom, benchmark);
+        return Ok(());
+    }
+
     // Powers up the MotherBoard
-    let mut motherboard = MotherBoard::power_up(rom);
+    let mut motherboard = if rom.to_lowercase().ends_with(".gbs") {
+        MotherBoard::load_gbs(&rom).map_err(|e| e.to_string())?
+    } else {
+        let (motherboard, warnings) = MotherBoard::try_power_up_with(&rom, LoadOptions::default()).map_err(|e| e.to_string())?;
+        for warning in warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        motherboard
+    };
+    motherboard.set_hang_threshold(HANG_THRESHOLD);
+    if !boot_rom.is_empty() {
+        let image = std::fs::read(&boot_rom).map_err(|e| format!("{}: {}", boot_rom, e))?;
+        motherboard.set_boot_rom(image);
+    }
+    if let Some(peer) = link_peer(&link)? {
+        motherboard.mmu.borrow_mut().serial.attach(peer);
+    }
     let rom_name = motherboard.mmu.borrow().cartridge.title();
+    if !diag_log.is_empty() {
+        let file = if diag_log == "-" { None } else { Some(std::fs::File::create(&diag_log).map_err(|e| format!("{}: {}", diag_log, e))?) };
+        motherboard.mmu.borrow_mut().set_diagnostics(Some(Box::new(move |d| {
+            let line = format!("pc={:04x} {} {:04x}={:02x} cycle={} {}", d.pc, if d.access.write { "write" } else { "read" }, d.access.addr, d.access.value, d.access.cycle, d.reason);
+            match &file {
+                // &File is Write, so the shared closure can log without a lock
+                Some(f) => {
+                    let _ = writeln!(&*f, "{}", line);
+                }
+                None => eprintln!("{}", line),
+            }
+        })));
+    }
+    if !border.is_empty() {
+        let (rgba, width, height) = load_png_rgba(&border)?;
+        if width < SCREEN_W || height < SCREEN_H {
+            return Err(format!("{}: border is smaller than the {}x{} screen", border, SCREEN_W, SCREEN_H));
+        }
+        motherboard.set_border(&rgba, width, height);
+    }
+    // The window grows to the border when there is one
+    let (out_w, out_h) = motherboard.border().map_or((SCREEN_W, SCREEN_H), |b| (b.width, b.height));
 
     // Creates sdl2 dependencies and unwraps them
     let sdl_context = sdl2::init()?;
     let video = sdl_context.video()?;
 
-    let mut window = video.window(format!("OxidBoy - {}", rom_name).as_str(), (SCREEN_W as u32) * _scale, (SCREEN_H as u32) * _scale)
-    .position_centered()
+    // Options win over the remembered position, which is dropped if its display is gone
+    let displays: Vec<_> = (0..video.num_video_displays()?).filter_map(|i| video.display_bounds(i).ok()).collect();
+    let saved = load_window_position(CONFIG_PATH).filter(|&(x, y)| on_any_display(x, y, &displays));
+    let position = match (window_x.or(saved.map(|p| p.0)), window_y.or(saved.map(|p| p.1))) {
+        (Some(x), Some(y)) => Some((x, y)),
+        _ => None,
+    };
+
+    let mut window = video.window(format!("OxidBoy - {}", rom_name).as_str(), (out_w as u32) * _scale, (out_h as u32) * _scale);
+    match position {
+        Some((x, y)) => window.position(x, y),
+        None => window.position_centered(),
+    };
+    let mut window = window
     .build()
     .map_err(|e| e.to_string())?;
 
     let icon = Surface::load_bmp(Path::new("./assets/OBicon.bmp")).map_err(|e| e.to_string())?;
     window.set_icon(icon);
 
-    let mut canvas = window.into_canvas()
-    .present_vsync()
+    let mut canvas = window.into_canvas();
+    if !no_vsync {
+        canvas = canvas.present_vsync();
+    }
+    let mut canvas = canvas
     .build()
     .map_err(|e| e.to_string())?;
 
     let texture_creator = canvas.texture_creator();
 
-    let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::ARGB8888, SCREEN_W as u32, SCREEN_H as u32)
+    let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::ARGB8888, out_w as u32, out_h as u32)
     .map_err(|e| e.to_string())?;
 
-    let mut window_buffer = vec![0x00; SCREEN_W * SCREEN_H];
+    let mut window_buffer = vec![0x00u32; out_w * out_h];
+    let mut screen_buffer = vec![0x00u32; SCREEN_W * SCREEN_H];
 
 
     // Initialize audio related. It is necessary to ensure that the stream object remains alive.
-    let stream: cpal::Stream;
-        let host = cpal::default_host();
-        let device = host.default_output_device().unwrap();
-        let config = device.default_output_config().unwrap();
-        let sample_format = config.sample_format();
-        let config: cpal::StreamConfig = config.into();
-
-        let apu = Apu::power_up(config.sample_rate.0);
-        let apu_data = apu.buffer.clone();
-        motherboard.mmu.borrow_mut().apu = apu;
-
-        stream = match sample_format {
-            cpal::SampleFormat::F32 => device
-                .build_output_stream(
-                    &config,
-                    move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                        let len = std::cmp::min(data.len() / 2, apu_data.lock().unwrap().len());
-                        for (i, (data_l, data_r)) in apu_data.lock().unwrap().drain(..len).enumerate() {
-                            data[i * 2 + 0] = data_l;
-                            data[i * 2 + 1] = data_r;
-                        }
-                    },
-                    move |err| println!("{}", err),
-                    None,
-                )
-                .unwrap(),
-            cpal::SampleFormat::F64 => device
-                .build_output_stream(
-                    &config,
-                    move |data: &mut [f64], _: &cpal::OutputCallbackInfo| {
-                        let len = std::cmp::min(data.len() / 2, apu_data.lock().unwrap().len());
-                        for (i, (data_l, data_r)) in apu_data.lock().unwrap().drain(..len).enumerate() {
-                            data[i * 2 + 0] = data_l.to_sample::<f64>();
-                            data[i * 2 + 1] = data_r.to_sample::<f64>();
-                        }
-                    },
-                    move |err| println!("{}", err),
-                    None,
-                )
-                .unwrap(),
-            _ => panic!("unreachable"),
-        };
-        stream.play().unwrap();
-    let _ = stream;
+    let audio_lost = Arc::new(AtomicBool::new(false));
+    let (mut _stream, sample_rate) = open_audio(&motherboard.mmu.borrow().apu, audio_lost.clone());
+    let mut audio_paused = false;
+    motherboard.mmu.borrow_mut().apu.set_sample_rate(sample_rate);
+    if mono {
+        motherboard.mmu.borrow_mut().apu.set_output_mode(OutputMode::Mono);
+    }
+    if latency > 0 {
+        motherboard.mmu.borrow_mut().apu.set_target_latency(std::time::Duration::from_millis(latency));
+    }
 
     let keymap = vec![
             (sdl2::keyboard::Keycode::Right, OxidBoy::joypad::Key::Right),
@@ -113,57 +249,274 @@ fn main() -> Result<(), String> {
             (sdl2::keyboard::Keycode::C, OxidBoy::joypad::Key::Select),
             (sdl2::keyboard::Keycode::V, OxidBoy::joypad::Key::Start),
         ];
+    // Frames are paced here instead of by the CPU clock so a fast monitor doesn't speed things up
+    motherboard.cpu.set_throttle(false);
+    let mut limiter = FrameLimiter::power_up(FRAME_RATE);
+    let mut frame_cycles = 0;
+    let mut skip = FrameSkip::power_up(frameskip);
+    let mut watchdog = PumpWatchdog::power_up(PUMP_BUDGET);
+
     // Intialize the event punp for receiving input
     let mut event_pump = sdl_context.event_pump()?;
+
+    // SIGINT/SIGTERM only raise a flag so the loop can leave and save like a normal exit
+    let terminate = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, terminate.clone()).map_err(|e| e.to_string())?;
+    }
     'running: loop 
     {
         // Execute next instruction
-        motherboard.next();
+        let cycles = motherboard.next();
+        frame_cycles += cycles / motherboard.mmu.borrow().speed as u32;
+        if frame_cycles >= FRAME_CYCLES {
+            frame_cycles -= FRAME_CYCLES;
+            limiter.wait();
+        }
 
         // Update the window
-        if motherboard.check_reset_gpu() {
-            let mut i: usize = 0;
-            for l in motherboard.mmu.borrow().gpu.data.iter() {
-                for w in l.iter() {
-                    let b = u32::from(w[0]) << 16;
-                    let g = u32::from(w[1]) << 8;
-                    let r = u32::from(w[2]);
-                    let a = 0xff00_0000;
-
-                    window_buffer[i] = a | r | g | b ;
-                    i += 1;
-                }
+        let vblank = motherboard.check_reset_gpu();
+        let forced = watchdog.step(vblank);
+        if vblank && skip.render() {
+            if let Some(border) = motherboard.border() {
+                motherboard.mmu.borrow().gpu.write_framebuffer(bytemuck::cast_slice_mut(&mut screen_buffer), PixelFormat::Argb8888);
+                border.composite(&screen_buffer, &mut window_buffer);
+            } else {
+                motherboard.mmu.borrow().gpu.write_framebuffer(bytemuck::cast_slice_mut(&mut window_buffer), PixelFormat::Argb8888);
             }
-            let _ = update_with_buffer(&mut canvas, &mut texture, &window_buffer, SCREEN_W);
+            let _ = update_with_buffer(&mut canvas, &mut texture, &window_buffer, out_w);
         }
         
 
-        if !motherboard.cpu.flip() {
+        if !motherboard.cpu.flip() && !forced {
             continue;
         }
 
+        if terminate.load(Ordering::Relaxed) {
+            break 'running;
+        }
+
+        if let Some(BoardEvent::HangDetected(pc)) = motherboard.take_event() {
+            eprintln!("The game looks frozen at 0x{:04x}, no interrupt can get it out of that loop", pc);
+        }
+
+        // Output device changed, reopen the default one and follow its sample rate
+        if audio_lost.swap(false, Ordering::Relaxed) {
+            let (stream, sample_rate) = open_audio(&motherboard.mmu.borrow().apu, audio_lost.clone());
+            _stream = stream;
+            audio_paused = false;
+            motherboard.mmu.borrow_mut().apu.set_sample_rate(sample_rate);
+        }
+
+        // Nothing playing and the tail drained, let the device idle until sound starts again
+        let silent = {
+            let apu = &motherboard.mmu.borrow().apu;
+            apu.is_silent() && apu.buffer.lock().unwrap().is_empty()
+        };
+        if silent != audio_paused {
+            let result = if silent { _stream.pause().map_err(|e| e.to_string()) } else { _stream.play().map_err(|e| e.to_string()) };
+            if let Err(e) = result {
+                eprintln!("{}", e);
+            }
+            audio_paused = silent;
+        }
+
         // Handling keyboard events
         for event in event_pump.poll_iter() {
             match event {
                 // Breaks loop if escape is pressed or program is exited
                 Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'running,
+                // F5 writes battery ram out now, F6 throws away what's in memory for the last save on disk
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                    motherboard.mmu.borrow_mut().cartridge.sav();
+                    println!("Saved battery ram");
+                }
+                Event::KeyDown { keycode: Some(Keycode::F6), .. } => match motherboard.reload_sram(&rom) {
+                    Ok(true) => println!("Reloaded battery ram from disk"),
+                    Ok(false) => println!("This cartridge has no battery ram to reload"),
+                    Err(e) => eprintln!("Couldn't reload battery ram: {}", e),
+                },
+                // Holding Tab fast forwards
+                Event::KeyDown { keycode: Some(Keycode::Tab), .. } => limiter.set_multiplier(4.0),
+                Event::KeyUp { keycode: Some(Keycode::Tab), .. } => limiter.set_multiplier(1.0),
                 // Uses keymap to use inputed key as a GB Button and set it in motherboard
                 Event::KeyDown { keycode: Some(key), .. } => {
                     if let Some((_, gbkey)) = keymap.iter().find(|(k, _)| *k == key) {
-                        motherboard.mmu.borrow_mut().joypad.keydown(gbkey.clone());
+                        motherboard.mmu.borrow_mut().joypad.keydown(*gbkey);
                     }
                 }
                 Event::KeyUp { keycode: Some(key), .. } => {
                     if let Some((_, gbkey)) = keymap.iter().find(|(k, _)| *k == key) {
-                        motherboard.mmu.borrow_mut().joypad.keyup(gbkey.clone());
+                        motherboard.mmu.borrow_mut().joypad.keyup(*gbkey);
                     }
                 }
                 _ => {}
             }
         }
     }
-    // Save all data on application end
-    motherboard.mmu.borrow_mut().cartridge.sav();
+    shutdown(&mut motherboard);
+    let (x, y) = canvas.window().position();
+    if let Err(e) = save_window_position(CONFIG_PATH, x, y) {
+        eprintln!("{}: {}", CONFIG_PATH, e);
+    }
     Ok(())
 }
 
+/// Save all data on application end, reached from both a normal quit and a signal
+fn shutdown(motherboard: &mut MotherBoard) {
+    motherboard.mmu.borrow_mut().cartridge.sav();
+}
+
+/// Peer for the --link option, None leaves the port empty
+fn link_peer(name: &str) -> Result<Option<Box<dyn SerialPeer>>, String> {
+    match name {
+        "" | "none" => Ok(None),
+        "loopback" => Ok(Some(Box::new(LoopbackPeer))),
+        other => Err(format!("Unknown link peer {}, try none or loopback", other)),
+    }
+}
+
+/// Decodes a PNG into RGBA bytes, returns the pixels, width and height
+fn load_png_rgba(path: &str) -> Result<(Vec<u8>, usize, usize), String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|e| format!("{}: {}", path, e))?;
+    let mut buf = vec![0x00; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|e| format!("{}: {}", path, e))?;
+    let buf = &buf[..info.buffer_size()];
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => buf.to_vec(),
+        png::ColorType::Rgb => buf.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 0xFF]).collect(),
+        png::ColorType::GrayscaleAlpha => buf.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&g| [g, g, g, 0xFF]).collect(),
+        png::ColorType::Indexed => return Err(format!("{}: indexed color wasn't expanded", path)),
+    };
+    Ok((rgba, info.width as usize, info.height as usize))
+}
+
+/// Runs the rom headless for the given number of frames and prints frames per second and time per instruction
+/// * Also lists the opcodes and 256 byte pages most instructions came from
+fn run_benchmark(rom: String, frames: u32) {
+    let stats = MotherBoard::power_up(rom).benchmark(frames);
+    let fps = stats.fps();
+    println!("{} frames in {:.3}s", frames, stats.elapsed.as_secs_f64());
+    println!("{:.1} fps ({:.1}x real time)", fps, fps / FRAME_RATE);
+    println!("{:.2} ns per instruction", stats.ns_per_instruction());
+    let profile = stats.profile;
+    let total = profile.opcodes.iter().map(|(_, n)| n).sum::<u64>().max(1) as f64;
+    println!("hottest opcodes:");
+    for (opcode, n) in profile.opcodes.iter().take(10) {
+        println!("  {:02X}  {:5.1}%", opcode, *n as f64 * 100.0 / total);
+    }
+    println!("hottest pages:");
+    for (page, n) in profile.pages.iter().take(5) {
+        println!("  {:02X}00  {:5.1}%", page, *n as f64 * 100.0 / total);
+    }
+}
+
+/// Opens the default output device and starts streaming the apu buffer to it
+/// * Returns the stream, which has to be kept alive, and the sample rate of the device
+/// * lost is set when the device goes away so the stream can be rebuilt
+fn open_audio(apu: &Apu, lost: Arc<AtomicBool>) -> (cpal::Stream, u32) {
+    let host = cpal::default_host();
+    let device = host.default_output_device().unwrap();
+    let config = device.default_output_config().unwrap();
+    let sample_format = config.sample_format();
+    let config: cpal::StreamConfig = config.into();
+
+    let apu_data = apu.buffer.clone();
+    let apu_underruns = apu.underruns.clone();
+    let error = move |err: cpal::StreamError| {
+        println!("{}", err);
+        if let cpal::StreamError::DeviceNotAvailable = err {
+            lost.store(true, Ordering::Relaxed);
+        }
+    };
+
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => device
+            .build_output_stream(
+                &config,
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    let len = std::cmp::min(data.len() / 2, apu_data.lock().unwrap().len());
+                    if len < data.len() / 2 {
+                        apu_underruns.fetch_add(1, Ordering::Relaxed);
+                    }
+                    for (i, (data_l, data_r)) in apu_data.lock().unwrap().drain(..len).enumerate() {
+                        data[i * 2 + 0] = data_l;
+                        data[i * 2 + 1] = data_r;
+                    }
+                },
+                error,
+                None,
+            )
+            .unwrap(),
+        cpal::SampleFormat::F64 => device
+            .build_output_stream(
+                &config,
+                move |data: &mut [f64], _: &cpal::OutputCallbackInfo| {
+                    let len = std::cmp::min(data.len() / 2, apu_data.lock().unwrap().len());
+                    if len < data.len() / 2 {
+                        apu_underruns.fetch_add(1, Ordering::Relaxed);
+                    }
+                    for (i, (data_l, data_r)) in apu_data.lock().unwrap().drain(..len).enumerate() {
+                        data[i * 2 + 0] = data_l.to_sample::<f64>();
+                        data[i * 2 + 1] = data_r.to_sample::<f64>();
+                    }
+                },
+                error,
+                None,
+            )
+            .unwrap(),
+        _ => panic!("unreachable"),
+    };
+    stream.play().unwrap();
+    (stream, config.sample_rate.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use OxidBoy::cartridge::{Cartridge, Stable};
+    use OxidBoy::mem::Memory;
+    use OxidBoy::terms::Term;
+
+    /// Cartridge that only counts how often it was saved
+    struct CountingSaves(Arc<AtomicU32>);
+
+    impl Memory for CountingSaves {
+        fn get(&self, _: u16) -> u8 {
+            0xFF
+        }
+
+        fn set(&mut self, _: u16, _: u8) {}
+    }
+
+    impl Stable for CountingSaves {
+        fn sav(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Cartridge for CountingSaves {}
+
+    #[test]
+    fn shutdown_saves_once() {
+        let saves = Arc::new(AtomicU32::new(0));
+        let mut motherboard = MotherBoard::with_cartridge(Box::new(CountingSaves(saves.clone())), Term::GB);
+        shutdown(&mut motherboard);
+        assert_eq!(saves.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn watchdog_forces_a_pump_once_the_budget_runs_out() {
+        let mut watchdog = PumpWatchdog::power_up(3);
+        assert_eq!([watchdog.step(false), watchdog.step(false), watchdog.step(false)], [false, false, true]);
+        // The count starts over after a forced pump and after every v-blank
+        assert_eq!([watchdog.step(false), watchdog.step(false), watchdog.step(true)], [false, false, false]);
+        assert_eq!([watchdog.step(false), watchdog.step(false), watchdog.step(false)], [false, false, true]);
+    }
+}
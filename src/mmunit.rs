@@ -1,5 +1,7 @@
-use super::apu::Apu;
-use super::cartridge::{self, Cartridge};
+use super::apu::{wave_ram_at_power_up, Apu};
+use super::cartridge::{self, Cartridge, LoadOptions};
+use super::cpu;
+use super::error::{EmuError, LoadWarning};
 use super::terms::Term;
 use super::gpu::{Gpu, Hdma, HdmaMode};
 use super::intf::Intf;
@@ -11,12 +13,38 @@ use std::cell::RefCell;
 use std::path::Path;
 use std::rc::Rc;
 
+/// One bus access as seen by a tracer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+    /// CPU cycles run before the instruction doing the access
+    pub cycle: u64,
+}
+
+/// A bus access to somewhere working programs normally leave alone, with the instruction that made it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub access: BusAccess,
+    /// Address of the instruction that was running
+    pub pc: u16,
+    pub reason: &'static str,
+}
+
 #[derive(Clone,Copy, PartialEq, Eq)]
 pub enum Speed {
     Normal = 0x01,
     Double = 0x02,
 }
 
+/// OAM DMA in progress, one byte is copied every 4 CPU cycles
+struct OamDma {
+    src: u16,
+    index: u16,
+    cycles: u32,
+}
+
 pub struct Mmunit {
     pub cartridge: Box<dyn Cartridge>,
     pub apu: Apu,
@@ -33,6 +61,17 @@ pub struct Mmunit {
     hram: [u8; 0x7F],
     wram: [u8; 0x8000],
     wram_bank: usize,
+    strict_vram_timing: bool,
+    strict: bool,
+    dma: u8,
+    oam_dma: Option<OamDma>,
+    undocumented: [u8; 4],
+    cycles: u64,
+    writes: u64,
+    tracer: Option<Box<dyn Fn(BusAccess)>>,
+    diagnostics: Option<Box<dyn Fn(Diagnostic)>>,
+    pc: u16,
+    boot_rom: Option<Vec<u8>>,
 }
 
 impl Mmunit {
@@ -40,18 +79,48 @@ impl Mmunit {
     pub fn power_up(path: impl AsRef<Path>) -> Self {
         // Get Cartridge data and decide if its GB or GBC
         let cart = cartridge::power_up(path);
-        let term = match cart.get(0x0143) & 0x80 {
-            0x80 => Term::GBC,
-            _ => Term::GB,
-        };
-        
+        let term = Self::detect_term(cart.as_ref());
+        Self::with_cartridge(cart, term)
+    }
+
+    /// Like power_up but hands back cartridge loading errors
+    pub fn try_power_up(path: impl AsRef<Path>) -> Result<Self, EmuError> {
+        Self::try_power_up_with(path, LoadOptions::default()).map(|(mmu, _)| mmu)
+    }
+
+    /// Like try_power_up with the given load options, also hands back the loading warnings
+    pub fn try_power_up_with(path: impl AsRef<Path>, options: LoadOptions) -> Result<(Self, Vec<LoadWarning>), EmuError> {
+        let (cart, warnings) = cartridge::try_power_up_with(path, options)?;
+        let term = Self::detect_term(cart.as_ref());
+        Ok((Self::with_cartridge(cart, term), warnings))
+    }
+
+    /// Intialize Memmory Management Unit from a rom already in memory, nothing is saved to disk
+    pub fn power_up_from_bytes(rom: Vec<u8>) -> Self {
+        let cart = cartridge::power_up_from_bytes(rom, "");
+        let term = Self::detect_term(cart.as_ref());
+        Self::with_cartridge(cart, term)
+    }
+
+    /// Picks GBC when the cartridge header flags color support
+    fn detect_term(cart: &dyn Cartridge) -> Term {
+        if cart.supports_cgb() { Term::GBC } else { Term::GB }
+    }
+
+    /// Intialize Memmory Management Unit as a specific hardware revision instead of detecting it
+    pub fn power_up_as(path: impl AsRef<Path>, term: Term) -> Self {
+        Self::with_cartridge(cartridge::power_up(path), term)
+    }
+
+    /// Intialize Memmory Management Unit around an already loaded cartridge
+    pub fn with_cartridge(cart: Box<dyn Cartridge>, term: Term) -> Self {
         let intf = Rc::new(RefCell::new(Intf::power_up()));
         let mut _return = Self {
             cartridge: cart,
             apu: Apu::power_up(48000),
             gpu: Gpu::power_up(term, intf.clone()),
-            serial: Serial::power_up(intf.clone()),
-            joypad: Joypad::power_up(intf.clone()),
+            serial: Serial::power_up(term, intf.clone()),
+            joypad: Joypad::power_up(term, intf.clone()),
             shift: false,
             speed: Speed::Normal,
             term,
@@ -62,58 +131,84 @@ impl Mmunit {
             hram: [0x00; 0x7F],
             wram: [0x00; 0x8000],
             wram_bank: 0x01,
+            strict_vram_timing: false,
+            strict: false,
+            dma: 0xFF,
+            oam_dma: None,
+            undocumented: [0x00; 4],
+            cycles: 0,
+            writes: 0,
+            tracer: None,
+            diagnostics: None,
+            pc: 0x0000,
+            boot_rom: None,
         };
-        // Intialize certain important adresses for start up
-        _return.set(0xFF05, 0x00);
-        _return.set(0xFF06, 0x00);
-        _return.set(0xFF07, 0x00);
-        _return.set(0xFF10, 0x80);
-        _return.set(0xFF11, 0xBF);
-        _return.set(0xFF12, 0xF3);
-        _return.set(0xFF14, 0xBF);
-        _return.set(0xFF16, 0x3F);
-        _return.set(0xFF16, 0x3F);
-        _return.set(0xFF17, 0x00);
-        _return.set(0xFF19, 0xBF);
-        _return.set(0xFF1A, 0x7F);
-        _return.set(0xFF1B, 0xFF);
-        _return.set(0xFF1C, 0x9F);
-        _return.set(0xFF1E, 0xFF);
-        _return.set(0xFF20, 0xFF);
-        _return.set(0xFF21, 0x00);
-        _return.set(0xFF22, 0x00);
-        _return.set(0xFF23, 0xBF);
-        _return.set(0xFF24, 0x77);
-        _return.set(0xFF25, 0xF3);
-        _return.set(0xFF26, 0xF1);
-        _return.set(0xFF40, 0x91);
-        _return.set(0xFF42, 0x00);
-        _return.set(0xFF43, 0x00);
-        _return.set(0xFF45, 0x00);
-        _return.set(0xFF47, 0xFC);
-        _return.set(0xFF48, 0xFF);
-        _return.set(0xFF49, 0xFF);
-        _return.set(0xFF4A, 0x00);
-        _return.set(0xFF4B, 0x00);
+        _return.fake_boot();
+        _return.time.set_div(term.div());
+        _return.apu.set_wave_ram_quirk(term != Term::GBC);
         _return
     }
 }
 
 impl Mmunit {
+    /// Leaves the I/O registers the way the boot rom hands them to the game
+    /// * The APU is powered first, its other registers ignore writes while it's off
+    /// * IF keeps the VBlank request raised by the boot rom's last frame
+    /// * Only channel 1 is left triggered by the boot sound, so NR52 reads 0xF1
+    fn fake_boot(&mut self) {
+        self.set(0xFF26, 0xF1);
+        self.apu.set_wave_ram(wave_ram_at_power_up(self.term));
+        self.set(0xFF05, 0x00);
+        self.set(0xFF06, 0x00);
+        self.set(0xFF07, 0x00);
+        self.set(0xFF0F, 0x01);
+        self.set(0xFF10, 0x80);
+        self.set(0xFF11, 0xBF);
+        self.set(0xFF12, 0xF3);
+        self.set(0xFF14, 0xBF);
+        self.set(0xFF16, 0x3F);
+        self.set(0xFF17, 0x00);
+        self.set(0xFF19, 0x3F);
+        self.set(0xFF1A, 0x7F);
+        self.set(0xFF1B, 0xFF);
+        self.set(0xFF1C, 0x9F);
+        self.set(0xFF1E, 0x7F);
+        self.set(0xFF20, 0xFF);
+        self.set(0xFF21, 0x00);
+        self.set(0xFF22, 0x00);
+        self.set(0xFF23, 0x3F);
+        self.set(0xFF24, 0x77);
+        self.set(0xFF25, 0xF3);
+        self.set(0xFF40, 0x91);
+        self.set(0xFF42, 0x00);
+        self.set(0xFF43, 0x00);
+        self.set(0xFF45, 0x00);
+        self.set(0xFF47, 0xFC);
+        self.set(0xFF48, 0xFF);
+        self.set(0xFF49, 0xFF);
+        self.set(0xFF4A, 0x00);
+        self.set(0xFF4B, 0x00);
+        self.set(0xFFFF, 0x00);
+    }
+
     /// Advances the program forward in memory
     /// * Returns the cycles in memory
     pub fn next(&mut self, cycles: u32) -> u32 {
+        self.cycles += u64::from(cycles);
         let cpu_divider = self.speed as u32;
         let vram_cycles = self.run_dma();
         let gpu_cycles = cycles / cpu_divider + vram_cycles;
         let cpu_cycles = cycles + vram_cycles * cpu_divider;
+        self.run_oam_dma(cpu_cycles);
         self.time.next(cpu_cycles);
+        self.serial.next(cpu_cycles);
         self.gpu.next(gpu_cycles);
         self.apu.next(gpu_cycles);
         gpu_cycles
     }
 
     /// Switches speed based on shift switches from one speed to the other
+    /// * Called when STOP executes, the armed bit of KEY1 is cleared either way
     pub fn switch_speed(&mut self) {
         if self.shift {
             if self.speed == Speed::Double {
@@ -126,6 +221,188 @@ impl Mmunit {
     }
 
 
+    /// Enables blocking of VRAM during mode 3 and OAM during modes 2 and 3 like hardware does
+    /// * Off by default since some games rely on the lenient behaviour
+    pub fn set_strict_vram_timing(&mut self, strict: bool) {
+        self.strict_vram_timing = strict;
+    }
+
+    /// Writes made through the bus since power up, DMA copies aren't counted
+    pub fn write_count(&self) -> u64 {
+        self.writes
+    }
+
+    /// Reads memory without notifying the bus tracer, for debuggers and dumps
+    pub fn peek(&self, a: u16) -> u8 {
+        self.read(a)
+    }
+
+    /// Hex and ASCII dump of len bytes from start, 16 bytes per row led by the address
+    /// * Non printable bytes show as '.', the dump stops at 0xFFFF
+    pub fn hexdump(&self, start: u16, len: u16) -> String {
+        let end = (u32::from(start) + u32::from(len)).min(0x10000);
+        let mut out = String::new();
+        for row in (u32::from(start)..end).step_by(16) {
+            let bytes: Vec<u8> = (row..end.min(row + 16)).map(|a| self.peek(a as u16)).collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let ascii: String = bytes.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+            out.push_str(&format!("{:04X}  {:<47}  |{}|\n", row, hex.join(" "), ascii));
+        }
+        out
+    }
+
+    /// Calls the tracer with every read and write in order, None turns tracing off
+    /// * Accesses made by OAM DMA and HDMA are included
+    pub fn set_bus_tracer(&mut self, tracer: Option<Box<dyn Fn(BusAccess)>>) {
+        self.tracer = tracer;
+    }
+
+    fn trace(&self, addr: u16, value: u8, write: bool) {
+        if let Some(tracer) = &self.tracer {
+            tracer(BusAccess { addr, value, write, cycle: self.cycles });
+        }
+    }
+
+    /// Hands CPU accesses that look wrong to the diagnostics sink, DMA copies don't go through here
+    fn diagnose(&self, addr: u16, value: u8, write: bool) {
+        if let Some(diagnostics) = &self.diagnostics {
+            if let Some(reason) = self.unusual(addr, write) {
+                diagnostics(Diagnostic { access: BusAccess { addr, value, write, cycle: self.cycles }, pc: self.pc, reason });
+            }
+        }
+    }
+
+    /// Calls the sink with every unusual access, None turns it off
+    /// * Writes to the rom of a cartridge without a mapper, any access to 0xFEA0-0xFEFF
+    /// * VRAM and OAM accesses while the PPU owns them
+    pub fn set_diagnostics(&mut self, diagnostics: Option<Box<dyn Fn(Diagnostic)>>) {
+        self.diagnostics = diagnostics;
+    }
+
+    /// Address of the instruction about to run, reported with diagnostics
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// Maps a boot rom image over the cartridge from 0x0000 until a non zero write to FF50
+    /// * A 256 byte DMG image covers 0x0000-0x00FF
+    /// * A 2304 byte CGB image also covers 0x0200-0x08FF, the cartridge header shows through in between
+    pub fn set_boot_rom(&mut self, boot: Vec<u8>) {
+        self.boot_rom = Some(boot);
+    }
+
+    /// Whether the boot rom is still mapped, it unmaps itself right before jumping to 0x0100
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    /// Byte of the mapped boot rom at a, None where the cartridge shows
+    fn boot_rom_byte(&self, a: u16) -> Option<u8> {
+        match a {
+            0x0000..=0x00FF | 0x0200..=0x08FF => self.boot_rom.as_ref()?.get(a as usize).copied(),
+            _ => None,
+        }
+    }
+
+    /// Why an access is worth logging, None for ordinary ones
+    fn unusual(&self, a: u16, write: bool) -> Option<&'static str> {
+        match a {
+            _ if self.ppu_locked(a) => Some("VRAM/OAM access while the PPU owns it"),
+            0x0000..=0x7FFF if write && self.cartridge.get(0x0147) == 0x00 => Some("write to rom without a mapper"),
+            0xFEA0..=0xFEFF => Some("access to unusable 0xFEA0-0xFEFF"),
+            _ => None,
+        }
+    }
+
+    /// Panics on accesses no working program makes, for catching bugs in homebrew
+    /// * Touching VRAM or OAM while the PPU owns it
+    /// * Writing the rom area of a cartridge without a mapper, or the unusable 0xFEA0-0xFEFF
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Panics with the offending access when strict mode is on
+    fn check_strict(&self, a: u16, write: bool) {
+        if !self.strict {
+            return;
+        }
+        let kind = if write { "write to" } else { "read from" };
+        if self.ppu_locked(a) {
+            panic!("Strict: {} 0x{:04x} while the PPU is in mode {}", kind, a, self.gpu.mode());
+        }
+        let illegal_write = match a {
+            0x0000..=0x7FFF => self.cartridge.get(0x0147) == 0x00,
+            0xFEA0..=0xFEFF => true,
+            _ => false,
+        };
+        if write && illegal_write {
+            panic!("Strict: {} 0x{:04x}, which isn't writable", kind, a);
+        }
+    }
+
+    /// Checks if the CPU is currently locked out of the given VRAM/OAM address by the PPU
+    fn ppu_blocked(&self, a: u16) -> bool {
+        self.strict_vram_timing && self.ppu_locked(a)
+    }
+
+    /// Whether the PPU owns the given VRAM/OAM address in its current mode
+    fn ppu_locked(&self, a: u16) -> bool {
+        match a {
+            0x8000..=0x9FFF => self.gpu.mode() == 3,
+            0xFE00..=0xFE9F => self.gpu.mode() == 2 || self.gpu.mode() == 3,
+            _ => false,
+        }
+    }
+
+    /// Address of the instruction following the one at addr, e.g. for a debugger's step over
+    pub fn next_pc(&self, addr: u16) -> u16 {
+        addr.wrapping_add(u16::from(cpu::INSTR_LEN[self.get(addr) as usize]))
+    }
+
+    /// Offset into wram for 0xC000-0xFDFF
+    /// * 0xE000-0xFDFF echoes 0xC000-0xDDFF, the upper half follows the switchable bank like the original
+    /// * wram holds eight 4 KiB banks back to back, bank 0 at 0x0000 and bank 7 ending at 0x7FFF
+    fn wram_index(&self, a: u16) -> usize {
+        debug_assert!((1..=7).contains(&self.wram_bank), "WRAM bank {} out of range", self.wram_bank);
+        let a = match a {
+            0xE000..=0xFDFF => a - 0x2000,
+            _ => a,
+        };
+        let i = match a {
+            0xC000..=0xCFFF => a as usize - 0xC000,
+            _ => a as usize - 0xD000 + 0x1000 * self.wram_bank,
+        };
+        debug_assert!(i < self.wram.len(), "WRAM index 0x{:04x} for 0x{:04x} in bank {} is past the end", i, a, self.wram_bank);
+        i
+    }
+
+    /// Copies the OAM DMA bytes that are due, the transfer takes 160 M-cycles in all
+    fn run_oam_dma(&mut self, cycles: u32) {
+        let Some(mut dma) = self.oam_dma.take() else { return };
+        dma.cycles += cycles;
+        while dma.cycles >= 4 && dma.index < 0xA0 {
+            let b = self.read_unchecked(dma.src + dma.index);
+            self.trace(dma.src + dma.index, b, false);
+            self.gpu.set(0xFE00 + dma.index, b);
+            self.trace(0xFE00 + dma.index, b, true);
+            dma.index += 1;
+            dma.cycles -= 4;
+        }
+        if dma.index < 0xA0 {
+            self.oam_dma = Some(dma);
+        }
+    }
+
+    /// Whether the CPU sees the in-transit OAM DMA byte instead of a at the moment
+    /// * Only the rom, vram, ram and OAM buses are taken, so IO, HRAM and IE stay reachable
+    fn dma_conflict(&self, a: u16) -> Option<u8> {
+        let dma = self.oam_dma.as_ref()?;
+        match a {
+            0x0000..=0xFEFF => Some(self.read_unchecked(dma.src + dma.index)),
+            _ => None,
+        }
+    }
+
     fn run_dma(&mut self) -> u32 {
         if !self.hdma.active { return 0; }
         match self.hdma.mode {
@@ -196,14 +473,40 @@ impl Memory for Mmunit {
     // $FF70		    CGB	WRAM Bank Select
 
     fn get(&self, a: u16) -> u8 {
+        self.check_strict(a, false);
+        let v = self.dma_conflict(a).unwrap_or_else(|| self.read(a));
+        self.trace(a, v, false);
+        self.diagnose(a, v, false);
+        v
+    }
+
+    fn set(&mut self, a: u16, v: u8) {
+        self.writes += 1;
+        self.check_strict(a, true);
+        self.trace(a, v, true);
+        self.diagnose(a, v, true);
+        self.write(a, v);
+    }
+}
+
+impl Mmunit {
+    fn read(&self, a: u16) -> u8 {
+        if self.ppu_blocked(a) {
+            return 0xFF;
+        }
+        self.read_unchecked(a)
+    }
+
+    /// Reads the bus without the PPU lock, DMA has its own path to VRAM and OAM
+    fn read_unchecked(&self, a: u16) -> u8 {
+        if let Some(v) = self.boot_rom_byte(a) {
+            return v;
+        }
         match a {
             0x0000..=0x7FFF => self.cartridge.get(a),
             0x8000..=0x9FFF => self.gpu.get(a),
             0xA000..=0xBFFF => self.cartridge.get(a),
-            0xC000..=0xCFFF => self.wram[a as usize - 0xC000],
-            0xD000..=0xDFFF => self.wram[a as usize - 0xD000 + 0x1000 * self.wram_bank],
-            0xE000..=0xEFFF => self.wram[a as usize - 0xE000],
-            0xF000..=0xFDFF => self.wram[a as usize - 0xF000 + 0x1000 * self.wram_bank],
+            0xC000..=0xFDFF => self.wram[self.wram_index(a)],
             0xFE00..=0xFE9F => self.gpu.get(a),
             0xFEA0..=0xFEFF => 0x00,
             0xFF00 => self.joypad.get(a),
@@ -211,58 +514,255 @@ impl Memory for Mmunit {
             0xFF04..=0xFF07 => self.time.get(a),
             0xFF0F => self.intf.borrow().data,
             0xFF10..=0xFF3F => self.apu.get(a),
-            0xFF4D => {
+            // KEY1, bit 7 is the current speed, bit 0 is armed, the rest read high
+            0xFF4D if self.term == Term::GBC => {
                 let a = if self.speed == Speed::Double { 0x80 } else { 0x00 };
                 let b = if self.shift { 0x01 } else { 0x00 };
-                a | b
+                0x7E | a | b
             }
             0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F => self.gpu.get(a),
             0xFF51..=0xFF55 => self.hdma.get(a),
             0xFF68..=0xFF6B => self.gpu.get(a),
-            0xFF70 => self.wram_bank as u8,
+            0xFF70 if self.term == Term::GBC => 0xF8 | self.wram_bank as u8,
             0xFF80..=0xFFFE => self.hram[a as usize - 0xFF80],
+            // All 8 bits of IE read back as written, only the low 5 select interrupts
             0xFFFF => self.inte,
-            _ => 0x00
+            0xFF46 => self.dma,
+            // Undocumented CGB registers, FF72-FF74 are plain bytes and only bits 4-6 of FF75 exist
+            0xFF72..=0xFF74 if self.term == Term::GBC => self.undocumented[a as usize - 0xFF72],
+            0xFF75 if self.term == Term::GBC => 0x8F | self.undocumented[3],
+            // PCM12/PCM34 show the channel outputs, the mixer doesn't expose them so they read silent
+            0xFF76..=0xFF77 if self.term == Term::GBC => 0x00,
+            // Unmapped I/O floats high on every model
+            _ => 0xFF
         }
     }
 
-    fn set(&mut self, a: u16, v: u8) {
+    fn write(&mut self, a: u16, v: u8) {
+        if self.ppu_blocked(a) {
+            return;
+        }
         match a {
             0x0000..=0x7FFF => self.cartridge.set(a, v),
             0x8000..=0x9FFF => self.gpu.set(a, v),
             0xA000..=0xBFFF => self.cartridge.set(a, v),
-            0xC000..=0xCFFF => self.wram[a as usize - 0xC000] = v,
-            0xD000..=0xDFFF => self.wram[a as usize - 0xD000 + 0x1000 * self.wram_bank] = v,
-            0xE000..=0xEFFF => self.wram[a as usize - 0xE000] = v,
-            0xF000..=0xFDFF => self.wram[a as usize - 0xF000 + 0x1000 * self.wram_bank] = v,
+            0xC000..=0xFDFF => {
+                let i = self.wram_index(a);
+                self.wram[i] = v;
+            }
             0xFE00..=0xFE9F => self.gpu.set(a, v),
             0xFEA0..=0xFEFF => {}
             0xFF00 => self.joypad.set(a, v),
             0xFF01..=0xFF02 => self.serial.set(a, v),
-            0xFF04..=0xFF07 => self.time.set(a, v),
+            0xFF04 => {
+                // DIV-APU clocks on the falling edge of DIV bit 4, bit 5 in double speed
+                let bit = if self.speed == Speed::Double { 0x20 } else { 0x10 };
+                let edge = self.time.get(0xFF04) & bit != 0x00;
+                self.time.set(a, v);
+                self.apu.div_reset(edge);
+            }
+            0xFF05..=0xFF07 => self.time.set(a, v),
             0xFF10..=0xFF3F => self.apu.set(a, v),
             0xFF46 => {
                 assert!(v <= 0xF1);
-                let base = u16::from(v) << 8;
-                for i in 0..0xA0 {
-                    let b = self.get(base + i);
-                    self.set(0xFE00 + i, b);
-                }
+                self.dma = v;
+                self.oam_dma = Some(OamDma { src: u16::from(v) << 8, index: 0, cycles: 0 });
             }
-            0xFF4D => self.shift = (v & 0x01) == 0x01,
+            0xFF4D if self.term == Term::GBC => self.shift = (v & 0x01) == 0x01,
             0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F => self.gpu.set(a, v),
             0xFF51..=0xFF55 => self.hdma.set(a, v),
             0xFF68..=0xFF6B => self.gpu.set(a, v),
             0xFF0F => self.intf.borrow_mut().data = v,
-            0xFF70 => {
+            0xFF50 if v != 0x00 => self.boot_rom = None,
+            0xFF70 if self.term == Term::GBC => {
                 self.wram_bank = match v & 0x7 {
                     0 => 1,
                     n => n as usize,
                 };
             }
+            0xFF72..=0xFF74 if self.term == Term::GBC => self.undocumented[a as usize - 0xFF72] = v,
+            0xFF75 if self.term == Term::GBC => self.undocumented[3] = v & 0x70,
             0xFF80..=0xFFFE => self.hram[a as usize - 0xFF80] = v,
             0xFFFF => self.inte = v,
             _ => {}
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_roms;
+
+    fn mmu() -> Mmunit {
+        Mmunit::power_up_from_bytes(test_roms::assemble("TEST", &[]))
+    }
+
+    /// Runs the PPU a dot group at a time until it reaches the mode
+    fn run_to_mode(mmu: &mut Mmunit, mode: u8) {
+        for _ in 0..100_000 {
+            if mmu.gpu.mode() == mode {
+                return;
+            }
+            mmu.next(4);
+        }
+        panic!("PPU never reached mode {}", mode);
+    }
+
+    #[test]
+    fn strict_vram_timing_blocks_mode_3_reads() {
+        let mut mmu = mmu();
+        mmu.set(0x8000, 0x5A);
+        mmu.set_strict_vram_timing(true);
+        run_to_mode(&mut mmu, 3);
+        assert_eq!(mmu.get(0x8000), 0xFF);
+        run_to_mode(&mut mmu, 0);
+        assert_eq!(mmu.get(0x8000), 0x5A);
+    }
+
+    #[test]
+    fn fake_boot_leaves_the_dmg_io_registers() {
+        let mmu = mmu();
+        // (address, value written by the boot rom), unused bits left out
+        let table = [
+            (0xFF05, 0x00), (0xFF06, 0x00), (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF16, 0x3F),
+            (0xFF17, 0x00), (0xFF1A, 0x7F), (0xFF1C, 0x9F), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF24, 0x77),
+            (0xFF25, 0xF3), (0xFF26, 0xF1), (0xFF40, 0x91), (0xFF42, 0x00), (0xFF43, 0x00), (0xFF45, 0x00),
+            (0xFF47, 0xFC), (0xFF4A, 0x00), (0xFF4B, 0x00), (0xFFFF, 0x00),
+        ];
+        let mismatches: Vec<String> = table
+            .iter()
+            .filter(|&&(a, v)| mmu.get(a) != v)
+            .map(|&(a, v)| format!("{:04x}: {:02x} != {:02x}", a, mmu.get(a), v))
+            .collect();
+        assert!(mismatches.is_empty(), "{:?}", mismatches);
+        assert_eq!(mmu.get(0xFF07) & 0x07, 0x00);
+        assert_eq!(mmu.get(0xFF0F) & 0x1F, 0x01);
+        let wave: Vec<u8> = (0xFF30..=0xFF3F).map(|a| mmu.get(a)).collect();
+        assert_eq!(wave, wave_ram_at_power_up(mmu.term));
+    }
+
+    #[test]
+    fn hexdump_shows_hex_and_ascii_rows() {
+        let mut mmu = mmu();
+        for (i, &b) in b"Hello, GB!\x00\x7F\xFF".iter().enumerate() {
+            mmu.set(0xC000 + i as u16, b);
+        }
+        let dump = mmu.hexdump(0xC000, 18);
+        assert_eq!(
+            dump,
+            "C000  48 65 6C 6C 6F 2C 20 47 42 21 00 7F FF 00 00 00  |Hello, GB!......|\n\
+             C010  00 00                                            |..|\n"
+        );
+        // Stops at the end of the address space
+        assert_eq!(mmu.hexdump(0xFFFE, 16).lines().count(), 1);
+    }
+
+    #[test]
+    fn unmapped_io_reads_0xff() {
+        let mut mmu = mmu();
+        let unmapped = [0xFF03, 0xFF4C, 0xFF4D, 0xFF4E, 0xFF70, 0xFF71]
+            .into_iter()
+            .chain(0xFF08..=0xFF0E)
+            .chain(0xFF57..=0xFF67)
+            .chain(0xFF72..=0xFF7F);
+        for a in unmapped {
+            mmu.set(a, 0x00);
+            assert_eq!(mmu.get(a), 0xFF, "{:04x}", a);
+        }
+    }
+
+    /// Dots of real time an internal clock serial transfer takes at the given speed
+    fn serial_transfer_dots(speed: Speed) -> u32 {
+        let mut rom = test_roms::assemble("SERIAL", &[]);
+        rom[0x0143] = 0x80;
+        rom[0x014D] = test_roms::header_checksum(&rom);
+        let mut mmu = Mmunit::power_up_from_bytes(rom);
+        mmu.speed = speed;
+        mmu.set(0xFF02, 0x81);
+        let mut dots = 0;
+        while mmu.get(0xFF02) & 0x80 != 0x00 {
+            dots += mmu.next(4);
+        }
+        dots
+    }
+
+    #[test]
+    fn serial_transfers_take_half_the_time_in_double_speed() {
+        // 8 bits at 8192Hz
+        assert_eq!(serial_transfer_dots(Speed::Normal), 4096);
+        assert_eq!(serial_transfer_dots(Speed::Double), 2048);
+    }
+
+    #[test]
+    fn oam_dma_blocks_wram_but_not_hram() {
+        let mut mmu = mmu();
+        mmu.set(0xC100, 0x11);
+        mmu.set(0xC000, 0x22);
+        mmu.set(0xFF90, 0x33);
+        mmu.set(0xFF46, 0xC1);
+        // WRAM reads see the byte the DMA is moving, HRAM is on its own bus
+        assert_eq!(mmu.get(0xC000), 0x11);
+        assert_eq!(mmu.get(0xFF90), 0x33);
+        // 160 bytes at 4 cycles each
+        mmu.next(640);
+        assert_eq!(mmu.get(0xC000), 0x22);
+        assert_eq!(mmu.gpu.get(0xFE00), 0x11);
+    }
+
+    #[test]
+    fn ie_reads_back_all_eight_bits() {
+        let mut mmu = mmu();
+        mmu.set(0xFFFF, 0xFF);
+        assert_eq!(mmu.get(0xFFFF), 0xFF);
+        mmu.set(0xFFFF, 0xE0);
+        assert_eq!(mmu.get(0xFFFF), 0xE0);
+    }
+
+    #[test]
+    fn wave_ram_power_up_pattern_per_term() {
+        let dmg = [0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34, 0xB8, 0x2E, 0xDA];
+        let cgb = [0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF];
+        for (term, name, expected) in [
+            (Term::DMG0, "DMG0", dmg),
+            (Term::GB, "GB", dmg),
+            (Term::GBP, "GBP", dmg),
+            (Term::SGB, "SGB", dmg),
+            (Term::GBC, "GBC", cgb),
+        ] {
+            let cart = cartridge::power_up_from_bytes(test_roms::assemble("WAVE", &[]), "");
+            let mmu = Mmunit::with_cartridge(cart, term);
+            let wave: Vec<u8> = (0xFF30..=0xFF3F).map(|a| mmu.get(a)).collect();
+            assert_eq!(wave, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn diagnostics_log_unusable_area_writes_only() {
+        let mut mmu = mmu();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let sink = log.clone();
+        mmu.set_diagnostics(Some(Box::new(move |d: Diagnostic| sink.borrow_mut().push((d.pc, d.access.addr, d.access.value, d.access.write)))));
+        mmu.set_pc(0x0150);
+        mmu.set(0xC000, 0x12);
+        assert!(log.borrow().is_empty());
+        mmu.set_pc(0x0153);
+        mmu.set(0xFEA0, 0x34);
+        assert_eq!(*log.borrow(), [(0x0153, 0xFEA0, 0x34, true)]);
+    }
+
+    #[test]
+    fn oam_dma_reads_vram_past_the_ppu_lock() {
+        let mut mmu = mmu();
+        for i in 0..0xA0 {
+            mmu.gpu.set(0x8000 + i, i as u8);
+        }
+        mmu.set_strict(true);
+        mmu.set_strict_vram_timing(true);
+        run_to_mode(&mut mmu, 3);
+        mmu.set(0xFF46, 0x80);
+        mmu.next(640);
+        let oam: Vec<u8> = (0..0xA0).map(|i| mmu.gpu.get(0xFE00 + i)).collect();
+        assert_eq!(oam, (0..0xA0).map(|i| i as u8).collect::<Vec<u8>>());
+    }
+}
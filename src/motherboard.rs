@@ -1,34 +1,748 @@
-use super::cpu::RTC;
+use super::cartridge::{Cartridge, LoadOptions, NoCartridge};
+use super::cpu::{Profile, RTC};
+use super::error::{EmuError, LoadWarning};
+use super::gbs::{Gbs, GbsPlayer};
+use super::gpu::{Border, PixelFormat, FRAME_CYCLES, SCREEN_H, SCREEN_W};
+use super::joypad::Key;
 use super::mem::Memory;
 use super::mmunit::Mmunit;
+use super::terms::Term;
 use std::cell::RefCell;
-use std::path::Path;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Instructions spent on one PC without a write before a hang is reported, what the frontend passes to set_hang_threshold
+pub const HANG_THRESHOLD: u32 = 100_000;
+
+/// Something the frontend may want to tell the user about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoardEvent {
+    /// The CPU sits on this PC writing nothing with no interrupt able to get it out
+    HangDetected(u16),
+}
+
+/// Watches for a CPU stuck on one instruction, like JR -2 with interrupts off
+struct HangDetector {
+    threshold: u32,
+    pc: u16,
+    writes: u64,
+    steps: u32,
+}
+
+/// What a headless benchmark run measured
+pub struct BenchmarkStats {
+    pub frames: u32,
+    pub elapsed: Duration,
+    /// Instructions run during the benchmark
+    pub steps: u64,
+    pub profile: Profile,
+}
+
+impl BenchmarkStats {
+    pub fn fps(&self) -> f64 {
+        f64::from(self.frames) / self.elapsed.as_secs_f64()
+    }
+
+    pub fn ns_per_instruction(&self) -> f64 {
+        self.elapsed.as_nanos() as f64 / self.steps.max(1) as f64
+    }
+}
 
 pub struct MotherBoard {
     pub mmu: Rc<RefCell<Mmunit>>,
     pub cpu: RTC,
+    pub gbs: Option<GbsPlayer>,
+    border: Option<Border>,
+    total_cycles: u64,
+    total_steps: u64,
+    frame_carry: u32,
+    hang: HangDetector,
+    event: Option<BoardEvent>,
 }
 
 impl MotherBoard {
     pub fn power_up(path: impl AsRef<Path>) -> Self {
-        let mmu = Rc::new(RefCell::new(Mmunit::power_up(path)));
-        let cpu = RTC::power_up(mmu.borrow().term, mmu.clone());
-        Self { mmu, cpu }
+        Self::from_mmunit(Mmunit::power_up(path))
     }
 
-    pub fn next(&mut self) -> u32 {
-        if self.mmu.borrow().get(self.cpu.cpu.reg.program_counter) == 0x10 {
-            self.mmu.borrow_mut().switch_speed();
+    /// Like power_up but returns missing files, corrupt roms and unsupported mappers as errors
+    pub fn try_power_up(path: impl AsRef<Path>) -> Result<Self, EmuError> {
+        Ok(Self::from_mmunit(Mmunit::try_power_up(path)?))
+    }
+
+    /// Like try_power_up with the given load options, also returns the warnings for the frontend to show
+    pub fn try_power_up_with(path: impl AsRef<Path>, options: LoadOptions) -> Result<(Self, Vec<LoadWarning>), EmuError> {
+        let (mmu, warnings) = Mmunit::try_power_up_with(path, options)?;
+        Ok((Self::from_mmunit(mmu), warnings))
+    }
+
+    /// Powers up the MotherBoard around an already built cartridge, no file is touched
+    /// * Pairs with `cartridge::power_up_from_bytes` or a hand made cartridge in tests and tools
+    pub fn with_cartridge(cart: Box<dyn Cartridge>, term: Term) -> Self {
+        Self::from_mmunit(Mmunit::with_cartridge(cart, term))
+    }
+
+    /// Powers up with an empty cartridge slot, the whole rom area reads 0xFF
+    /// * Load code into WRAM or HRAM through mmu and point PC at it, or run a boot rom with set_boot_rom
+    pub fn power_up_bare(term: Term) -> Self {
+        Self::with_cartridge(Box::new(NoCartridge), term)
+    }
+
+    fn from_mmunit(mmu: Mmunit) -> Self {
+        let term = mmu.term;
+        let mmu = Rc::new(RefCell::new(mmu));
+        let cpu = RTC::power_up(term, mmu.clone());
+        Self { mmu, cpu, gbs: None, border: None, total_cycles: 0, total_steps: 0, frame_carry: 0,
+            hang: HangDetector { threshold: 0, pc: 0, writes: 0, steps: 0 }, event: None }
+    }
+
+    /// Powers up the MotherBoard as the given hardware revision
+    /// * Picks the post boot registers and I/O defaults of that revision, e.g. Term::DMG0 for early test roms
+    pub fn power_up_as(path: impl AsRef<Path>, term: Term) -> Self {
+        Self::from_mmunit(Mmunit::power_up_as(path, term))
+    }
+
+    /// Powers up the MotherBoard from a rom already in memory
+    /// * Nothing is read from or saved to disk, handy for embedding and headless runs
+    pub fn power_up_from_bytes(rom: Vec<u8>) -> Self {
+        Self::from_mmunit(Mmunit::power_up_from_bytes(rom))
+    }
+
+    /// Powers up the MotherBoard to play a .gbs music rip instead of a game
+    pub fn load_gbs(path: impl AsRef<Path>) -> Result<Self, EmuError> {
+        Ok(Self::with_gbs(Gbs::load(path)?))
+    }
+
+    /// Starts the first song of an already loaded gbs rip
+    fn with_gbs(cart: Gbs) -> Self {
+        let player = GbsPlayer::power_up(&cart.header);
+        let (tma, tac) = (cart.header.tma, cart.header.tac);
+        let mut motherboard = Self::with_cartridge(Box::new(cart), Term::GB);
+        motherboard.gbs = Some(player);
+        {
+            let mut mmu = motherboard.mmu.borrow_mut();
+            // Music drivers are called directly so interrupts would only jump into empty vectors
+            mmu.set(0xFFFF, 0x00);
+            mmu.set(0xFF06, tma);
+            mmu.set(0xFF07, tac);
         }
+        let song = motherboard.gbs.as_ref().unwrap().song;
+        motherboard.gbs_select(song);
+        motherboard
+    }
+
+    /// Restarts the gbs player on the given song, wrapping around the song count
+    /// * Pressing Left or Right on the joypad does the same for the song before or after
+    pub fn gbs_select(&mut self, song: u8) {
+        let Some(player) = self.gbs.as_mut() else { return };
+        player.song = song % player.song_count.max(1);
+        let (init, sp) = player.init_call();
+        self.cpu.cpu.reg = Default::default();
+        self.cpu.cpu.halted = false;
+        self.cpu.cpu.reg.a_reg = player.song;
+        self.cpu.cpu.reg.stack_pointer = sp;
+        self.gbs_call(init);
+    }
+
+    /// Calls a routine of the gbs rip which returns into the idle loop
+    fn gbs_call(&mut self, addr: u16) {
+        let reg = &mut self.cpu.cpu.reg;
+        reg.stack_pointer = reg.stack_pointer.wrapping_sub(2);
+        self.mmu.borrow_mut().set_word(reg.stack_pointer, super::gbs::IDLE_ADDR);
+        reg.program_counter = addr;
+    }
+
+    pub fn next(&mut self) -> u32 {
+        self.mmu.borrow_mut().set_pc(self.cpu.cpu.reg.program_counter);
         let cycles = self.cpu.next();
-        self.mmu.borrow_mut().next(cycles);
+        self.advance(cycles)
+    }
+
+    /// Moves everything but the CPU on by the cycles of the instruction that just ran
+    fn advance(&mut self, cycles: u32) -> u32 {
+        self.total_cycles += u64::from(cycles);
+        self.total_steps += 1;
+        {
+            let mut mmu = self.mmu.borrow_mut();
+            if self.cpu.cpu.take_stop() {
+                mmu.switch_speed();
+            }
+            mmu.next(cycles);
+        }
+        if let Some(play) = self.gbs.as_mut().and_then(|p| p.next(cycles, self.cpu.cpu.reg.program_counter)) {
+            // The joypad is read once per play call, Left and Right switch songs
+            let song = self.gbs.as_mut().and_then(|p| p.poll(&self.mmu.borrow().joypad));
+            match song {
+                Some(song) => self.gbs_select(song),
+                None => self.gbs_call(play),
+            }
+        }
+        self.check_hang();
         cycles
     }
 
+    /// Counts instructions that leave PC and memory unchanged, HALT waiting for an interrupt doesn't count
+    /// * With IME on and an interrupt enabled the loop may be waiting on purpose, so it isn't a hang
+    fn check_hang(&mut self) {
+        if self.hang.threshold == 0 {
+            return;
+        }
+        let pc = self.cpu.cpu.reg.program_counter;
+        let (writes, inte) = {
+            let mmu = self.mmu.borrow();
+            (mmu.write_count(), mmu.peek(0xFFFF))
+        };
+        let rescuable = self.cpu.cpu.ei && inte & 0x1F != 0x00;
+        if self.cpu.cpu.halted || rescuable || pc != self.hang.pc || writes != self.hang.writes {
+            self.hang.pc = pc;
+            self.hang.writes = writes;
+            self.hang.steps = 0;
+            return;
+        }
+        self.hang.steps = self.hang.steps.saturating_add(1);
+        if self.hang.steps == self.hang.threshold {
+            self.event = Some(BoardEvent::HangDetected(pc));
+        }
+    }
+
+    /// Instructions on one PC before HangDetected is raised, 0 turns detection off
+    /// * Off by default, the check runs after every instruction so headless runs and benchmarks don't pay for it
+    pub fn set_hang_threshold(&mut self, threshold: u32) {
+        self.hang.threshold = threshold;
+    }
+
+    /// Event raised since the last call, a hang is reported once until the CPU moves on
+    pub fn take_event(&mut self) -> Option<BoardEvent> {
+        self.event.take()
+    }
+
     pub fn check_reset_gpu(&mut self) -> bool {
         let result = self.mmu.borrow().gpu.v_blank;
         self.mmu.borrow_mut().gpu.v_blank = false;
         result
     }
-}
\ No newline at end of file
+
+    /// CPU cycles run since power up
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    /// Instructions run since power up, a halted CPU counts one per idle step
+    pub fn total_steps(&self) -> u64 {
+        self.total_steps
+    }
+
+    /// Runs one frame worth of cycles, FRAME_CYCLES at normal speed
+    /// * Instructions don't end exactly on the boundary, the overshoot is taken off the next frame
+    pub fn frame(&mut self) {
+        let budget = FRAME_CYCLES - self.frame_carry;
+        let mut cycles = 0;
+        while cycles < budget {
+            cycles += self.next() / self.mmu.borrow().speed as u32;
+        }
+        self.frame_carry = (cycles - budget).min(FRAME_CYCLES - 1);
+    }
+
+    /// Runs the given number of frames unthrottled with profiling on and times them
+    /// * Leaves throttling off and profiling on afterwards
+    pub fn benchmark(&mut self, frames: u32) -> BenchmarkStats {
+        self.cpu.set_throttle(false);
+        self.cpu.cpu.set_profiling(true);
+        let steps = self.total_steps;
+        let start = Instant::now();
+        for _ in 0..frames {
+            self.frame();
+        }
+        let elapsed = start.elapsed();
+        BenchmarkStats { frames, elapsed, steps: self.total_steps - steps, profile: self.cpu.cpu.take_profile() }
+    }
+
+    /// Runs through exactly n v-blanks and returns the screen as RGBA, for golden image tests
+    /// * Steps the Cpu directly so it never sleeps, whatever the throttle is set to
+    /// * Gives up after twice the cycles n frames should take, so a rom keeping the LCD off can't hang it
+    pub fn run_frames(&mut self, n: u32) -> Vec<u8> {
+        let target = self.mmu.borrow().gpu.frame_count() + u64::from(n);
+        let limit = u64::from(n) * u64::from(FRAME_CYCLES) * 2;
+        let mut cycles = 0u64;
+        while self.mmu.borrow().gpu.frame_count() < target && cycles < limit {
+            self.mmu.borrow_mut().set_pc(self.cpu.cpu.reg.program_counter);
+            let step = self.cpu.cpu.next();
+            cycles += u64::from(self.advance(step) / self.mmu.borrow().speed as u32);
+        }
+        self.framebuffer_rgba()
+    }
+
+    /// Holds a key down for the given number of frames, then releases it
+    pub fn tap(&mut self, key: Key, frames: u32) {
+        self.mmu.borrow_mut().joypad.keydown(key);
+        for _ in 0..frames {
+            self.frame();
+        }
+        self.mmu.borrow_mut().joypad.keyup(key);
+    }
+
+    /// Current screen as tightly packed RGBA bytes, row by row
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        let mut out = vec![0x00; SCREEN_W * SCREEN_H * PixelFormat::Rgba8888.bytes_per_pixel()];
+        self.mmu.borrow().gpu.write_framebuffer(&mut out, PixelFormat::Rgba8888);
+        out
+    }
+
+    /// Panics on undefined opcodes and on memory accesses no working program makes, off by default
+    /// * Left off an undefined opcode locks the CPU like on hardware
+    pub fn set_strict(&mut self, strict: bool) {
+        self.cpu.cpu.set_strict(strict);
+        self.mmu.borrow_mut().set_strict(strict);
+    }
+
+    /// Battery ram, plus the clock epoch on MBC3 timer carts, for save slots kept by the frontend
+    /// * Empty for cartridges without a battery
+    pub fn export_sram(&self) -> Vec<u8> {
+        let mmu = self.mmu.borrow();
+        if !mmu.cartridge.has_battery() {
+            return Vec::new();
+        }
+        mmu.cartridge.sram()
+    }
+
+    /// Restores bytes from export_sram, ignored for cartridges without a battery
+    pub fn import_sram(&mut self, data: &[u8]) {
+        let mut mmu = self.mmu.borrow_mut();
+        if mmu.cartridge.has_battery() {
+            mmu.cartridge.set_sram(data);
+        }
+    }
+
+    /// Reloads battery ram from the .sav (and .rtc) next to the rom into the running cartridge
+    /// * Returns false without touching anything for cartridges without a battery
+    /// * An .rtc that isn't exactly the 8 byte clock epoch is a BadRom error, nothing is reloaded
+    pub fn reload_sram(&mut self, rom: impl AsRef<Path>) -> Result<bool, EmuError> {
+        let current = self.export_sram();
+        if current.is_empty() {
+            return Ok(false);
+        }
+        let read = |path: PathBuf| std::fs::read(&path).map_err(|source| EmuError::Io { path, source });
+        let clock = self.mmu.borrow().cartridge.get_rtc().is_some();
+        let ram_len = current.len() - if clock { 8 } else { 0 };
+        let mut data = if ram_len == 0 { Vec::new() } else { read(rom.as_ref().with_extension("sav"))? };
+        if clock {
+            // A missing .rtc keeps the running clock
+            let path = rom.as_ref().with_extension("rtc");
+            match std::fs::read(&path) {
+                Ok(rtc) if rtc.len() == 8 => data.extend_from_slice(&rtc),
+                Ok(rtc) => return Err(EmuError::BadRom { path, reason: format!("Clock file is {} bytes, expected 8", rtc.len()) }),
+                Err(_) => data.extend_from_slice(&current[current.len() - 8..]),
+            }
+        }
+        self.import_sram(&data);
+        Ok(true)
+    }
+
+    /// Runs a boot rom image from 0x0000 before the cartridge, it hands over by writing FF50 like on hardware
+    /// * Registers keep their post boot values until the boot rom sets them
+    pub fn set_boot_rom(&mut self, boot: Vec<u8>) {
+        self.mmu.borrow_mut().set_boot_rom(boot);
+        self.cpu.cpu.reg.program_counter = 0x0000;
+    }
+
+    /// Sets the picture the frontend draws around the screen from width * height RGBA pixels
+    pub fn set_border(&mut self, rgba: &[u8], width: usize, height: usize) {
+        self.border = Some(Border::power_up(rgba, width, height));
+    }
+
+    pub fn border(&self) -> Option<&Border> {
+        self.border.as_ref()
+    }
+
+    /// Writes the current screen to a PNG file
+    pub fn screenshot(&self, path: impl AsRef<Path>) {
+        let file = File::create(path.as_ref()).unwrap();
+        let mut encoder = png::Encoder::new(BufWriter::new(file), SCREEN_W as u32, SCREEN_H as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&self.framebuffer_rgba()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gbs::GbsHeader;
+
+    #[test]
+    fn gbs_init_runs_once() {
+        let mut data = vec![0x00; GbsHeader::SIZE];
+        data[0..6].copy_from_slice(b"GBS\x01\x01\x01");
+        data[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&0x0405u16.to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0xFFFEu16.to_le_bytes());
+        data.extend_from_slice(&[
+            0x21, 0x00, 0xC0, // init: ld hl, 0xC000
+            0x34,             // inc (hl)
+            0xC9,             // ret
+            0xC9,             // play: ret
+        ]);
+        let header = GbsHeader::parse(&data, "").unwrap();
+        assert_eq!((header.load, header.init, header.play), (0x0400, 0x0400, 0x0405));
+        let mut motherboard = MotherBoard::with_gbs(Gbs::power_up(&data, "").unwrap());
+        for _ in 0..3 {
+            motherboard.frame();
+        }
+        assert_eq!(motherboard.mmu.borrow().peek(0xC000), 0x01);
+    }
+
+    #[test]
+    fn joypad_left_and_right_switch_gbs_songs() {
+        let mut data = vec![0x00; GbsHeader::SIZE];
+        data[0..6].copy_from_slice(b"GBS\x01\x03\x01");
+        data[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&0x0404u16.to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0xFFFEu16.to_le_bytes());
+        data.extend_from_slice(&[
+            0xEA, 0x00, 0xC0, // init: ld (0xC000), a
+            0xC9,             // ret
+            0xC9,             // play: ret
+        ]);
+        let mut motherboard = MotherBoard::with_gbs(Gbs::power_up(&data, "").unwrap());
+        motherboard.cpu.set_throttle(false);
+        let mut press = |key: Key, frames: u32| {
+            motherboard.tap(key, frames);
+            motherboard.frame();
+            (motherboard.gbs.as_ref().unwrap().song, motherboard.mmu.borrow().peek(0xC000))
+        };
+        assert_eq!(press(Key::Right, 1), (1, 1));
+        // Held for a while it still moves one song
+        assert_eq!(press(Key::Right, 5), (2, 2));
+        assert_eq!(press(Key::Right, 1), (0, 0));
+        assert_eq!(press(Key::Left, 1), (2, 2));
+        assert_eq!(press(Key::Left, 1), (1, 1));
+    }
+
+    /// Test rom flagged as CGB enhanced so the board powers up as a GBC
+    fn cgb_rom(program: &[u8]) -> Vec<u8> {
+        let mut rom = crate::test_roms::assemble("CGB", program);
+        rom[0x0143] = 0x80;
+        rom[0x014D] = crate::test_roms::header_checksum(&rom);
+        rom
+    }
+
+    #[test]
+    fn stop_switches_speed_once_armed() {
+        let mut motherboard = MotherBoard::power_up_from_bytes(cgb_rom(&[
+            0x3E, 0x01, // ld a, 1
+            0xE0, 0x4D, // ldh (KEY1), a
+            0x10, 0x00, // stop
+            0x10, 0x00, // stop
+        ]));
+        assert_eq!(motherboard.mmu.borrow().peek(0xFF4D), 0x7E);
+        for _ in 0..4 {
+            motherboard.next();
+        }
+        assert_eq!(motherboard.mmu.borrow().peek(0xFF4D), 0x7F);
+        motherboard.next();
+        assert_eq!(motherboard.mmu.borrow().peek(0xFF4D), 0xFE);
+        motherboard.next();
+        assert_eq!(motherboard.mmu.borrow().peek(0xFF4D), 0xFE, "an unarmed STOP keeps the speed");
+    }
+
+    #[test]
+    fn frame_runs_frame_cycles() {
+        let mut motherboard = MotherBoard::power_up_from_bytes(crate::test_roms::assemble("FRAME", &[0x18, 0xFE]));
+        motherboard.cpu.set_throttle(false);
+        for n in 1..=10u64 {
+            motherboard.frame();
+            let expected = n * u64::from(FRAME_CYCLES);
+            // The overshoot of one instruction is taken off the next frame, so it never builds up
+            assert!((expected..expected + 24).contains(&motherboard.total_cycles()), "{} after {} frames", motherboard.total_cycles(), n);
+        }
+    }
+
+    #[test]
+    fn benchmark_reports_positive_fps() {
+        let mut motherboard = MotherBoard::power_up_from_bytes(crate::test_roms::assemble("BENCH", &[0x18, 0xFE]));
+        let stats = motherboard.benchmark(5);
+        assert_eq!(stats.frames, 5);
+        assert!(stats.fps() > 0.0);
+        assert!(stats.steps > 0);
+        // JR -2 is the only instruction the rom runs after the header jump
+        assert_eq!(stats.profile.opcodes[0].0, 0x18);
+    }
+
+    #[test]
+    fn vblank_interrupts_dispatch_mid_frame() {
+        let mut rom = crate::test_roms::assemble("VBLANK", &[
+            0x3E, 0x01, // ld a, 0x01
+            0xE0, 0xFF, // ldh (IE), a
+            0xAF,       // xor a
+            0xE0, 0x0F, // ldh (IF), a
+            0xFB,       // ei
+            0x18, 0xFE, // jr -2
+        ]);
+        // ld hl, 0xC000 / inc (hl) / reti
+        rom[0x0040..0x0045].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0xD9]);
+        let mut motherboard = MotherBoard::power_up_from_bytes(rom);
+        motherboard.cpu.set_throttle(false);
+        // The first frame may catch one more v-blank, depending on where the LCD was at power up
+        motherboard.frame();
+        let first = motherboard.mmu.borrow().get(0xC000);
+        assert!(first >= 1);
+        for _ in 0..9 {
+            motherboard.frame();
+        }
+        assert_eq!(motherboard.mmu.borrow().get(0xC000), first + 9);
+        assert_eq!(motherboard.mmu.borrow().get(0xFF0F) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn with_cartridge_runs_a_rom_from_memory() {
+        let rom = crate::test_roms::assemble("MEMORY", &[
+            0x3E, 0x42, // ld a, 0x42
+            0xEA, 0x00, 0xC0, // ld (0xC000), a
+            0x18, 0xFE, // jr -2
+        ]);
+        let cart = crate::cartridge::power_up_from_bytes(rom, "memory.gb");
+        let mut motherboard = MotherBoard::with_cartridge(cart, Term::GB);
+        motherboard.cpu.set_throttle(false);
+        motherboard.frame();
+        assert_eq!(motherboard.mmu.borrow().get(0xC000), 0x42);
+        assert!(motherboard.total_cycles() >= u64::from(FRAME_CYCLES));
+    }
+
+    #[test]
+    fn tap_holds_the_key_then_releases_it() {
+        let mut motherboard = MotherBoard::power_up_from_bytes(crate::test_roms::assemble("TAP", &[
+            0x3E, 0x10, // ld a, 0x10
+            0xE0, 0x00, // ldh (P1), a
+            0xF0, 0x00, // ldh a, (P1)
+            0xCB, 0x5F, // bit 3, a
+            0x20, 0xF6, // jr nz, -10
+            0x3E, 0x01, // ld a, 0x01
+            0xEA, 0x00, 0xC0, // ld (0xC000), a
+            0x18, 0xEF, // jr -17
+        ]));
+        motherboard.cpu.set_throttle(false);
+        // Let the rom select the buttons first, Start only pulls a line low while they are selected
+        motherboard.frame();
+        assert_eq!(motherboard.mmu.borrow().get(0xFF0F) & 0x10, 0x00);
+        let frames = motherboard.mmu.borrow().gpu.frame_count();
+        motherboard.tap(Key::Start, 2);
+        assert_eq!(motherboard.mmu.borrow().gpu.frame_count(), frames + 2);
+        assert_ne!(motherboard.mmu.borrow().get(0xFF0F) & 0x10, 0x00);
+        // The rom saw Start held while tap ran
+        assert_eq!(motherboard.mmu.borrow().get(0xC000), 0x01);
+        motherboard.mmu.borrow_mut().set(0xC000, 0x00);
+        motherboard.frame();
+        assert_eq!(motherboard.mmu.borrow().get(0xC000), 0x00);
+    }
+
+    #[test]
+    fn ld_a_hl_fetches_the_opcode_then_reads_hl() {
+        let mut motherboard = MotherBoard::power_up_from_bytes(crate::test_roms::assemble("TRACE", &[
+            0x21, 0x00, 0xC0, // ld hl, 0xC000
+            0x7E,             // ld a, (hl)
+            0x18, 0xFE,       // jr -2
+        ]));
+        motherboard.cpu.set_throttle(false);
+        motherboard.mmu.borrow_mut().set(0xC000, 0x5A);
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let sink = trace.clone();
+        motherboard.mmu.borrow_mut().set_bus_tracer(Some(Box::new(move |access| sink.borrow_mut().push(access))));
+        // nop, jp 0x0150, ld hl and ld a, (hl)
+        for _ in 0..4 {
+            motherboard.next();
+        }
+        let trace = trace.borrow();
+        let fetch = trace.iter().position(|a| a.addr == 0x0153).unwrap();
+        let seen: Vec<(u16, u8, bool)> = trace[fetch..].iter().map(|a| (a.addr, a.value, a.write)).collect();
+        assert_eq!(seen, [(0x0153, 0x7E, false), (0xC000, 0x5A, false)]);
+        assert_eq!(motherboard.cpu.cpu.reg.a_reg, 0x5A);
+    }
+
+    /// FNV-1a, spelled out so the golden value doesn't move with the std hasher
+    fn fnv1a(data: &[u8]) -> u64 {
+        data.iter().fold(0xCBF2_9CE4_8422_2325, |h, &b| (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01B3))
+    }
+
+    #[test]
+    fn gradient_frame_hash_is_stable() {
+        let shot = |frames| MotherBoard::power_up_from_bytes(crate::test_roms::gradient()).run_frames(frames);
+        let image = shot(5);
+        assert_eq!(image.len(), SCREEN_W * SCREEN_H * 4);
+        // Every row is one color, the top band is lighter than the bottom one
+        let row = |y: usize| &image[y * SCREEN_W * 4..(y + 1) * SCREEN_W * 4];
+        for y in 0..SCREEN_H {
+            assert!(row(y).chunks(4).all(|p| p == &row(y)[..4]), "row {} isn't a single color", y);
+        }
+        assert!(row(0)[0] > row(SCREEN_H - 1)[0]);
+        // Same frames, same image, and a static picture doesn't change with more frames
+        assert_eq!(fnv1a(&image), fnv1a(&shot(5)));
+        assert_eq!(fnv1a(&image), fnv1a(&shot(8)));
+        assert_eq!(fnv1a(&image), 0xB3FE_5F1C_E2A9_9F25, "the gradient renders differently");
+    }
+
+    /// Test rom with the given cartridge type and ram size bytes
+    fn rom_with(kind: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = crate::test_roms::assemble("SRAM", &[0x18, 0xFE]);
+        rom[0x0147] = kind;
+        rom[0x0149] = ram_size;
+        rom[0x014D] = crate::test_roms::header_checksum(&rom);
+        rom
+    }
+
+    #[test]
+    fn export_then_import_sram_round_trips() {
+        let source = MotherBoard::power_up_from_bytes(rom_with(0x1B, 0x02));
+        source.mmu.borrow_mut().set(0x0000, 0x0A);
+        source.mmu.borrow_mut().set(0xA000, 0x42);
+        source.mmu.borrow_mut().set(0xBFFF, 0x99);
+        let saved = source.export_sram();
+        assert_eq!(saved.len(), 0x2000);
+
+        let mut target = MotherBoard::power_up_from_bytes(rom_with(0x1B, 0x02));
+        target.import_sram(&saved);
+        target.mmu.borrow_mut().set(0x0000, 0x0A);
+        assert_eq!(target.mmu.borrow().get(0xA000), 0x42);
+        assert_eq!(target.mmu.borrow().get(0xBFFF), 0x99);
+        assert_eq!(target.export_sram(), saved);
+
+        // No battery, nothing to export and an import is dropped
+        let mut plain = MotherBoard::power_up_from_bytes(rom_with(0x1A, 0x02));
+        assert!(plain.export_sram().is_empty());
+        plain.import_sram(&saved);
+        plain.mmu.borrow_mut().set(0x0000, 0x0A);
+        assert_eq!(plain.mmu.borrow().get(0xA000), 0x00);
+    }
+
+    #[test]
+    fn reload_sram_replaces_ram_from_the_sav() {
+        let rom = std::env::temp_dir().join(format!("oxidboy-{}-reload.gb", std::process::id()));
+        let sav = rom.with_extension("sav");
+        let mut ram = vec![0x00; 0x2000];
+        ram[0x0000] = 0x5A;
+        ram[0x1FFF] = 0xA5;
+        std::fs::write(&sav, &ram).unwrap();
+
+        let mut motherboard = MotherBoard::power_up_from_bytes(rom_with(0x1B, 0x02));
+        motherboard.mmu.borrow_mut().set(0x0000, 0x0A);
+        motherboard.mmu.borrow_mut().set(0xA000, 0x11);
+        motherboard.mmu.borrow_mut().set(0xA100, 0x22);
+        assert!(motherboard.reload_sram(&rom).unwrap());
+        assert_eq!(motherboard.mmu.borrow().get(0xA000), 0x5A);
+        assert_eq!(motherboard.mmu.borrow().get(0xA100), 0x00);
+        assert_eq!(motherboard.mmu.borrow().get(0xBFFF), 0xA5);
+        std::fs::remove_file(&sav).unwrap();
+
+        // A missing .sav is an error and leaves the ram alone
+        assert!(motherboard.reload_sram(&rom).is_err());
+        assert_eq!(motherboard.mmu.borrow().get(0xA000), 0x5A);
+        // No battery, nothing to reload
+        let mut plain = MotherBoard::power_up_from_bytes(rom_with(0x1A, 0x02));
+        assert!(!plain.reload_sram(&rom).unwrap());
+    }
+
+    #[test]
+    fn reload_sram_rejects_an_rtc_that_is_not_8_bytes() {
+        let rom = std::env::temp_dir().join(format!("oxidboy-{}-reload-rtc.gb", std::process::id()));
+        let sav = rom.with_extension("sav");
+        let rtc = rom.with_extension("rtc");
+        std::fs::write(&sav, vec![0x5A; 0x2000]).unwrap();
+        std::fs::write(&rtc, [0x00; 12]).unwrap();
+
+        let mut motherboard = MotherBoard::power_up_from_bytes(rom_with(0x10, 0x02));
+        let before = motherboard.export_sram();
+        match motherboard.reload_sram(&rom) {
+            Err(EmuError::BadRom { path, .. }) => assert_eq!(path, rtc),
+            other => panic!("expected a bad .rtc, got {:?}", other),
+        }
+        assert_eq!(motherboard.export_sram(), before);
+        std::fs::remove_file(&sav).unwrap();
+        std::fs::remove_file(&rtc).unwrap();
+    }
+
+    #[test]
+    fn battery_comes_from_the_header_whatever_bank_is_mapped() {
+        let mut rom = rom_with(0x03, 0x02);
+        rom[0x0148] = 0x05;
+        rom[0x014D] = crate::test_roms::header_checksum(&rom);
+        rom.resize(0x10_0000, 0x00);
+        let mut motherboard = MotherBoard::power_up_from_bytes(rom);
+        // Mode 1 with the upper bank bits at 1 puts bank 0x20, with no header, at 0x0000
+        motherboard.mmu.borrow_mut().set(0x6000, 0x01);
+        motherboard.mmu.borrow_mut().set(0x4000, 0x01);
+        assert_eq!(motherboard.mmu.borrow().get(0x0147), 0x00);
+        assert_eq!(motherboard.export_sram().len(), 0x2000);
+        motherboard.import_sram(&[0x42; 0x2000]);
+        motherboard.mmu.borrow_mut().set(0x0000, 0x0A);
+        motherboard.mmu.borrow_mut().set(0x6000, 0x00);
+        assert_eq!(motherboard.mmu.borrow().get(0xA000), 0x42);
+    }
+
+    #[test]
+    fn di_jr_loop_hangs_but_halt_for_vblank_does_not() {
+        let rom = crate::test_roms::assemble("HANG", &[
+            0xF3,       // di
+            0x18, 0xFE, // jr -2
+        ]);
+        // Nothing is reported until detection is turned on
+        let mut unwatched = MotherBoard::power_up_from_bytes(rom.clone());
+        unwatched.run_frames(1);
+        assert_eq!(unwatched.take_event(), None);
+
+        let mut stuck = MotherBoard::power_up_from_bytes(rom);
+        stuck.set_hang_threshold(1000);
+        stuck.run_frames(1);
+        assert_eq!(stuck.take_event(), Some(BoardEvent::HangDetected(0x0151)));
+        // Reported once
+        stuck.run_frames(1);
+        assert_eq!(stuck.take_event(), None);
+
+        for (name, program) in [
+            ("EI HALT", [0x3E, 0x01, 0xE0, 0xFF, 0xFB, 0x76, 0x18, 0xFD]), // ld a, 1 / ldh (IE), a / ei / halt / jr -3
+            ("DI HALT", [0x3E, 0x01, 0xE0, 0xFF, 0xF3, 0x76, 0x18, 0xFD]), // same with di, HALT still wakes on IF
+        ] {
+            let mut rom = crate::test_roms::assemble("WAIT", &program);
+            rom[0x0040] = 0xD9; // reti
+            let mut waiting = MotherBoard::power_up_from_bytes(rom);
+            waiting.set_hang_threshold(1000);
+            waiting.run_frames(5);
+            assert_eq!(waiting.take_event(), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn boot_rom_runs_until_it_unmaps_itself() {
+        let mut boot = vec![0x00; 0x0100];
+        boot[..5].copy_from_slice(&[
+            0x3E, 0x01,       // ld a, 1
+            0xC3, 0xFE, 0x00, // jp 0x00FE
+        ]);
+        boot[0xFE..].copy_from_slice(&[0xE0, 0x50]); // ldh (0xFF50), a
+        let mut motherboard = MotherBoard::power_up_bare(Term::GB);
+        motherboard.set_boot_rom(boot);
+        assert!(motherboard.mmu.borrow().boot_rom_mapped());
+        assert_eq!(motherboard.cpu.cpu.reg.program_counter, 0x0000);
+
+        for _ in 0..3 {
+            motherboard.next();
+        }
+        assert!(!motherboard.mmu.borrow().boot_rom_mapped());
+        assert_eq!(motherboard.cpu.cpu.reg.program_counter, 0x0100);
+    }
+
+    #[test]
+    fn cgb_boot_rom_leaves_the_header_visible() {
+        let mut boot = vec![0x11; 0x0900];
+        boot[0x0100..0x0200].fill(0x22);
+        let mut motherboard = MotherBoard::power_up_from_bytes(cgb_rom(&[0x18, 0xFE]));
+        motherboard.set_boot_rom(boot);
+        let mmu = motherboard.mmu.borrow();
+        assert_eq!((mmu.peek(0x00FF), mmu.peek(0x0104), mmu.peek(0x0200), mmu.peek(0x08FF)), (0x11, 0xCE, 0x11, 0x11));
+        assert_eq!(mmu.peek(0x0900), 0x00);
+    }
+}
@@ -107,23 +107,48 @@ impl Register {
     /// * Returns the state of Registers after the powerup sequence has been finished
     pub fn power_up(term: Term) -> Self {
         let mut registers = Self::default();
-        match term {
-            Term::GB => registers.a_reg =  0x01,
-            Term::GBP => registers.a_reg = 0xFF,
-            Term::GBC => registers.a_reg = 0x11,
-            Term::SGB => registers.a_reg = 0x01,
+        // A, F, B, C, D, E, H, L as left behind by each boot rom
+        let boot = match term {
+            Term::DMG0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            Term::GB => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Term::GBP => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Term::GBC => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Term::SGB => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
         };
 
-        registers.f_reg = 0xB0;
-        registers.b_reg = 0x00;
-        registers.c_reg = 0x13;
-        registers.d_reg = 0x00;
-        registers.e_reg = 0xD8;
-        registers.h_reg = 0x01;
-        registers.l_reg = 0x4D;
+        registers.a_reg = boot[0];
+        registers.f_reg = boot[1];
+        registers.b_reg = boot[2];
+        registers.c_reg = boot[3];
+        registers.d_reg = boot[4];
+        registers.e_reg = boot[5];
+        registers.h_reg = boot[6];
+        registers.l_reg = boot[7];
         registers.program_counter = 0x0100;
         registers.stack_pointer = 0xFFFE;
         registers
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_up_matches_each_boot_rom() {
+        // AF, BC, DE, HL per the Pan Docs power up sequence table
+        let expected = [
+            (Term::DMG0, [0x0100, 0xFF13, 0x00C1, 0x8403]),
+            (Term::GB, [0x01B0, 0x0013, 0x00D8, 0x014D]),
+            (Term::GBP, [0xFFB0, 0x0013, 0x00D8, 0x014D]),
+            (Term::GBC, [0x1180, 0x0000, 0xFF56, 0x000D]),
+            (Term::SGB, [0x0100, 0x0014, 0x0000, 0xC060]),
+        ];
+        for (term, [af, bc, de, hl]) in expected {
+            let r = Register::power_up(term);
+            assert_eq!([r.parse_af(), r.parse_bc(), r.parse_de(), r.parse_hl()], [af, bc, de, hl]);
+            assert_eq!((r.stack_pointer, r.program_counter), (0xFFFE, 0x0100));
+        }
+    }
+}
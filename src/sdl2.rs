@@ -1,4 +1,5 @@
-use sdl2::{render::{Canvas, Texture}, video::Window};
+use std::path::Path;
+use sdl2::{rect::Rect, render::{Canvas, Texture}, video::Window};
 
 // Render
 pub fn update_with_buffer(
@@ -17,4 +18,45 @@ pub fn update_with_buffer(
     canvas.present();
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// File the window position is remembered in, relative to the working directory like the assets
+pub const CONFIG_PATH: &str = "./OxidBoy.cfg";
+
+/// True if the top left corner of a window at x, y lands on one of the displays
+pub fn on_any_display(x: i32, y: i32, displays: &[Rect]) -> bool {
+    displays.iter().any(|d| d.contains_point((x, y)))
+}
+
+/// Reads window_x and window_y back from the config file, None if either is missing or unreadable
+pub fn load_window_position(path: impl AsRef<Path>) -> Option<(i32, i32)> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let get = |key: &str| text.lines().find_map(|l| l.strip_prefix(key)?.strip_prefix('=')?.trim().parse().ok());
+    Some((get("window_x")?, get("window_y")?))
+}
+
+/// Writes the window position into the config file, other lines in it are kept
+pub fn save_window_position(path: impl AsRef<Path>, x: i32, y: i32) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).unwrap_or_default();
+    let mut out: Vec<String> = text.lines().filter(|l| !l.starts_with("window_x=") && !l.starts_with("window_y=")).map(String::from).collect();
+    out.push(format!("window_x={}", x));
+    out.push(format!("window_y={}", y));
+    std::fs::write(path, out.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_position_must_land_on_a_display() {
+        // A 1920x1080 primary with a 1280x1024 display to its left
+        let displays = [Rect::new(0, 0, 1920, 1080), Rect::new(-1280, 0, 1280, 1024)];
+        assert!(on_any_display(100, 100, &displays));
+        assert!(on_any_display(-1280, 1023, &displays));
+        assert!(!on_any_display(-1280, 1030, &displays));
+        assert!(!on_any_display(1920, 0, &displays));
+        assert!(!on_any_display(0, 0, &[]));
+    }
+}
@@ -1,7 +1,22 @@
+/// Hardware revision the emulator powers up as
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Term {
+    /// Early DMG with the first boot rom revision
+    DMG0,
     GB,
+    /// Game Boy Pocket (MGB)
     GBP,
     GBC,
     SGB,
-}
\ No newline at end of file
+}
+
+impl Term {
+    /// Value DIV holds once the boot rom hands over to the cartridge
+    pub fn div(&self) -> u8 {
+        match self {
+            Term::DMG0 => 0x18,
+            Term::GB | Term::GBP => 0xAB,
+            Term::GBC | Term::SGB => 0x00,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use super::cartridge::NINTENDO_LOGO;
+
+/// Every generated rom is 32 KiB with no mapper
+const ROM_SIZE: usize = 0x8000;
+/// Where the program starts, right after the header
+const PROGRAM: usize = 0x0150;
+
+/// Wraps a program in a rom that passes the logo and header checksum checks
+/// * 0x0100 jumps to the program at 0x0150, the title is cut to 15 bytes
+pub fn assemble(title: &str, program: &[u8]) -> Vec<u8> {
+    assert!(program.len() <= ROM_SIZE - PROGRAM, "Program of {} bytes doesn't fit in the rom", program.len());
+    let mut rom = vec![0x00; ROM_SIZE];
+    // nop, jp 0x0150
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+    for (i, b) in title.bytes().take(15).enumerate() {
+        rom[0x0134 + i] = b;
+    }
+    rom[0x014D] = header_checksum(&rom);
+    rom[PROGRAM..PROGRAM + program.len()].copy_from_slice(program);
+    rom
+}
+
+/// Checksum of 0x0134-0x014C as the boot rom computes it
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..0x014D].iter().fold(0u8, |v, b| v.wrapping_sub(*b).wrapping_sub(1))
+}
+
+/// Sends text over serial on the internal clock one byte at a time, then loops forever
+/// * Read it back with Serial::set_capture and take_output
+pub fn serial_print(text: &[u8]) -> Vec<u8> {
+    let mut program = Vec::new();
+    for &b in text {
+        program.extend_from_slice(&[
+            0x3E, b,    // ld a, b
+            0xE0, 0x01, // ldh (SB), a
+            0x3E, 0x81, // ld a, 0x81
+            0xE0, 0x02, // ldh (SC), a
+            0xF0, 0x02, // ldh a, (SC)
+            0xCB, 0x7F, // bit 7, a
+            0x20, 0xFA, // jr nz, -6
+        ]);
+    }
+    program.extend_from_slice(&[0x18, 0xFE]); // jr -2
+    assemble("SERIAL", &program)
+}
+
+/// Fills the background with four horizontal bands from white to black, then loops forever
+/// * The LCD is switched off in v-blank while VRAM is written, so it is safe in strict mode
+pub fn gradient() -> Vec<u8> {
+    let mut program = vec![
+        0xF0, 0x44, // ldh a, (LY)
+        0xFE, 0x90, // cp 144
+        0x38, 0xFA, // jr c, -6
+        0xAF,       // xor a
+        0xE0, 0x40, // ldh (LCDC), a
+        0x3E, 0xE4, // ld a, 0xE4
+        0xE0, 0x47, // ldh (BGP), a
+        0x21, 0x00, 0x80, // ld hl, 0x8000
+    ];
+    // Tiles 0-3 are solid colors 0-3
+    for color in 0..4u8 {
+        let lo = if color & 0x01 != 0 { 0xFF } else { 0x00 };
+        let hi = if color & 0x02 != 0 { 0xFF } else { 0x00 };
+        for _ in 0..8 {
+            program.extend_from_slice(&[0x3E, lo, 0x22, 0x3E, hi, 0x22]); // ld a, n / ld (hl+), a
+        }
+    }
+    program.extend_from_slice(&[0x21, 0x00, 0x98]); // ld hl, 0x9800
+    for row in 0..18u8 {
+        let tile = row * 4 / 18;
+        for _ in 0..32 {
+            program.extend_from_slice(&[0x3E, tile, 0x22]);
+        }
+    }
+    program.extend_from_slice(&[
+        0x3E, 0x91, // ld a, 0x91
+        0xE0, 0x40, // ldh (LCDC), a
+        0x18, 0xFE, // jr -2
+    ]);
+    assemble("GRADIENT", &program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::motherboard::MotherBoard;
+
+    #[test]
+    fn header_checksum_matches_the_boot_rom() {
+        let mut rom = vec![0x00; ROM_SIZE];
+        // All zero header: 25 bytes each subtract 1
+        assert_eq!(header_checksum(&rom), 0xE7);
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        assert_eq!(header_checksum(&rom), 0xA7, "0xE7 less the bytes of TEST");
+        let rom = assemble("CHECK", &[]);
+        assert_eq!(rom[0x014D], header_checksum(&rom));
+    }
+
+    #[test]
+    fn serial_print_sends_its_text() {
+        let mut motherboard = MotherBoard::power_up_from_bytes(serial_print(b"OK"));
+        motherboard.mmu.borrow_mut().serial.set_capture(true);
+        motherboard.run_frames(2);
+        assert_eq!(motherboard.mmu.borrow_mut().serial.take_output(), b"OK");
+    }
+}
@@ -23,6 +23,11 @@ impl Timer {
         Timer { intf, reg: Register::default(), div_clock: Clock::power_up(256), tma_clock: Clock::power_up(1024) }
     }
 
+    /// Presets DIV, writes through set always clear it
+    pub fn set_div(&mut self, v: u8) {
+        self.reg.div = v;
+    }
+
     pub fn get(&self, a: u16) -> u8 {
         match a {
             0xFF04 => self.reg.div,
@@ -37,20 +42,20 @@ impl Timer {
         match a {
             0xFF04 => {
                 self.reg.div = 0x00;
-                self.div_clock.n = 0x00;
+                self.div_clock.reset();
             }
             0xFF05 => self.reg.tima = v,
             0xFF06 => self.reg.tma = v,
             0xFF07 => {
                 if (self.reg.tac & 0x03) != (v & 0x03) {
-                    self.tma_clock.n = 0x00;
-                    self.tma_clock.period = match v & 0x03 {
+                    self.tma_clock.reset();
+                    self.tma_clock.set_period(match v & 0x03 {
                         0x00 => 1024,
                         0x01 => 16,
                         0x02 => 64,
                         0x03 => 256,
                         _ => panic!(""),
-                    };
+                    });
                     self.reg.tima = self.reg.tma;
                 }
                 self.reg.tac = v;
@@ -0,0 +1,127 @@
+use OxidBoy::cpu::{Magic, CLOCK_FREQUENCY};
+use OxidBoy::motherboard::MotherBoard;
+use std::path::{Path, PathBuf};
+
+// Runs test roms headless and checks they pass, roms that aren't on disk are skipped
+// * blargg roms report over serial, mooneye roms stop on LD B,B with fibonacci numbers in B..L
+// * OXIDBOY_TEST_ROMS overrides where they are looked for, `cargo run --example blargg` fetches the blargg ones
+// $ cargo test --release --test rom_tests
+// $ cargo test --release --test rom_tests -- --ignored --nocapture
+
+/// Emulated seconds a rom gets before it's reported as timed out
+const TIMEOUT_SECONDS: u64 = 120;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Passed,
+    Failed,
+    Timeout,
+    Panicked,
+}
+
+fn rom_dir() -> PathBuf {
+    PathBuf::from(std::env::var("OXIDBOY_TEST_ROMS").unwrap_or_else(|_| String::from("./Roms/gb-test-roms")))
+}
+
+fn run(path: &Path) -> (Outcome, String) {
+    let mut motherboard = MotherBoard::power_up_from_bytes(std::fs::read(path).unwrap());
+    motherboard.cpu.set_throttle(false);
+    motherboard.cpu.cpu.set_magic_breakpoint(true);
+    motherboard.mmu.borrow_mut().serial.set_capture(true);
+
+    let mut serial = String::new();
+    let mut cycles = 0u64;
+    while cycles < u64::from(CLOCK_FREQUENCY) * TIMEOUT_SECONDS {
+        cycles += u64::from(motherboard.next());
+        match motherboard.cpu.cpu.take_magic() {
+            Some(Magic::Passed) => return (Outcome::Passed, serial),
+            Some(Magic::Failed) => return (Outcome::Failed, serial),
+            None => {}
+        }
+
+        // An undefined opcode hangs the CPU for good, no need to wait out the timeout
+        if motherboard.cpu.cpu.is_locked() {
+            return (Outcome::Failed, serial);
+        }
+
+        let bytes = motherboard.mmu.borrow_mut().serial.take_output();
+        if bytes.is_empty() {
+            continue;
+        }
+        serial.extend(bytes.iter().map(|&b| b as char));
+        if serial.contains("Passed") {
+            return (Outcome::Passed, serial);
+        }
+        if serial.contains("Failed") {
+            return (Outcome::Failed, serial);
+        }
+    }
+    (Outcome::Timeout, serial)
+}
+
+/// Runs one rom under the rom dir and asserts it passes, missing roms only print a note
+fn check(rom: &str) {
+    let path = rom_dir().join(rom);
+    if !path.exists() {
+        eprintln!("{} not found, skipping", path.display());
+        return;
+    }
+    let (outcome, serial) = run(&path);
+    assert_eq!(outcome, Outcome::Passed, "{}\n{}", path.display(), serial.trim());
+}
+
+#[test]
+fn cpu_instrs() {
+    check("cpu_instrs/cpu_instrs.gb");
+}
+
+#[test]
+fn instr_timing() {
+    check("instr_timing/instr_timing.gb");
+}
+
+fn collect(dir: &Path, roms: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap().flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect(&path, roms);
+        } else if path.extension().is_some_and(|e| e == "gb" || e == "gbc") {
+            roms.push(path);
+        }
+    }
+}
+
+/// Every rom under the rom dir with a pass/fail line each, ignored by default since not every rom passes yet
+#[test]
+#[ignore]
+fn every_rom() {
+    let dir = rom_dir();
+    if !dir.exists() {
+        eprintln!("{} not found, skipping", dir.display());
+        return;
+    }
+
+    let mut roms = Vec::new();
+    collect(&dir, &mut roms);
+    roms.sort();
+
+    let mut failures = 0;
+    for rom in &roms {
+        let (outcome, serial) = std::panic::catch_unwind(|| run(rom)).unwrap_or((Outcome::Panicked, String::new()));
+        let label = match outcome {
+            Outcome::Passed => "PASS",
+            Outcome::Failed => "FAIL",
+            Outcome::Timeout => "TIMEOUT",
+            Outcome::Panicked => "PANIC",
+        };
+        if outcome != Outcome::Passed {
+            failures += 1;
+        }
+        println!("{:<8}{}", label, rom.display());
+        if outcome == Outcome::Failed && !serial.is_empty() {
+            println!("        {}", serial.trim().replace('\n', "\n        "));
+        }
+    }
+    println!("{} passed, {} failed", roms.len() - failures, failures);
+    assert_eq!(failures, 0);
+}